@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use bevy::{
+    log::{Level, LogPlugin},
+    prelude::*,
+    time::common_conditions::on_timer,
+};
+use serde::{Deserialize, Serialize};
+
+use bevy_octopus::{
+    prelude::*,
+    tls::{CertificateDer, PrivateKeyDer},
+};
+use bevy_octopus_quic::{QuicAddress, QuicPlugin};
+
+/// both channels are streams of the same QUIC connection
+const JSON_CHANNEL: ChannelId = ChannelId("json");
+const BINCODE_CHANNEL: ChannelId = ChannelId("bincode");
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PlayerInformation {
+    health: usize,
+    position: (u32, u32, u32),
+}
+
+fn main() {
+    App::new()
+        .add_plugins((
+            MinimalPlugins.set(bevy::app::ScheduleRunnerPlugin::run_loop(
+                Duration::from_secs_f64(1.0 / 60.0),
+            )),
+            LogPlugin {
+                level: Level::INFO,
+                filter: "bevy_octopus=debug,bevy_octopus_quic=debug".to_string(),
+                ..default()
+            },
+        ))
+        .add_plugins((OctopusPlugin, QuicPlugin))
+        .add_transformer::<PlayerInformation, JsonTransformer>(JSON_CHANNEL)
+        .add_transformer::<PlayerInformation, BincodeTransformer>(BINCODE_CHANNEL)
+        .add_systems(Startup, setup)
+        .add_systems(Update, handle_message_events)
+        .add_systems(
+            Update,
+            send_messages.run_if(on_timer(Duration::from_secs_f64(1.0))),
+        )
+        .add_observer(on_node_event)
+        .run();
+}
+
+/// server and clients in one app, secured with a self signed certificate
+fn setup(mut commands: Commands) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .expect("Failed to generate certificate");
+    let cert_der: CertificateDer<'static> = cert.cert.der().clone();
+    let key_der = PrivateKeyDer::try_from(cert.signing_key.serialize_der())
+        .expect("Failed to read private key");
+    let server_tls = TlsSettings::server(vec![cert_der.clone()], key_der);
+    let client_tls = TlsSettings::client(vec![cert_der]).with_server_name("localhost");
+
+    for channel_id in [JSON_CHANNEL, BINCODE_CHANNEL] {
+        commands.spawn((
+            NetworkBundle::new(channel_id),
+            ServerNode(QuicAddress::new("127.0.0.1:7010")),
+            server_tls.clone(),
+        ));
+        commands.spawn((
+            NetworkBundle::new(channel_id),
+            ClientNode(QuicAddress::new("127.0.0.1:7010")),
+            client_tls.clone(),
+        ));
+    }
+}
+
+fn send_messages(mut messages: MessageWriter<SendChannelMessage<PlayerInformation>>) {
+    for channel_id in [JSON_CHANNEL, BINCODE_CHANNEL] {
+        messages.write(SendChannelMessage::new(
+            channel_id,
+            PlayerInformation {
+                health: 100,
+                position: (1, 2, 3),
+            },
+        ));
+    }
+}
+
+fn handle_message_events(mut messages: MessageReader<ReceiveChannelMessage<PlayerInformation>>) {
+    for message in messages.read() {
+        info!(
+            "{} Received from {} {:?}: {:?}",
+            message.channel_id, message.entity, message.addr, message.message
+        );
+    }
+}
+
+fn on_node_event(on: On<NodeEvent>) {
+    let e = on.event();
+    info!("{:?} trigger {:?}", e.entity, e.event);
+}
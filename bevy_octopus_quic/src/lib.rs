@@ -0,0 +1,775 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+};
+
+use async_std::{future::timeout, task};
+use bevy::prelude::*;
+use bytes::{BufMut, BytesMut};
+use futures::{
+    FutureExt, future,
+    future::{BoxFuture, Shared},
+    pin_mut,
+};
+use kanal::{AsyncReceiver, AsyncSender};
+use quinn::{
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig,
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+
+use bevy_octopus::{prelude::*, shutdown::graceful_shutdown, tls::TlsSettings};
+
+/// Longest channel name sent in the stream header
+const MAX_CHANNEL_NAME: usize = u16::MAX as usize;
+
+pub struct QuicPlugin;
+
+impl Plugin for QuicPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<QuicEndpoints>()
+            .add_systems(PostUpdate, handle_endpoint)
+            .add_systems(Last, close_endpoints.after(graceful_shutdown))
+            .add_observer(on_start_server)
+            .add_observer(on_start_client)
+            .add_observer(on_remove_server);
+    }
+}
+
+/// Address of a QUIC node.
+///
+/// Nodes of different channels with the same address share one endpoint on
+/// servers and one connection on clients, every channel is a bidirectional
+/// stream of that connection so a stalled channel never blocks the others.
+/// A server node gets one [`NetworkPeer`] per client connection, a second
+/// stream of its channel on the same connection is refused while the first is
+/// open. QUIC is always encrypted, both sides need [`TlsSettings`].
+#[derive(Debug, Clone)]
+pub struct QuicAddress {
+    pub socket_addr: SocketAddr,
+}
+
+impl QuicAddress {
+    pub fn new(address: impl ToSocketAddrs) -> Self {
+        let socket_addr = address.to_socket_addrs().unwrap().next().unwrap();
+        Self { socket_addr }
+    }
+}
+
+impl NetworkAddress for QuicAddress {
+    fn to_string(&self) -> String {
+        self.socket_addr.to_string()
+    }
+
+    fn from_string(s: &str) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        match s.parse() {
+            Ok(socket_addr) => Ok(Self { socket_addr }),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// A stream opened by a remote client, waiting for the server node of its channel
+struct IncomingStream {
+    channel: String,
+    remote_addr: SocketAddr,
+    send: SendStream,
+    recv: RecvStream,
+    claim: ChannelClaim,
+}
+
+/// Channels with an open stream on one connection
+type OpenChannels = Arc<Mutex<HashSet<String>>>;
+
+/// A channel taken on a connection, released once its stream is done
+struct ChannelClaim {
+    channels: OpenChannels,
+    channel: String,
+}
+
+impl Drop for ChannelClaim {
+    fn drop(&mut self) {
+        self.channels.lock().unwrap().remove(&self.channel);
+    }
+}
+
+struct ServerEndpoint {
+    endpoint: Endpoint,
+    incoming: AsyncChannel<IncomingStream>,
+    nodes: usize,
+}
+
+/// A connection being established or established, shared by the client nodes
+type Connecting = Shared<BoxFuture<'static, Result<Connection, String>>>;
+
+type Connections = Arc<Mutex<HashMap<(SocketAddr, String), Connecting>>>;
+
+/// QUIC endpoints and connections shared by the nodes of the app
+#[derive(Resource, Default)]
+struct QuicEndpoints {
+    servers: HashMap<SocketAddr, ServerEndpoint>,
+    clients: HashMap<SocketAddr, Endpoint>,
+    connections: Connections,
+}
+
+impl QuicEndpoints {
+    fn client(&mut self, remote_addr: SocketAddr) -> Result<Endpoint, NetworkError> {
+        let bind: SocketAddr = match remote_addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        if let Some(endpoint) = self.clients.get(&bind) {
+            return Ok(endpoint.clone());
+        }
+        let endpoint = Endpoint::client(bind)?;
+        self.clients.insert(bind, endpoint.clone());
+
+        Ok(endpoint)
+    }
+}
+
+/// Messages of a stream are length prefixed unless the node has a [`FrameCodec`]
+fn default_codec() -> FrameCodec {
+    FrameCodec::length_prefixed(LengthPrefix::U32)
+}
+
+fn server_config(tls: &TlsSettings) -> Result<ServerConfig, NetworkError> {
+    let crypto = QuicServerConfig::try_from(tls.server_config()?)
+        .map_err(|e| NetworkError::Tls(e.to_string()))?;
+
+    Ok(ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+fn client_config(tls: &TlsSettings) -> Result<ClientConfig, NetworkError> {
+    let crypto = QuicClientConfig::try_from(tls.client_config()?)
+        .map_err(|e| NetworkError::Tls(e.to_string()))?;
+
+    Ok(ClientConfig::new(Arc::new(crypto)))
+}
+
+fn on_start_server(
+    on: On<StartServer>,
+    mut endpoints: ResMut<QuicEndpoints>,
+    q_quic_server: Query<(&NetworkNode, &ServerNode<QuicAddress>, Option<&TlsSettings>)>,
+) {
+    let ev = on.event();
+    let Ok((net_node, server_node, opt_tls)) = q_quic_server.get(ev.entity) else {
+        return;
+    };
+    let local_addr = server_node.socket_addr;
+    let event_tx = net_node.event_channel.sender.clone();
+    // the endpoint is shared, the node only reports its shutdown
+    task::spawn(until_shutdown(
+        future::pending(),
+        net_node.shutdown_channel.receiver.clone_async(),
+        net_node.event_channel.sender.clone_async(),
+    ));
+
+    if let Some(server) = endpoints.servers.get_mut(&local_addr) {
+        server.nodes += 1;
+        let event = match server.endpoint.local_addr() {
+            Ok(bound) => NetworkEvent::Listen(bound),
+            Err(e) => NetworkEvent::Error(NetworkError::Listen(e)),
+        };
+        let _ = event_tx.try_send(event);
+        return;
+    }
+
+    let endpoint = opt_tls
+        .ok_or_else(|| NetworkError::Tls("QUIC server requires TlsSettings".to_string()))
+        .and_then(server_config)
+        .and_then(|config| Endpoint::server(config, local_addr).map_err(NetworkError::Listen));
+    let endpoint = match endpoint {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            let _ = event_tx.try_send(NetworkEvent::Error(e));
+            return;
+        }
+    };
+
+    let bound = match endpoint.local_addr() {
+        Ok(bound) => bound,
+        Err(e) => {
+            let _ = event_tx.try_send(NetworkEvent::Error(NetworkError::Listen(e)));
+            return;
+        }
+    };
+    debug!("QUIC server listening on {}", bound);
+    let _ = event_tx.try_send(NetworkEvent::Listen(bound));
+
+    let incoming = AsyncChannel::new();
+    let incoming_tx = incoming.sender.clone_async();
+    task::spawn(accept_connections(endpoint.clone(), incoming_tx));
+
+    endpoints.servers.insert(
+        local_addr,
+        ServerEndpoint {
+            endpoint,
+            incoming,
+            nodes: 1,
+        },
+    );
+}
+
+/// Close the endpoint once the last server node of its address is gone
+fn on_remove_server(
+    on: On<Remove, ServerNode<QuicAddress>>,
+    mut endpoints: ResMut<QuicEndpoints>,
+    q_quic_server: Query<&ServerNode<QuicAddress>>,
+) {
+    let Ok(server_node) = q_quic_server.get(on.event().entity) else {
+        return;
+    };
+    let local_addr = server_node.socket_addr;
+    if let Some(server) = endpoints.servers.get_mut(&local_addr) {
+        server.nodes = server.nodes.saturating_sub(1);
+        if server.nodes == 0 {
+            server.endpoint.close(0u32.into(), b"server closed");
+            endpoints.servers.remove(&local_addr);
+        }
+    }
+}
+
+/// Close every endpoint on exit once the nodes are done, waiting for the
+/// remotes to be told within the [`ShutdownSettings`] deadline
+fn close_endpoints(
+    mut exit: MessageReader<AppExit>,
+    settings: Res<ShutdownSettings>,
+    endpoints: Res<QuicEndpoints>,
+) {
+    if exit.read().last().is_none() {
+        return;
+    }
+    let all = endpoints
+        .servers
+        .values()
+        .map(|server| &server.endpoint)
+        .chain(endpoints.clients.values());
+    let idle = future::join_all(all.map(|endpoint| {
+        endpoint.close(0u32.into(), b"shutdown");
+        endpoint.wait_idle()
+    }));
+    if task::block_on(timeout(settings.deadline, idle)).is_err() {
+        warn!(
+            "QUIC endpoints did not close within {:?}",
+            settings.deadline
+        );
+    }
+}
+
+async fn accept_connections(endpoint: Endpoint, incoming_tx: AsyncSender<IncomingStream>) {
+    while let Some(connecting) = endpoint.accept().await {
+        task::spawn(accept_streams(connecting, incoming_tx.clone()));
+    }
+}
+
+/// Accept the channel streams of a new connection
+async fn accept_streams(connecting: quinn::Incoming, incoming_tx: AsyncSender<IncomingStream>) {
+    let connection = match connecting.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("QUIC handshake failed {}", e);
+            return;
+        }
+    };
+    let remote_addr = connection.remote_address();
+    let open_channels = OpenChannels::default();
+
+    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+        let incoming_tx = incoming_tx.clone();
+        let open_channels = open_channels.clone();
+        task::spawn(async move {
+            match read_channel_header(&mut recv).await {
+                Ok(channel) => {
+                    if !open_channels.lock().unwrap().insert(channel.clone()) {
+                        warn!(
+                            "{} opened a second stream for channel {}",
+                            remote_addr, channel
+                        );
+                        let _ = send.reset(0u32.into());
+                        return;
+                    }
+                    let claim = ChannelClaim {
+                        channels: open_channels,
+                        channel: channel.clone(),
+                    };
+                    let _ = incoming_tx
+                        .send(IncomingStream {
+                            channel,
+                            remote_addr,
+                            send,
+                            recv,
+                            claim,
+                        })
+                        .await;
+                }
+                Err(e) => error!("{} invalid QUIC stream header {}", remote_addr, e),
+            }
+        });
+    }
+}
+
+async fn read_channel_header(recv: &mut RecvStream) -> Result<String, NetworkError> {
+    let mut len = [0; 2];
+    recv.read_exact(&mut len)
+        .await
+        .map_err(|e| NetworkError::Connection(e.to_string()))?;
+    let mut name = vec![0; u16::from_be_bytes(len) as usize];
+    recv.read_exact(&mut name)
+        .await
+        .map_err(|e| NetworkError::Connection(e.to_string()))?;
+
+    String::from_utf8(name).map_err(|e| NetworkError::InvalidFrame(e.to_string()))
+}
+
+async fn write_channel_header(send: &mut SendStream, channel: &str) -> Result<(), NetworkError> {
+    if channel.len() > MAX_CHANNEL_NAME {
+        return Err(NetworkError::Common(format!(
+            "channel name of {} bytes is too long",
+            channel.len()
+        )));
+    }
+    let mut header = BytesMut::with_capacity(2 + channel.len());
+    header.put_u16(channel.len() as u16);
+    header.extend_from_slice(channel.as_bytes());
+    send.write_all(&header)
+        .await
+        .map_err(|e| NetworkError::Connection(e.to_string()))
+}
+
+#[allow(clippy::type_complexity)]
+fn on_start_client(
+    on: On<StartClient>,
+    mut endpoints: ResMut<QuicEndpoints>,
+    q_quic_client: Query<
+        (
+            &NetworkNode,
+            &ClientNode<QuicAddress>,
+            &ChannelId,
+            Option<&TlsSettings>,
+            Option<&FrameCodec>,
+            Option<&ReconnectSetting>,
+            Option<&NetworkConditioner>,
+            Option<&QueueSettings>,
+        ),
+        Without<NetworkPeer>,
+    >,
+) {
+    let ev = on.event();
+    let Ok((
+        net_node,
+        remote_addr,
+        channel_id,
+        opt_tls,
+        opt_codec,
+        opt_reconnect,
+        opt_conditioner,
+        opt_queue,
+    )) = q_quic_client.get(ev.entity)
+    else {
+        return;
+    };
+    let connect_timeout = opt_reconnect
+        .map(ReconnectSetting::connect_timeout)
+        .unwrap_or_else(|| ReconnectSetting::default().connect_timeout());
+    info!("try connect to {}", remote_addr.to_string());
+
+    let addr = remote_addr.socket_addr;
+    let channel = channel_id.0.to_string();
+    let codec = opt_codec.cloned().unwrap_or_else(default_codec);
+    let event_tx = net_node.event_channel.sender.clone_async();
+    let layers = TransportLayers::new(opt_queue, None, opt_conditioner);
+    let (recv_tx, message_rx) = net_node.transport_channels(&layers);
+    let channels = (
+        recv_tx,
+        message_rx,
+        event_tx.clone(),
+        net_node.shutdown_channel.receiver.clone_async(),
+    );
+
+    let connect = opt_tls
+        .ok_or_else(|| NetworkError::Tls("QUIC client requires TlsSettings".to_string()))
+        .and_then(|tls| {
+            let server_name = tls
+                .server_name
+                .clone()
+                .unwrap_or_else(|| addr.ip().to_string());
+            Ok((endpoints.client(addr)?, client_config(tls)?, server_name))
+        });
+    let connections = endpoints.connections.clone();
+
+    task::spawn(async move {
+        let stream = match connect {
+            Ok((endpoint, config, server_name)) => timeout(
+                connect_timeout,
+                open_stream(endpoint, config, server_name, addr, connections, &channel),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(NetworkError::Connection(format!(
+                    "connect to {} timed out",
+                    addr
+                )))
+            }),
+            Err(e) => Err(e),
+        };
+        match stream {
+            Ok((send, recv)) => serve_stream(send, recv, addr, codec, channels).await,
+            Err(e) => {
+                let _ = event_tx.send(NetworkEvent::Error(e)).await;
+            }
+        }
+    });
+}
+
+/// Open the stream of `channel` on the shared connection to `addr`
+async fn open_stream(
+    endpoint: Endpoint,
+    config: ClientConfig,
+    server_name: String,
+    addr: SocketAddr,
+    connections: Connections,
+    channel: &str,
+) -> Result<(SendStream, RecvStream), NetworkError> {
+    let connecting = {
+        let mut connections = connections.lock().unwrap();
+        let key = (addr, server_name);
+        let reusable = connections
+            .get(&key)
+            .filter(|connecting| match connecting.peek() {
+                Some(Ok(connection)) => connection.close_reason().is_none(),
+                Some(Err(_)) => false,
+                None => true,
+            });
+        match reusable {
+            Some(connecting) => connecting.clone(),
+            None => {
+                let connect = endpoint.connect_with(config, addr, &key.1);
+                let connecting = async move {
+                    connect
+                        .map_err(|e| e.to_string())?
+                        .await
+                        .map_err(|e| e.to_string())
+                }
+                .boxed()
+                .shared();
+                connections.insert(key, connecting.clone());
+                connecting
+            }
+        }
+    };
+    // awaited without the lock, so connecting to one remote never holds up the others
+    let connection = connecting.await.map_err(NetworkError::Connection)?;
+
+    let (mut send, recv) = connection
+        .open_bi()
+        .await
+        .map_err(|e| NetworkError::Connection(e.to_string()))?;
+    write_channel_header(&mut send, channel).await?;
+
+    Ok((send, recv))
+}
+
+async fn serve_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    addr: SocketAddr,
+    codec: FrameCodec,
+    (recv_tx, message_rx, event_tx, shutdown_rx): (
+        AsyncSender<NetworkRawPacket>,
+        AsyncReceiver<NetworkRawPacket>,
+        AsyncSender<NetworkEvent>,
+        AsyncReceiver<DisconnectReason>,
+    ),
+) {
+    let _ = event_tx.send(NetworkEvent::Connected).await;
+    let event_tx_clone = event_tx.clone();
+    let read_codec = codec.clone();
+
+    let read_task = async move {
+        let mut buffer = vec![0; 4096];
+        let mut frames = BytesMut::new();
+
+        loop {
+            match recv.read(&mut buffer).await {
+                Ok(None) => {
+                    let reason = DisconnectReason::RemoteClosed;
+                    let _ = event_tx_clone
+                        .send(NetworkEvent::Disconnected(reason))
+                        .await;
+                    break;
+                }
+                Ok(Some(n)) => {
+                    trace!("read {} bytes from {}", n, addr);
+                    frames.extend_from_slice(&buffer[..n]);
+                    loop {
+                        match read_codec.decode(&mut frames) {
+                            Ok(Some(bytes)) => {
+                                let _ = recv_tx
+                                    .send(NetworkRawPacket {
+                                        addr: Some(addr),
+                                        bytes,
+                                        text: None,
+                                    })
+                                    .await;
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("invalid frame from {}: {}", addr, e);
+                                let reason = DisconnectReason::ProtocolError(e.to_string());
+                                let _ = event_tx_clone.send(NetworkEvent::Error(e)).await;
+                                let _ = event_tx_clone
+                                    .send(NetworkEvent::Disconnected(reason))
+                                    .await;
+                                return;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    trace!("Failed to read data from stream: {}", e);
+                    let _ = event_tx_clone
+                        .send(NetworkEvent::Error(NetworkError::Common(e.to_string())))
+                        .await;
+                    let reason = DisconnectReason::ConnectionLost;
+                    let _ = event_tx_clone
+                        .send(NetworkEvent::Disconnected(reason))
+                        .await;
+                    break;
+                }
+            }
+        }
+    };
+
+    let write_task = async {
+        let mut frame = BytesMut::new();
+        let mut queue = SendQueue::new(message_rx, shutdown_rx);
+        while let Some(outgoing) = queue.next().await {
+            let data = match outgoing {
+                Outgoing::Packet(data) => data,
+                Outgoing::Close(reason) => {
+                    debug!("closing stream to {}: {:?}", addr, reason);
+                    // wait until the remote got everything
+                    let _ = send.finish();
+                    let _ = send.stopped().await;
+                    let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                    break;
+                }
+            };
+            trace!("write {} bytes to {} ", data.bytes.len(), addr);
+            frame.clear();
+            if let Err(e) = codec.encode(&data.bytes, &mut frame) {
+                error!("failed to frame message for {}: {}", addr, e);
+                let _ = event_tx.send(NetworkEvent::Error(e)).await;
+                break;
+            }
+            if let Err(e) = send.write_all(&frame).await {
+                trace!("Failed to write data to stream: {}", e);
+                let _ = event_tx
+                    .send(NetworkEvent::Error(NetworkError::Common(e.to_string())))
+                    .await;
+                let reason = DisconnectReason::ConnectionLost;
+                let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                break;
+            }
+        }
+    };
+
+    {
+        pin_mut!(read_task, write_task);
+        future::select(read_task, write_task).await;
+    }
+    let _ = send.finish();
+}
+
+#[allow(clippy::type_complexity)]
+fn handle_endpoint(
+    mut commands: Commands,
+    endpoints: Res<QuicEndpoints>,
+    q_quic_server: Query<(
+        Entity,
+        &ServerNode<QuicAddress>,
+        &ChannelId,
+        Option<&FrameCodec>,
+        Option<&NetworkConditioner>,
+        Option<&QueueSettings>,
+    )>,
+) {
+    for (local_addr, server) in endpoints.servers.iter() {
+        while let Ok(Some(stream)) = server.incoming.receiver.try_recv() {
+            let IncomingStream {
+                channel,
+                remote_addr,
+                mut send,
+                recv,
+                claim,
+            } = stream;
+            let Some((entity, _, channel_id, opt_codec, opt_conditioner, opt_queue)) =
+                q_quic_server
+                    .iter()
+                    .find(|(_, server_node, channel_id, _, _, _)| {
+                        server_node.socket_addr == *local_addr && channel_id.0 == channel
+                    })
+            else {
+                warn!(
+                    "{} opened a stream for unknown channel {}",
+                    remote_addr, channel
+                );
+                let _ = send.reset(0u32.into());
+                continue;
+            };
+
+            let peer_entity = commands.spawn_empty().id();
+            let layers = TransportLayers::new(opt_queue, None, opt_conditioner).for_peer();
+            let new_net_node = layers.node();
+            let (recv_tx, message_rx) = new_net_node.transport_channels(&layers);
+            let channels = (
+                recv_tx,
+                message_rx,
+                new_net_node.event_channel.sender.clone_async(),
+                new_net_node.shutdown_channel.receiver.clone_async(),
+            );
+            let codec = opt_codec.cloned().unwrap_or_else(default_codec);
+            let serve = serve_stream(send, recv, remote_addr, codec.clone(), channels);
+            task::spawn(async move {
+                serve.await;
+                drop(claim);
+            });
+
+            commands.entity(peer_entity).insert((
+                new_net_node,
+                *channel_id,
+                ClientNode(QuicAddress::new(remote_addr)),
+                RemoteAddr(remote_addr),
+                NetworkPeer,
+                codec,
+            ));
+            layers.insert_on(&mut commands.entity(peer_entity));
+
+            debug!(
+                "new QUIC stream {} from {} {:?}",
+                channel, remote_addr, peer_entity
+            );
+
+            commands.entity(entity).add_child(peer_entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::task::block_on;
+    use bevy_octopus::tls::{CertificateDer, PrivateKeyDer};
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn tls() -> (TlsSettings, TlsSettings) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der: CertificateDer<'static> = cert.cert.der().clone();
+        let key_der = PrivateKeyDer::try_from(cert.signing_key.serialize_der()).unwrap();
+
+        (
+            TlsSettings::server(vec![cert_der.clone()], key_der),
+            TlsSettings::client(vec![cert_der]).with_server_name("localhost"),
+        )
+    }
+
+    async fn within<T>(future: impl Future<Output = T>) -> T {
+        timeout(WAIT, future).await.unwrap()
+    }
+
+    fn serve(send: SendStream, recv: RecvStream, addr: SocketAddr, node: &NetworkNode) {
+        let (recv_tx, message_rx) = node.transport_channels(&TransportLayers::default());
+        task::spawn(serve_stream(
+            send,
+            recv,
+            addr,
+            default_codec(),
+            (
+                recv_tx,
+                message_rx,
+                node.event_channel.sender.clone_async(),
+                node.shutdown_channel.receiver.clone_async(),
+            ),
+        ));
+    }
+
+    #[test]
+    fn channels_share_one_loopback_connection() {
+        let (server_tls, client_tls) = tls();
+
+        block_on(async {
+            let server = Endpoint::server(
+                server_config(&server_tls).unwrap(),
+                (Ipv4Addr::LOCALHOST, 0).into(),
+            )
+            .unwrap();
+            let addr = server.local_addr().unwrap();
+            let incoming = AsyncChannel::new();
+            task::spawn(accept_connections(server, incoming.sender.clone_async()));
+
+            let mut endpoints = QuicEndpoints::default();
+            let client = endpoints.client(addr).unwrap();
+            let config = client_config(&client_tls).unwrap();
+            let open = |channel| {
+                let stream = open_stream(
+                    client.clone(),
+                    config.clone(),
+                    "localhost".to_string(),
+                    addr,
+                    endpoints.connections.clone(),
+                    channel,
+                );
+                within(stream)
+            };
+
+            let (a, b) = future::join(open("a"), open("b")).await;
+            let ((client_send, client_recv), _b) = (a.unwrap(), b.unwrap());
+            assert_eq!(endpoints.connections.lock().unwrap().len(), 1);
+
+            let incoming_rx = incoming.receiver.as_async();
+            let first = within(incoming_rx.recv()).await.unwrap();
+            let second = within(incoming_rx.recv()).await.unwrap();
+            assert_eq!(first.remote_addr, second.remote_addr);
+            let (stream_a, _stream_b) = if first.channel == "a" {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            assert_eq!(stream_a.channel, "a");
+
+            let (client_node, server_node) = (NetworkNode::default(), NetworkNode::default());
+            serve(client_send, client_recv, addr, &client_node);
+            serve(
+                stream_a.send,
+                stream_a.recv,
+                stream_a.remote_addr,
+                &server_node,
+            );
+
+            client_node.send_bytes(b"ping");
+            let received = within(server_node.recv_message_channel.receiver.as_async().recv())
+                .await
+                .unwrap();
+            assert_eq!(&received.bytes[..], b"ping");
+            server_node.send_bytes(b"pong");
+            let received = within(client_node.recv_message_channel.receiver.as_async().recv())
+                .await
+                .unwrap();
+            assert_eq!(&received.bytes[..], b"pong");
+
+            // the channel is taken on this connection
+            let (_, mut duplicate) = open("a").await.unwrap();
+            let mut buf = [0; 1];
+            assert!(within(duplicate.read(&mut buf)).await.is_err());
+            assert!(incoming_rx.is_empty());
+        });
+    }
+}
@@ -50,7 +50,10 @@ pub fn handle_message_events(
     mut ev_channels: MessageReader<ReceiveChannelMessage<PlayerInformation>>,
 ) {
     for event in ev_channels.read() {
-        info!("{} Received: {:?}", event.channel_id, &event.message);
+        info!(
+            "{} Received from {:?} {:?}: {:?}",
+            event.channel_id, event.entity, event.addr, &event.message
+        );
     }
 }
 
@@ -1,16 +1,36 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use async_std::{
+    future::timeout,
     net::{TcpListener, TcpStream},
     task,
 };
-use async_tungstenite::{accept_async, async_std::connect_async, tungstenite::Message};
+use async_tungstenite::{
+    WebSocketStream, accept_async,
+    async_std::connect_async,
+    tungstenite::{
+        Message,
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
+};
 use bevy::prelude::*;
 use bytes::Bytes;
-use futures::{pin_mut, prelude::*};
-use kanal::{AsyncReceiver, AsyncSender};
+use futures::{lock::Mutex, pin_mut, prelude::*};
+use kanal::AsyncSender;
 
-use bevy_octopus::prelude::*;
+use bevy_octopus::{
+    heartbeat::{ping_payload, pong_rtt},
+    prelude::*,
+};
+#[cfg(feature = "tls")]
+use {
+    async_tungstenite::{
+        client_async,
+        tungstenite::http::{Uri, uri::InvalidUri},
+    },
+    bevy_octopus::tls::TlsSettings,
+    std::net::IpAddr,
+};
 
 pub struct WebsocketPlugin;
 
@@ -59,26 +79,21 @@ impl WebsocketAddress {
         event_tx: AsyncSender<NetworkEvent>,
         new_connection_tx: AsyncSender<(TcpStream, SocketAddr)>,
     ) -> Result<(), NetworkError> {
-        let server = async move {
-            let listener = TcpListener::bind(addr).await?;
-
-            debug!("Websocket Server listening on {}", addr);
-            let _ = event_tx.send(NetworkEvent::Connected).await;
-
-            while let Ok((tcp_stream, peer_addr)) = listener.accept().await {
-                tcp_stream
-                    .set_nodelay(true)
-                    .expect("set_nodelay call failed");
-                new_connection_tx
-                    .send((tcp_stream, peer_addr))
-                    .await
-                    .unwrap();
-            }
-
-            Ok::<(), NetworkError>(())
-        };
-
-        async_std::task::spawn(server);
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        debug!("Websocket Server listening on {}", local_addr);
+        let _ = event_tx.send(NetworkEvent::Listen(local_addr)).await;
+
+        while let Ok((tcp_stream, peer_addr)) = listener.accept().await {
+            tcp_stream
+                .set_nodelay(true)
+                .expect("set_nodelay call failed");
+            new_connection_tx
+                .send((tcp_stream, peer_addr))
+                .await
+                .unwrap();
+        }
 
         Ok(())
     }
@@ -92,57 +107,64 @@ fn on_start_server(
     if let Ok((net_node, server_node)) = q_ws_server.get(ev.entity) {
         let local_addr = server_node.url.parse().expect("Invalid address");
         let event_tx = net_node.event_channel.sender.clone_async();
-        let shutdown_clone = net_node.shutdown_channel.receiver.clone_async();
-        let event_tx_clone = event_tx.clone();
+        let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
         let new_connection_tx = server_node.new_connection_channel.sender.clone_async();
-        async_std::task::spawn(async move {
-            let tasks = vec![
-                async_std::task::spawn(WebsocketAddress::listen(
-                    local_addr,
-                    event_tx_clone,
-                    new_connection_tx,
-                )),
-                async_std::task::spawn(async move {
-                    let _ = shutdown_clone.recv().await;
-                    Ok(())
-                }),
-            ];
-
-            if let Err(err) = future::try_join_all(tasks).await {
-                let _ = event_tx.send(NetworkEvent::Error(err)).await;
-            }
-        });
+        async_std::task::spawn(until_shutdown(
+            WebsocketAddress::listen(local_addr, event_tx.clone(), new_connection_tx),
+            shutdown_rx,
+            event_tx,
+        ));
     }
 }
 
 #[allow(clippy::type_complexity)]
 fn on_start_client(
     on: On<StartClient>,
-    q_ws_client: Query<(&NetworkNode, &ClientNode<WebsocketAddress>), Without<NetworkPeer>>,
+    q_ws_client: Query<
+        (
+            &NetworkNode,
+            &ClientNode<WebsocketAddress>,
+            Option<&ReconnectSetting>,
+            Option<(&Heartbeat, &NetworkRtt)>,
+            Option<&NetworkConditioner>,
+            Option<&QueueSettings>,
+        ),
+        Without<NetworkPeer>,
+    >,
+    #[cfg(feature = "tls")] q_tls: Query<&TlsSettings>,
 ) {
     let ev = on.event();
-    if let Ok((net_node, remote_addr)) = q_ws_client.get(ev.entity) {
+    if let Ok((net_node, remote_addr, opt_reconnect, opt_heartbeat, opt_conditioner, opt_queue)) =
+        q_ws_client.get(ev.entity)
+    {
         let url = remote_addr.url.clone();
         debug!("try connect to {}", url);
-        let recv_tx = net_node.recv_message_channel.sender.clone_async();
-        let message_rx = net_node.send_message_channel.receiver.clone_async();
+        let connect_timeout = opt_reconnect
+            .map(ReconnectSetting::connect_timeout)
+            .unwrap_or_else(|| ReconnectSetting::default().connect_timeout());
+        let heartbeat = opt_heartbeat.map(|(heartbeat, rtt)| (heartbeat.clone(), rtt.clone()));
+        #[cfg(feature = "tls")]
+        let tls = q_tls.get(ev.entity).ok().cloned();
+        // websocket pings keep the heartbeat, it is no layer
+        let layers = TransportLayers::new(opt_queue, None, opt_conditioner);
+        let (recv_tx, message_rx) = net_node.transport_channels(&layers);
         let event_tx = net_node.event_channel.sender.clone_async();
         let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
+        let queue = SendQueue::new(message_rx, shutdown_rx);
 
         async_std::task::spawn(async move {
-            let tasks = vec![
-                task::spawn(handle_client_conn(
-                    url,
-                    recv_tx,
-                    message_rx,
-                    event_tx.clone(),
-                )),
-                task::spawn(async move {
-                    let _ = shutdown_rx.recv().await;
-                    Ok(())
-                }),
-            ];
-            if let Err(err) = future::try_join_all(tasks).await {
+            if let Err(err) = handle_client_conn(
+                url,
+                connect_timeout,
+                #[cfg(feature = "tls")]
+                tls,
+                heartbeat,
+                recv_tx,
+                queue,
+                event_tx.clone(),
+            )
+            .await
+            {
                 let _ = event_tx.send(NetworkEvent::Error(err)).await;
             }
         });
@@ -151,23 +173,97 @@ fn on_start_client(
 
 async fn handle_client_conn(
     url: String,
+    connect_timeout: Duration,
+    #[cfg(feature = "tls")] tls: Option<TlsSettings>,
+    heartbeat: Option<(Heartbeat, NetworkRtt)>,
     recv_tx: AsyncSender<NetworkRawPacket>,
-    message_rx: AsyncReceiver<NetworkRawPacket>,
+    queue: SendQueue,
     event_tx: AsyncSender<NetworkEvent>,
 ) -> Result<(), NetworkError> {
-    let ws_stream = connect_async(url.clone())
+    let timed_out = |_| NetworkError::Connection(format!("connect to {} timed out", url));
+
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        let connect = async {
+            let stream = connect_tls(&url, &tls).await?;
+            client_async(url.clone(), stream)
+                .await
+                .map_err(|e| NetworkError::Connection(e.to_string()))
+        };
+        let (ws_stream, _) = timeout(connect_timeout, connect)
+            .await
+            .map_err(timed_out)??;
+        return client_session(ws_stream, heartbeat, recv_tx, queue, event_tx).await;
+    }
+
+    let (ws_stream, _) = timeout(connect_timeout, connect_async(url.clone()))
+        .await
+        .map_err(timed_out)?
+        .map_err(|e| NetworkError::Connection(e.to_string()))?;
+
+    client_session(ws_stream, heartbeat, recv_tx, queue, event_tx).await
+}
+
+/// Open a TLS stream to the host of a `wss://` url trusting the roots of `tls`
+#[cfg(feature = "tls")]
+async fn connect_tls(
+    url: &str,
+    tls: &TlsSettings,
+) -> Result<impl AsyncRead + AsyncWrite + Unpin + Send + use<>, NetworkError> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e: InvalidUri| NetworkError::Connection(e.to_string()))?;
+    let host = uri
+        .host()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .ok_or_else(|| NetworkError::Connection(format!("{} has no host", url)))?;
+    let port = uri.port_u16().unwrap_or(443);
+
+    let tcp_stream = TcpStream::connect((host, port))
         .await
         .map_err(|e| NetworkError::Connection(e.to_string()))?;
+    tcp_stream
+        .set_nodelay(true)
+        .map_err(|e| NetworkError::Connection(e.to_string()))?;
+    let remote_addr = tcp_stream.peer_addr()?;
 
+    let mut tls = tls.clone();
+    if tls.server_name.is_none() && host.parse::<IpAddr>().is_err() {
+        tls.server_name = Some(host.to_string());
+    }
+
+    tls.connect(remote_addr, tcp_stream).await
+}
+
+async fn client_session<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    ws_stream: WebSocketStream<S>,
+    heartbeat: Option<(Heartbeat, NetworkRtt)>,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    mut queue: SendQueue,
+    event_tx: AsyncSender<NetworkEvent>,
+) -> Result<(), NetworkError> {
     let _ = event_tx.send(NetworkEvent::Connected).await;
     let event_tx_clone = event_tx.clone();
+    let last_seen = LastSeen::default();
 
-    let (mut writer, read) = ws_stream.0.split();
+    let (writer, mut read) = ws_stream.split();
+    let writer = Mutex::new(writer);
 
-    let ws_to_output = {
-        read.for_each(|message| async {
+    let ws_to_output = async {
+        while let Some(message) = read.next().await {
             match message {
+                Ok(Message::Close(frame)) => {
+                    let reason = close_reason(frame);
+                    let _ = event_tx_clone
+                        .send(NetworkEvent::Disconnected(reason))
+                        .await;
+                    break;
+                }
                 Ok(message) => {
+                    last_seen.touch();
+                    if !is_data(&message, &heartbeat) {
+                        continue;
+                    }
                     let data = message.into_data();
                     recv_tx
                         .send(NetworkRawPacket {
@@ -182,14 +278,26 @@ async fn handle_client_conn(
                     let _ = event_tx_clone
                         .send(NetworkEvent::Error(NetworkError::Common(err.to_string())))
                         .await;
-                    let _ = event_tx_clone.send(NetworkEvent::Disconnected).await;
+                    let reason = DisconnectReason::ConnectionLost;
+                    let _ = event_tx_clone
+                        .send(NetworkEvent::Disconnected(reason))
+                        .await;
+                    break;
                 }
             }
-        })
+        }
     };
 
-    let write_task = async move {
-        while let Ok(data) = message_rx.recv().await {
+    let write_task = async {
+        while let Some(outgoing) = queue.next().await {
+            let data = match outgoing {
+                Outgoing::Packet(data) => data,
+                Outgoing::Close(reason) => {
+                    close(&writer, &reason).await;
+                    let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                    break;
+                }
+            };
             trace!("write {} bytes ", data.bytes.len());
             let message = if let Some(text) = data.text {
                 Message::Text(text)
@@ -197,43 +305,91 @@ async fn handle_client_conn(
                 Message::binary(data.bytes)
             };
 
-            if let Err(err) = writer.send(message).await {
+            if let Err(err) = writer.lock().await.send(message).await {
                 let _ = event_tx
                     .send(NetworkEvent::Error(NetworkError::Common(err.to_string())))
                     .await;
-                let _ = event_tx.send(NetworkEvent::Disconnected).await;
+                let reason = DisconnectReason::ConnectionLost;
+                let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
 
                 break;
             }
         }
     };
 
-    pin_mut!(write_task, ws_to_output);
-    future::select(write_task, ws_to_output).await;
+    let keepalive = keepalive(&heartbeat, &last_seen, &writer, &event_tx);
+
+    pin_mut!(write_task, ws_to_output, keepalive);
+    future::select(future::select(write_task, ws_to_output), keepalive).await;
 
     Ok(())
 }
 
-async fn server_handle_conn(
+async fn server_accept_conn(
     tcp_stream: TcpStream,
-    addr: String,
+    #[cfg(feature = "tls")] tls: Option<TlsSettings>,
+    heartbeat: Option<(Heartbeat, NetworkRtt)>,
+    addr: SocketAddr,
     recv_tx: AsyncSender<NetworkRawPacket>,
-    message_rx: AsyncReceiver<NetworkRawPacket>,
+    queue: SendQueue,
     event_tx: AsyncSender<NetworkEvent>,
 ) {
-    let ws_stream = accept_async(tcp_stream)
-        .await
-        .expect("Failed TCP incoming connection");
-    let (mut writer, read) = ws_stream.split();
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        match tls.accept(tcp_stream).await {
+            Ok(stream) => {
+                server_handle_conn(stream, heartbeat, addr, recv_tx, queue, event_tx).await
+            }
+            Err(e) => {
+                error!("{} TLS handshake failed {}", addr, e);
+                let _ = event_tx.send(NetworkEvent::Error(e)).await;
+            }
+        }
+        return;
+    }
+
+    server_handle_conn(tcp_stream, heartbeat, addr, recv_tx, queue, event_tx).await
+}
+
+async fn server_handle_conn<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    heartbeat: Option<(Heartbeat, NetworkRtt)>,
+    addr: SocketAddr,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    mut queue: SendQueue,
+    event_tx: AsyncSender<NetworkEvent>,
+) {
+    let ws_stream = match accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            error!("{} websocket handshake failed {}", addr, e);
+            let _ = event_tx
+                .send(NetworkEvent::Error(NetworkError::Connection(e.to_string())))
+                .await;
+            return;
+        }
+    };
+    let last_seen = LastSeen::default();
+    let (writer, mut read) = ws_stream.split();
+    let writer = Mutex::new(writer);
 
-    let ws_to_output = {
-        read.for_each(|message| async {
+    let ws_to_output = async {
+        while let Some(message) = read.next().await {
             match message {
+                Ok(Message::Close(frame)) => {
+                    let reason = close_reason(frame);
+                    let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                    break;
+                }
                 Ok(message) => {
+                    last_seen.touch();
+                    if !is_data(&message, &heartbeat) {
+                        continue;
+                    }
                     let data = message.into_data();
                     let _ = recv_tx
                         .send(NetworkRawPacket {
-                            addr: None,
+                            addr: Some(addr),
                             bytes: Bytes::from_iter(data),
                             text: None,
                         })
@@ -243,17 +399,25 @@ async fn server_handle_conn(
                     error!("{} websocket error {:?}", addr, err);
                 }
             }
-        })
+        }
     };
 
-    let write_task = async move {
-        while let Ok(data) = message_rx.recv().await {
+    let write_task = async {
+        while let Some(outgoing) = queue.next().await {
+            let data = match outgoing {
+                Outgoing::Packet(data) => data,
+                Outgoing::Close(reason) => {
+                    close(&writer, &reason).await;
+                    let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                    break;
+                }
+            };
             let message = if let Some(text) = data.text {
                 Message::Text(text)
             } else {
                 Message::binary(data.bytes)
             };
-            if let Err(e) = writer.send(message).await {
+            if let Err(e) = writer.lock().await.send(message).await {
                 let _ = event_tx
                     .send(NetworkEvent::Error(NetworkError::Common(e.to_string())))
                     .await;
@@ -263,47 +427,139 @@ async fn server_handle_conn(
         }
     };
 
-    pin_mut!(write_task, ws_to_output);
-    future::select(write_task, ws_to_output).await;
+    let keepalive = keepalive(&heartbeat, &last_seen, &writer, &event_tx);
+
+    pin_mut!(write_task, ws_to_output, keepalive);
+    future::select(future::select(write_task, ws_to_output), keepalive).await;
 }
 
+/// Record the round trip of pongs, control frames are not handed to the node
+fn is_data(message: &Message, heartbeat: &Option<(Heartbeat, NetworkRtt)>) -> bool {
+    match message {
+        Message::Pong(payload) => {
+            if let (Some((_, rtt)), Some(sample)) = (heartbeat, pong_rtt(payload)) {
+                rtt.record(sample);
+            }
+            false
+        }
+        Message::Ping(_) | Message::Frame(_) => false,
+        _ => true,
+    }
+}
+
+/// Reason the remote gave in its close frame
+fn close_reason(frame: Option<CloseFrame>) -> DisconnectReason {
+    let Some(frame) = frame else {
+        return DisconnectReason::RemoteClosed;
+    };
+    match frame.code {
+        CloseCode::Away => DisconnectReason::ServerShutdown,
+        CloseCode::Policy => DisconnectReason::Kicked(frame.reason.into_owned()),
+        CloseCode::Protocol | CloseCode::Unsupported | CloseCode::Invalid => {
+            DisconnectReason::ProtocolError(frame.reason.into_owned())
+        }
+        _ => DisconnectReason::RemoteClosed,
+    }
+}
+
+/// Send a close frame telling the remote why, after everything queued
+async fn close<W>(writer: &Mutex<W>, reason: &DisconnectReason)
+where
+    W: Sink<Message> + Unpin,
+{
+    let (code, text) = match reason {
+        DisconnectReason::Kicked(message) => (CloseCode::Policy, message.clone()),
+        DisconnectReason::ProtocolError(message) => (CloseCode::Protocol, message.clone()),
+        DisconnectReason::ServerShutdown => (CloseCode::Away, String::new()),
+        DisconnectReason::QueueOverflow => (CloseCode::Policy, "queue overflow".to_string()),
+        _ => (CloseCode::Normal, String::new()),
+    };
+    let frame = CloseFrame {
+        code,
+        reason: text.into(),
+    };
+    let mut writer = writer.lock().await;
+    let _ = writer.send(Message::Close(Some(frame))).await;
+    let _ = writer.close().await;
+}
+
+/// Send native ping frames until the remote times out, forever without a heartbeat
+async fn keepalive<W>(
+    heartbeat: &Option<(Heartbeat, NetworkRtt)>,
+    last_seen: &LastSeen,
+    writer: &Mutex<W>,
+    event_tx: &AsyncSender<NetworkEvent>,
+) where
+    W: Sink<Message> + Unpin,
+{
+    let Some((heartbeat, _)) = heartbeat else {
+        return future::pending().await;
+    };
+    let ping = || async {
+        let payload = ping_payload().to_vec();
+        writer
+            .lock()
+            .await
+            .send(Message::Ping(payload))
+            .await
+            .is_ok()
+    };
+    heartbeat.watch(last_seen, ping, event_tx).await;
+}
+
+#[allow(clippy::type_complexity)]
 fn handle_endpoint(
     mut commands: Commands,
     q_ws_server: Query<(
         Entity,
         &ServerNode<WebsocketAddress>,
-        &NetworkNode,
         &ChannelId,
+        Option<&Heartbeat>,
+        Option<&NetworkConditioner>,
+        Option<&QueueSettings>,
     )>,
+    #[cfg(feature = "tls")] q_tls: Query<&TlsSettings>,
 ) {
-    for (entity, ws_node, net_node, channel_id) in q_ws_server.iter() {
+    for (entity, ws_node, channel_id, opt_heartbeat, opt_conditioner, opt_queue) in
+        q_ws_server.iter()
+    {
+        #[cfg(feature = "tls")]
+        let tls = q_tls.get(entity).ok().cloned();
+        #[cfg(feature = "tls")]
+        let scheme = if tls.is_some() { "wss" } else { "ws" };
+        #[cfg(not(feature = "tls"))]
+        let scheme = "ws";
+        // websocket pings keep the heartbeat, it is no layer
+        let layers = TransportLayers::new(opt_queue, None, opt_conditioner);
+
         while let Ok(Some((tcp_stream, socket))) =
             ws_node.new_connection_channel.receiver.try_recv()
         {
-            let new_net_node = NetworkNode::default();
             // Create a new entity for the client
             let child_ws_client = commands.spawn_empty().id();
-            let recv_tx = net_node.recv_message_channel.sender.clone_async();
-            let message_rx = new_net_node.send_message_channel.receiver.clone_async();
+            let peer_layers = layers.for_peer();
+            let new_net_node = peer_layers.node();
+            let (recv_tx, message_rx) = new_net_node.transport_channels(&peer_layers);
+            let queue = SendQueue::new(
+                message_rx,
+                new_net_node.shutdown_channel.receiver.clone_async(),
+            );
             let event_tx = new_net_node.event_channel.sender.clone_async();
-            let shutdown_rx = new_net_node.shutdown_channel.receiver.clone_async();
-
-            task::spawn(async move {
-                let tasks = vec![
-                    task::spawn(server_handle_conn(
-                        tcp_stream,
-                        socket.to_string(),
-                        recv_tx,
-                        message_rx,
-                        event_tx,
-                    )),
-                    task::spawn(async move {
-                        let _ = shutdown_rx.recv().await;
-                    }),
-                ];
-
-                future::join_all(tasks).await
-            });
+            let rtt = NetworkRtt::default();
+            let heartbeat = opt_heartbeat.map(|heartbeat| (heartbeat.clone(), rtt.clone()));
+            #[cfg(feature = "tls")]
+            let tls = tls.clone();
+
+            task::spawn(server_accept_conn(
+                tcp_stream,
+                #[cfg(feature = "tls")]
+                tls,
+                heartbeat,
+                socket,
+                recv_tx,
+                queue,
+                event_tx,
+            ));
 
             let peer = NetworkPeer {};
 
@@ -311,13 +567,20 @@ fn handle_endpoint(
                 "new websocket client {:?} connected {:?}",
                 socket, child_ws_client
             );
-            let url_str = format!("ws://{}", socket);
+            let url_str = format!("{}://{}", scheme, socket);
             commands.entity(child_ws_client).insert((
                 ClientNode(WebsocketAddress::new(&url_str)),
+                RemoteAddr(socket),
                 new_net_node,
                 *channel_id,
                 peer,
             ));
+            if let Some(heartbeat) = opt_heartbeat {
+                commands
+                    .entity(child_ws_client)
+                    .insert((heartbeat.clone(), rtt));
+            }
+            peer_layers.insert_on(&mut commands.entity(child_ws_client));
 
             // Add the client to the server's children
             commands.entity(entity).add_child(child_ws_client);
@@ -328,3 +591,164 @@ fn handle_endpoint(
         }
     }
 }
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::{net::SocketAddr, time::Instant};
+
+    use bevy::time::TimePlugin;
+    use bevy_octopus::tls::{CertificateDer, PrivateKeyDer};
+
+    use super::*;
+
+    const CHANNEL: ChannelId = ChannelId("wss test");
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[derive(Resource, Default)]
+    struct Events(Vec<(Entity, String)>);
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((TimePlugin, OctopusPlugin, WebsocketPlugin))
+            .init_resource::<Events>()
+            .add_observer(|on: On<NodeEvent>, mut events: ResMut<Events>| {
+                let ev = on.event();
+                events.0.push((ev.entity, format!("{:?}", ev.event)));
+            });
+        app
+    }
+
+    /// Self-signed certificate for `localhost` with its private key
+    fn certificate() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivateKeyDer::try_from(cert.signing_key.serialize_der()).unwrap();
+
+        (cert.cert.der().clone(), key)
+    }
+
+    /// Update until `done`, the transport tasks run in the background
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < WAIT, "timed out");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn has_event(app: &App, entity: Entity, prefix: &str) -> bool {
+        app.world()
+            .resource::<Events>()
+            .0
+            .iter()
+            .any(|(e, event)| *e == entity && event.starts_with(prefix))
+    }
+
+    /// Start a wss server, returning its app and the address it listens on
+    fn server(cert: CertificateDer<'static>, key: PrivateKeyDer<'static>) -> (App, SocketAddr) {
+        let mut app = app();
+        let server = app
+            .world_mut()
+            .spawn((
+                NetworkBundle::new(CHANNEL),
+                ServerNode(WebsocketAddress::new("127.0.0.1:0")),
+                TlsSettings::server(vec![cert], key),
+            ))
+            .id();
+
+        let mut addr = None;
+        wait_for(|| {
+            app.update();
+            addr = app
+                .world()
+                .resource::<Events>()
+                .0
+                .iter()
+                .find_map(|(e, event)| {
+                    let port = event.strip_prefix("Listen(")?.rsplit(':').next()?;
+                    (*e == server).then(|| port.trim_end_matches(')').parse().ok())?
+                });
+            addr.is_some()
+        });
+
+        (app, SocketAddr::from(([127, 0, 0, 1], addr.unwrap())))
+    }
+
+    fn client(app: &mut App, addr: SocketAddr, root: CertificateDer<'static>) -> Entity {
+        app.world_mut()
+            .spawn((
+                NetworkBundle::new(CHANNEL),
+                ClientNode(WebsocketAddress::new(format!(
+                    "wss://localhost:{}",
+                    addr.port()
+                ))),
+                TlsSettings::client(vec![root]),
+            ))
+            .id()
+    }
+
+    fn peers(app: &mut App) -> Vec<Entity> {
+        app.world_mut()
+            .query_filtered::<Entity, With<NetworkPeer>>()
+            .iter(app.world())
+            .collect()
+    }
+
+    fn node(app: &App, entity: Entity) -> &NetworkNode {
+        app.world().get::<NetworkNode>(entity).unwrap()
+    }
+
+    #[test]
+    fn wss_round_trip_with_custom_root() {
+        let (cert, key) = certificate();
+        let (mut server_app, addr) = server(cert.clone(), key);
+        let mut client_app = app();
+        let client = client(&mut client_app, addr, cert);
+
+        let mut peer = None;
+        wait_for(|| {
+            server_app.update();
+            client_app.update();
+            peer = peers(&mut server_app).first().copied();
+            peer.is_some() && has_event(&client_app, client, "Connected")
+        });
+        let peer = peer.unwrap();
+        let url = &server_app
+            .world()
+            .get::<ClientNode<WebsocketAddress>>(peer)
+            .unwrap()
+            .url;
+        assert!(url.starts_with("wss://"), "{}", url);
+
+        node(&client_app, client).send_bytes(b"ping");
+        let mut received = None;
+        wait_for(|| {
+            received = node(&server_app, peer).try_recv();
+            received.is_some()
+        });
+        assert_eq!(&received.unwrap().bytes[..], b"ping");
+
+        node(&server_app, peer).send_bytes(b"pong");
+        let mut received = None;
+        wait_for(|| {
+            received = node(&client_app, client).try_recv();
+            received.is_some()
+        });
+        assert_eq!(&received.unwrap().bytes[..], b"pong");
+    }
+
+    #[test]
+    fn client_without_the_root_fails() {
+        let (cert, key) = certificate();
+        let (other, _) = certificate();
+        let (mut server_app, addr) = server(cert, key);
+        let mut client_app = app();
+        let client = client(&mut client_app, addr, other);
+
+        wait_for(|| {
+            server_app.update();
+            client_app.update();
+            has_event(&client_app, client, "Error(Tls(")
+        });
+        assert!(!has_event(&client_app, client, "Connected"));
+    }
+}
@@ -60,13 +60,16 @@ pub fn handle_message_events(
     mut ev_channels: MessageReader<ReceiveChannelMessage<PlayerInformation>>,
 ) {
     for event in ev_channels.read() {
-        info!("{} Received: {:?}", event.channel_id, &event.message);
+        info!(
+            "{} Received from {:?} {:?}: {:?}",
+            event.channel_id, event.entity, event.addr, &event.message
+        );
     }
 }
 
 pub fn handle_raw_packet(q_server: Query<(Entity, &ChannelId, &NetworkNode)>) {
     for (entity, channel_id, net_node) in q_server.iter() {
-        while let Ok(Some(packet)) = net_node.recv_message_channel.receiver.try_recv() {
+        while let Some(packet) = net_node.try_recv() {
             info!(
                 "{} {} Received bytes: {:?}",
                 channel_id, entity, packet.bytes
@@ -42,5 +42,7 @@ fn setup_clients(mut commands: Commands) {
     commands.spawn((
         NetworkBundle::new(BINCODE_CHANNEL),
         ClientNode(TcpAddress::new("127.0.0.1:5005")),
+        // bincode messages are length prefixed so they survive TCP fragmentation
+        FrameCodec::length_prefixed(LengthPrefix::U32),
     ));
 }
@@ -18,7 +18,10 @@ fn main() {
     app.add_transformer::<PlayerInformation, JsonTransformer>(JSON_CHANNEL)
         .add_transformer::<PlayerInformation, BincodeTransformer>(BINCODE_CHANNEL)
         .add_systems(Startup, setup_server)
-        .add_systems(Update, (handle_raw_packet, handle_message_events))
+        .add_systems(
+            Update,
+            (handle_raw_packet, handle_message_events, reply_to_sender),
+        )
         .add_systems(
             Update,
             (broadcast_message, send_json_message, send_channel_packet)
@@ -39,6 +42,8 @@ fn setup_server(mut commands: Commands) {
     commands.spawn((
         NetworkBundle::new(BINCODE_CHANNEL),
         ServerNode(TcpAddress::new("0.0.0.0:5005")),
+        // bincode messages are length prefixed so they survive TCP fragmentation
+        FrameCodec::length_prefixed(LengthPrefix::U32),
     ));
 }
 
@@ -61,14 +66,28 @@ fn broadcast_message(
 
             let child_net_node = q_child.get(child).expect("Child node not found.");
 
-            let _ = child_net_node
-                .send_message_channel
-                .sender
-                .try_send(NetworkRawPacket {
-                    addr: None,
-                    bytes: Bytes::from_static(message),
-                    text: None,
-                });
+            child_net_node.send(NetworkRawPacket {
+                addr: None,
+                bytes: Bytes::from_static(message),
+                text: None,
+            });
         }
     }
 }
+
+/// answer typed messages only to the peer that sent them
+fn reply_to_sender(
+    mut received: MessageReader<ReceiveChannelMessage<PlayerInformation>>,
+    mut replies: MessageWriter<SendTo<PlayerInformation>>,
+) {
+    for event in received.read() {
+        replies.write(SendTo::new(
+            event.channel_id,
+            SendTarget::Entity(event.entity),
+            PlayerInformation {
+                health: event.message.health + 1,
+                position: event.message.position,
+            },
+        ));
+    }
+}
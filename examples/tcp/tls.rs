@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, time::common_conditions::on_timer};
+
+use bevy_octopus::{
+    prelude::*,
+    tls::{CertificateDer, PrivateKeyDer},
+    transports::tcp::TcpAddress,
+};
+
+use crate::common::*;
+
+#[path = "../common/lib.rs"]
+mod common;
+
+fn main() {
+    let mut app = App::new();
+
+    shared_setup(&mut app);
+
+    app.add_transformer::<PlayerInformation, JsonTransformer>(JSON_CHANNEL)
+        .add_systems(Startup, setup)
+        .add_systems(Update, handle_message_events)
+        .add_systems(
+            Update,
+            send_json_message.run_if(on_timer(Duration::from_secs_f64(1.0))),
+        )
+        .run();
+}
+
+/// server and client in one app, secured with a self signed certificate
+fn setup(mut commands: Commands) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .expect("Failed to generate certificate");
+    let cert_der: CertificateDer<'static> = cert.cert.der().clone();
+    let key_der = PrivateKeyDer::try_from(cert.signing_key.serialize_der())
+        .expect("Failed to read private key");
+
+    commands.spawn((
+        NetworkBundle::new(JSON_CHANNEL),
+        ServerNode(TcpAddress::new("127.0.0.1:5006")),
+        FrameCodec::length_prefixed(LengthPrefix::U32),
+        TlsSettings::server(vec![cert_der.clone()], key_der),
+    ));
+
+    commands.spawn((
+        NetworkBundle::new(JSON_CHANNEL),
+        ClientNode(TcpAddress::new("127.0.0.1:5006")),
+        FrameCodec::length_prefixed(LengthPrefix::U32),
+        TlsSettings::client(vec![cert_der]).with_server_name("localhost"),
+    ));
+}
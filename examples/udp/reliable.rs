@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, time::common_conditions::on_timer};
+
+use bevy_octopus::{
+    prelude::*,
+    transports::udp::{
+        UdpAddress,
+        reliable::{Reliable, ReliableStats},
+    },
+};
+
+use crate::common::*;
+
+#[path = "../common/lib.rs"]
+mod common;
+
+fn main() {
+    let mut app = App::new();
+    shared_setup(&mut app);
+
+    app.add_transformer::<PlayerInformation, JsonTransformer>(JSON_CHANNEL)
+        .add_systems(Startup, setup)
+        .add_systems(Update, handle_message_events)
+        .add_systems(
+            Update,
+            (send_json_message, log_stats).run_if(on_timer(Duration::from_secs_f64(1.0))),
+        )
+        .run();
+}
+
+/// reliable ordered server and client in one app
+fn setup(mut commands: Commands) {
+    commands.spawn((
+        NetworkBundle::new(JSON_CHANNEL),
+        ServerNode(UdpAddress::new("127.0.0.1:6010")),
+        Reliable::ordered(),
+    ));
+
+    commands.spawn((
+        NetworkBundle::new(JSON_CHANNEL),
+        ServerNode(UdpAddress::new("127.0.0.1:0")),
+        ClientNode(UdpAddress::new("127.0.0.1:6010")),
+        Reliable::ordered(),
+    ));
+}
+
+fn log_stats(q_stats: Query<(Entity, &ReliableStats)>) {
+    for (entity, stats) in q_stats.iter() {
+        info!(
+            "{} delivered: {} lost: {} retransmitted: {} rtt: {:?}",
+            entity,
+            stats.delivered(),
+            stats.lost(),
+            stats.retransmitted(),
+            stats.rtt()
+        );
+    }
+}
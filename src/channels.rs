@@ -1,12 +1,18 @@
-use std::fmt::Display;
+use std::{fmt::Display, net::SocketAddr};
 
 use bevy::{
-    ecs::reflect::ReflectComponent,
-    prelude::{Component, Message, MessageReader, Query, Reflect},
+    ecs::{query::QueryData, reflect::ReflectComponent},
+    prelude::{ChildOf, Component, Entity, Has, Message, MessageReader, Query, Reflect},
 };
 use bytes::Bytes;
 
-use crate::network_node::{NetworkNode, NetworkRawPacket};
+use crate::{
+    client::ClientTag,
+    connection_state::ConnectionState,
+    network_node::{NetworkNode, NetworkRawPacket, RemoteAddr},
+    server::ServerNode,
+    transports::udp::UdpAddress,
+};
 
 /// Channel marker
 #[derive(Clone, PartialEq, Eq, Hash, Default, Component, Reflect, Copy, Debug)]
@@ -24,6 +30,8 @@ pub struct ChannelPacket {
     pub channel_id: ChannelId,
     pub bytes: Bytes,
     pub text: Option<String>,
+    /// Deliver only to matching nodes instead of every node in the channel
+    pub target: Option<SendTarget>,
 }
 
 impl ChannelPacket {
@@ -32,7 +40,78 @@ impl ChannelPacket {
             channel_id,
             bytes: Bytes::copy_from_slice(bytes),
             text: None,
+            target: None,
+        }
+    }
+
+    pub fn with_target(mut self, target: SendTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+}
+
+/// Receivers of a targeted send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTarget {
+    /// A single node, usually a [`NetworkPeer`](crate::network_node::NetworkPeer) of a server
+    Entity(Entity),
+    /// The peer connected from this address, or a datagram to it through a UDP node
+    Addr(SocketAddr),
+    /// All peers of a server entity
+    PeersOf(Entity),
+    /// All client nodes except this entity
+    AllExcept(Entity),
+}
+
+/// Node fields needed to resolve a [`SendTarget`]
+#[derive(QueryData)]
+pub(crate) struct TargetNode {
+    pub entity: Entity,
+    pub channel_id: &'static ChannelId,
+    pub net_node: &'static NetworkNode,
+    pub state: &'static ConnectionState,
+    pub client: Has<ClientTag>,
+    pub datagram: Has<ServerNode<UdpAddress>>,
+    pub remote_addr: Option<&'static RemoteAddr>,
+    pub parent: Option<&'static ChildOf>,
+}
+
+/// Nodes in `channel_id` matched by `target` with the packet address to send to
+pub(crate) fn resolve_target<'a>(
+    target: &SendTarget,
+    channel_id: &ChannelId,
+    nodes: impl Iterator<Item = TargetNodeItem<'a, 'a>>,
+) -> Vec<(Entity, &'a NetworkNode, Option<SocketAddr>)> {
+    let nodes = nodes.filter(|node| node.channel_id == channel_id);
+
+    match *target {
+        SendTarget::Entity(entity) => nodes
+            .filter(|node| node.entity == entity)
+            .map(|node| (node.entity, node.net_node, None))
+            .collect(),
+        SendTarget::Addr(addr) => {
+            let mut datagram_node = None;
+            let mut peers = vec![];
+            for node in nodes {
+                if node.remote_addr.is_some_and(|remote| **remote == addr) {
+                    peers.push((node.entity, node.net_node, None));
+                } else if node.datagram && datagram_node.is_none() {
+                    datagram_node = Some((node.entity, node.net_node, Some(addr)));
+                }
+            }
+            if peers.is_empty() {
+                peers.extend(datagram_node);
+            }
+            peers
         }
+        SendTarget::PeersOf(server) => nodes
+            .filter(|node| node.parent.is_some_and(|parent| parent.parent() == server))
+            .map(|node| (node.entity, node.net_node, None))
+            .collect(),
+        SendTarget::AllExcept(entity) => nodes
+            .filter(|node| node.client && node.entity != entity)
+            .map(|node| (node.entity, node.net_node, None))
+            .collect(),
     }
 }
 
@@ -51,10 +130,33 @@ impl<M> SendChannelMessage<M> {
     }
 }
 
+/// Send a message to the nodes matched by [`SendTarget`], it is encoded only once
+#[derive(Message, Debug)]
+pub struct SendTo<M> {
+    pub channel_id: ChannelId,
+    pub target: SendTarget,
+    pub message: M,
+}
+
+impl<M> SendTo<M> {
+    pub fn new(channel_id: ChannelId, target: SendTarget, message: M) -> Self {
+        Self {
+            channel_id,
+            target,
+            message,
+        }
+    }
+}
+
 #[derive(Message, Debug)]
 pub struct ReceiveChannelMessage<M> {
     pub channel_id: ChannelId,
     pub message: M,
+    /// Node the message arrived on, for server connections this is the
+    /// [`NetworkPeer`](crate::network_node::NetworkPeer) entity
+    pub entity: Entity,
+    /// Remote address of the sender if the transport knows it
+    pub addr: Option<SocketAddr>,
 }
 
 impl<M> ReceiveChannelMessage<M> {
@@ -62,18 +164,40 @@ impl<M> ReceiveChannelMessage<M> {
         Self {
             channel_id,
             message,
+            entity: Entity::PLACEHOLDER,
+            addr: None,
         }
     }
+
+    pub fn with_sender(mut self, entity: Entity, addr: Option<SocketAddr>) -> Self {
+        self.entity = entity;
+        self.addr = addr;
+        self
+    }
 }
 
 pub(crate) fn send_channel_message_system(
     q_net: Query<(&ChannelId, &NetworkNode)>,
+    q_target: Query<TargetNode>,
     mut channel_events: MessageReader<ChannelPacket>,
 ) {
     for channel_ev in channel_events.read() {
+        if let Some(target) = &channel_ev.target {
+            for (_, net_node, addr) in
+                resolve_target(target, &channel_ev.channel_id, q_target.iter())
+            {
+                net_node.send(NetworkRawPacket {
+                    bytes: channel_ev.bytes.clone(),
+                    addr,
+                    text: channel_ev.text.clone(),
+                });
+            }
+            continue;
+        }
+
         q_net.par_iter().for_each(|(channel_id, net_node)| {
             if channel_id == &channel_ev.channel_id {
-                let _ = net_node.send_message_channel.sender.send(NetworkRawPacket {
+                net_node.send(NetworkRawPacket {
                     bytes: channel_ev.bytes.clone(),
                     addr: None,
                     text: channel_ev.text.clone(),
@@ -82,3 +206,120 @@ pub(crate) fn send_channel_message_system(
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::World;
+
+    use super::*;
+
+    const CHANNEL: ChannelId = ChannelId("game");
+
+    struct Nodes {
+        world: World,
+        server: Entity,
+        peers: [Entity; 2],
+        client: Entity,
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// A UDP server with two peers and a client in another channel
+    fn nodes() -> Nodes {
+        let mut world = World::new();
+        let open = || (CHANNEL, NetworkNode::default(), ConnectionState::Connected);
+        let server = world
+            .spawn((open(), ServerNode(UdpAddress::new("127.0.0.1:5000"))))
+            .id();
+        let peers = [6001, 6002].map(|port| {
+            world
+                .spawn((open(), ClientTag, RemoteAddr(addr(port)), ChildOf(server)))
+                .id()
+        });
+        let client = world
+            .spawn((
+                ChannelId("other"),
+                NetworkNode::default(),
+                ConnectionState::Connected,
+                ClientTag,
+            ))
+            .id();
+
+        Nodes {
+            world,
+            server,
+            peers,
+            client,
+        }
+    }
+
+    fn resolve(nodes: &mut Nodes, target: SendTarget) -> Vec<(Entity, Option<SocketAddr>)> {
+        let mut query = nodes.world.query::<TargetNode>();
+        resolve_target(&target, &CHANNEL, query.iter(&nodes.world))
+            .into_iter()
+            .map(|(entity, _, addr)| (entity, addr))
+            .collect()
+    }
+
+    #[test]
+    fn entity() {
+        let mut nodes = nodes();
+        let peer = nodes.peers[0];
+
+        assert_eq!(
+            resolve(&mut nodes, SendTarget::Entity(peer)),
+            [(peer, None)]
+        );
+        // nodes of other channels are never matched
+        let client = nodes.client;
+        assert!(resolve(&mut nodes, SendTarget::Entity(client)).is_empty());
+    }
+
+    #[test]
+    fn addr_prefers_peers() {
+        let mut nodes = nodes();
+        let peer = nodes.peers[1];
+
+        assert_eq!(
+            resolve(&mut nodes, SendTarget::Addr(addr(6002))),
+            [(peer, None)]
+        );
+    }
+
+    #[test]
+    fn addr_falls_back_to_datagram_node() {
+        let mut nodes = nodes();
+        let server = nodes.server;
+
+        assert_eq!(
+            resolve(&mut nodes, SendTarget::Addr(addr(7000))),
+            [(server, Some(addr(7000)))]
+        );
+    }
+
+    #[test]
+    fn peers_of() {
+        let mut nodes = nodes();
+        let (server, peers) = (nodes.server, nodes.peers);
+
+        let mut resolved = resolve(&mut nodes, SendTarget::PeersOf(server));
+        let mut expected = peers.map(|peer| (peer, None));
+        resolved.sort();
+        expected.sort();
+        assert_eq!(resolved, expected);
+        assert!(resolve(&mut nodes, SendTarget::PeersOf(peers[0])).is_empty());
+    }
+
+    #[test]
+    fn all_except() {
+        let mut nodes = nodes();
+        let peers = nodes.peers;
+
+        assert_eq!(
+            resolve(&mut nodes, SendTarget::AllExcept(peers[0])),
+            [(peers[1], None)]
+        );
+    }
+}
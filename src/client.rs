@@ -1,11 +1,14 @@
 use crate::{
     error::NetworkError,
-    network_node::{NetworkAddress, NetworkEvent, NetworkPeer, NodeEvent},
+    network_node::{
+        Closing, DisconnectReason, NetworkAddress, NetworkEvent, NetworkPeer, NodeEvent,
+    },
 };
 use bevy::{
     ecs::component::{Immutable, StorageType},
     prelude::*,
 };
+use std::time::Duration;
 
 pub(super) fn plugin(app: &mut App) {
     app.register_type::<ReconnectSetting>()
@@ -37,11 +40,20 @@ pub struct StartClient {
     pub entity: Entity,
 }
 
+/// Retry policy of a client node, a fixed delay unless backoff or jitter are set
 #[derive(Debug, Component, Reflect)]
 #[reflect(Component)]
 pub struct ReconnectSetting {
-    /// Delay in seconds
+    /// Delay in seconds before the first retry
     pub delay: f32,
+    /// Factor applied to the delay after every failed attempt
+    pub multiplier: f32,
+    /// Upper bound of the delay in seconds
+    pub max_delay: f32,
+    /// Fraction of the delay randomly added or removed, between 0 and 1
+    pub jitter: f32,
+    /// Seconds to wait for a connection before the attempt fails
+    pub connect_timeout: f32,
     pub max_retries: usize,
     pub retries: usize,
 }
@@ -50,37 +62,102 @@ impl Default for ReconnectSetting {
     fn default() -> Self {
         Self {
             delay: 2.0,
+            multiplier: 1.0,
+            max_delay: 60.0,
+            jitter: 0.0,
+            connect_timeout: 10.0,
             max_retries: usize::MAX,
             retries: 0,
         }
     }
 }
 
+impl ReconnectSetting {
+    /// Retry after a fixed delay, the default waits 2 seconds
+    pub fn fixed(delay: f32) -> Self {
+        Self { delay, ..default() }
+    }
+
+    /// Multiply the delay after every failed attempt, up to `max_delay`
+    pub fn with_backoff(mut self, multiplier: f32, max_delay: f32) -> Self {
+        self.multiplier = multiplier;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f32) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: f32) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Delay before retry number `attempt`, starting at 1
+    pub fn delay_for(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let delay = (self.delay * self.multiplier.powi(exponent)).min(self.max_delay);
+        let jitter = delay * self.jitter * (fastrand::f32() * 2.0 - 1.0);
+
+        Duration::from_secs_f32((delay + jitter).max(0.0))
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs_f32(self.connect_timeout.max(0.0))
+    }
+}
+
+#[allow(clippy::type_complexity)]
 pub(crate) fn client_reconnect(
     on: On<NodeEvent>,
     mut commands: Commands,
-    mut q_net: Query<&mut ReconnectSetting, Without<NetworkPeer>>,
+    // closed nodes stay down, whatever their transport reports while flushing
+    mut q_net: Query<
+        (&mut ReconnectSetting, Has<ReconnectTimer>),
+        (Without<NetworkPeer>, Without<Closing>),
+    >,
 ) {
     let ev = on.event();
-    if let Ok(mut reconnect) = q_net.get_mut(ev.entity) {
-        let event = &ev.event;
-        if reconnect.retries < reconnect.max_retries {
-            reconnect.retries += 1;
-        } else {
-            return;
-        }
-        match event {
-            NetworkEvent::Listen | NetworkEvent::Connected => reconnect.retries = 0,
-            NetworkEvent::Disconnected | NetworkEvent::Error(NetworkError::Connection(_)) => {
-                commands
-                    .entity(ev.entity)
-                    .insert(ReconnectTimer(Timer::from_seconds(
-                        reconnect.delay,
-                        TimerMode::Once,
-                    )));
+    let Ok((mut reconnect, waiting)) = q_net.get_mut(ev.entity) else {
+        return;
+    };
+    match &ev.event {
+        NetworkEvent::Listen(_) | NetworkEvent::Connected => reconnect.retries = 0,
+        // closed on purpose, by us or the server
+        NetworkEvent::Disconnected(DisconnectReason::Closed | DisconnectReason::Kicked(_)) => {}
+        // an error is usually followed by a disconnect, schedule one retry for both
+        NetworkEvent::Disconnected(_)
+        | NetworkEvent::Error(NetworkError::Connection(_) | NetworkError::Tls(_))
+            if !waiting =>
+        {
+            if reconnect.retries >= reconnect.max_retries {
+                commands.trigger(NodeEvent {
+                    entity: ev.entity,
+                    event: NetworkEvent::ReconnectExhausted,
+                });
+                return;
             }
-            _ => {}
+            reconnect.retries += 1;
+            let next_delay = reconnect.delay_for(reconnect.retries);
+            commands
+                .entity(ev.entity)
+                .insert(ReconnectTimer(Timer::new(next_delay, TimerMode::Once)));
+            commands.trigger(NodeEvent {
+                entity: ev.entity,
+                event: NetworkEvent::Reconnecting {
+                    attempt: reconnect.retries,
+                    next_delay,
+                },
+            });
         }
+        _ => {}
     }
 }
 
@@ -110,10 +187,56 @@ pub(crate) fn cleanup_client_session(
         let event = &ev.event;
 
         match event {
-            NetworkEvent::Disconnected | NetworkEvent::Error(NetworkError::Connection(_)) => {
-                commands.entity(entity).despawn();
+            NetworkEvent::Disconnected(_)
+            | NetworkEvent::Error(NetworkError::Connection(_) | NetworkError::Tls(_)) => {
+                commands.entity(entity).try_despawn();
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(delay: Duration) -> f32 {
+        delay.as_secs_f32()
+    }
+
+    #[test]
+    fn default_delay_is_fixed() {
+        let reconnect = ReconnectSetting::default();
+
+        for attempt in [1, 2, 10, 1000] {
+            assert_eq!(reconnect.delay_for(attempt), Duration::from_secs(2));
+        }
+        assert_eq!(
+            ReconnectSetting::fixed(0.5).delay_for(5),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max_delay() {
+        let reconnect = ReconnectSetting::fixed(1.0).with_backoff(2.0, 10.0);
+
+        let delays: Vec<f32> = (1..=6)
+            .map(|attempt| secs(reconnect.delay_for(attempt)))
+            .collect();
+        assert_eq!(delays, [1.0, 2.0, 4.0, 8.0, 10.0, 10.0]);
+        assert_eq!(secs(reconnect.delay_for(0)), 1.0);
+        assert_eq!(secs(reconnect.delay_for(usize::MAX)), 10.0);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let reconnect = ReconnectSetting::fixed(4.0).with_jitter(0.25);
+
+        for _ in 0..100 {
+            let delay = secs(reconnect.delay_for(1));
+            assert!((3.0..=5.0).contains(&delay), "{delay}");
+        }
+        assert_eq!(ReconnectSetting::default().with_jitter(3.0).jitter, 1.0);
+    }
+}
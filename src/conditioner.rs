@@ -0,0 +1,423 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::{
+        Arc,
+        atomic::{self, AtomicU64},
+    },
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use bevy::prelude::*;
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use kanal::{AsyncReceiver, AsyncSender};
+
+use crate::network_node::{AsyncChannel, NetworkRawPacket};
+
+/// Degrade the traffic of a node to test under bad networks.
+///
+/// Packets between the node channels and its transport get latency, jitter,
+/// loss, duplication, reordering and a bandwidth cap, in both directions.
+/// Reliable UDP conditions its datagrams below the reliability layer, acks
+/// and retransmits included. Insert it together with the `ServerNode` or
+/// `ClientNode`, peers of a server inherit it with a seed of their own.
+///
+/// Every packet draws its decisions from `seed` and its position in the
+/// traffic, the same seed and packets get the same drops, duplicates,
+/// delays and swaps however the tasks are scheduled. Only the wall clock
+/// time a packet is released at depends on the machine. Packets still
+/// delayed when the node closes are lost.
+#[derive(Component, Debug, Clone, Default, Reflect)]
+#[reflect(Component)]
+pub struct NetworkConditioner {
+    /// Delay added to every packet
+    pub latency: Duration,
+    /// Largest random deviation from `latency`, packets keep their order
+    pub jitter: Duration,
+    /// Chance between 0 and 1 a packet is dropped
+    pub loss: f32,
+    /// Chance between 0 and 1 a packet is delivered twice
+    pub duplicate: f32,
+    /// Chance between 0 and 1 a packet is delivered after the next one
+    pub reorder: f32,
+    /// Bytes per second in each direction, unlimited when `None`
+    pub bandwidth: Option<u32>,
+    pub seed: u64,
+    /// Peers created with this conditioner so far
+    #[reflect(ignore)]
+    peers: Arc<AtomicU64>,
+}
+
+impl NetworkConditioner {
+    pub fn new(latency: Duration, jitter: Duration) -> Self {
+        Self {
+            latency,
+            jitter,
+            ..default()
+        }
+    }
+
+    pub fn with_loss(mut self, loss: f32) -> Self {
+        self.loss = loss.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_duplicate(mut self, duplicate: f32) -> Self {
+        self.duplicate = duplicate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_reorder(mut self, reorder: f32) -> Self {
+        self.reorder = reorder.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_second: u32) -> Self {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Conditioner of the next peer of a server, seeded from `seed` and the
+    /// order the peers connected in
+    pub fn for_peer(&self) -> Self {
+        let peer = self.peers.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+
+        Self {
+            seed: mix(self.seed, peer),
+            peers: Default::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Condition the packets between a node and its transport.
+    ///
+    /// Takes the node side `recv_tx` and `message_rx` and returns the ones
+    /// the transport uses instead.
+    pub fn spawn_layer(
+        &self,
+        recv_tx: AsyncSender<NetworkRawPacket>,
+        message_rx: AsyncReceiver<NetworkRawPacket>,
+    ) -> (
+        AsyncSender<NetworkRawPacket>,
+        AsyncReceiver<NetworkRawPacket>,
+    ) {
+        let inbound: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+        let outbound: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+        let inbound_link = Link::new(self.clone(), mix(self.seed, 0));
+        let outbound_link = Link::new(self.clone(), mix(self.seed, 1));
+
+        task::spawn(condition(
+            inbound_link,
+            inbound.receiver.clone_async(),
+            recv_tx,
+        ));
+        task::spawn(condition(
+            outbound_link,
+            message_rx,
+            outbound.sender.clone_async(),
+        ));
+
+        (
+            inbound.sender.clone_async(),
+            outbound.receiver.clone_async(),
+        )
+    }
+}
+
+/// Seed of the stream `n` of `seed`, splitmix64
+fn mix(seed: u64, n: u64) -> u64 {
+    let mut z = seed ^ n.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Forward packets from `input` to `output` once their delay passed
+async fn condition(
+    mut link: Link,
+    input: AsyncReceiver<NetworkRawPacket>,
+    output: AsyncSender<NetworkRawPacket>,
+) {
+    loop {
+        let now = Instant::now();
+        while let Some(packet) = link.pop_due(now) {
+            if output.send(packet).await.is_err() {
+                return;
+            }
+        }
+
+        let next_at = link.next_at();
+        let packet = input.recv();
+        let wait = async move {
+            match next_at {
+                Some(at) => task::sleep(at.saturating_duration_since(now)).await,
+                None => future::pending().await,
+            }
+        };
+        pin_mut!(packet, wait);
+        match future::select(packet, wait).await {
+            Either::Left((Ok(packet), _)) => link.push(packet, Instant::now()),
+            Either::Left((Err(_), _)) => return,
+            Either::Right(_) => {}
+        }
+    }
+}
+
+struct Scheduled {
+    at: Instant,
+    seq: u64,
+    packet: NetworkRawPacket,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// Conditions of one direction
+struct Link {
+    settings: NetworkConditioner,
+    seed: u64,
+    /// Packets pushed so far, picks the random numbers of the next one
+    pushed: u64,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+    /// Release time of the last packet kept in order
+    last_at: Option<Instant>,
+    /// When the bandwidth cap allows the next packet
+    free_at: Option<Instant>,
+    /// Packet waiting to be released after the next one
+    held: Option<(Instant, NetworkRawPacket)>,
+}
+
+impl Link {
+    fn new(settings: NetworkConditioner, seed: u64) -> Self {
+        Self {
+            settings,
+            seed,
+            pushed: 0,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            last_at: None,
+            free_at: None,
+            held: None,
+        }
+    }
+
+    fn push(&mut self, packet: NetworkRawPacket, now: Instant) {
+        // decisions of a packet never depend on those of the packets before
+        self.pushed += 1;
+        let mut rng = fastrand::Rng::with_seed(mix(self.seed, self.pushed));
+        if rng.f32() < self.settings.loss {
+            trace!("conditioner dropped {} bytes", packet.bytes.len());
+            return;
+        }
+        if rng.f32() < self.settings.duplicate {
+            self.schedule(packet.clone(), now, &mut rng);
+        }
+        self.schedule(packet, now, &mut rng);
+    }
+
+    fn schedule(&mut self, packet: NetworkRawPacket, now: Instant, rng: &mut fastrand::Rng) {
+        let mut at = now;
+        if let Some(bandwidth) = self.settings.bandwidth {
+            let start = self.free_at.map_or(now, |free_at| free_at.max(now));
+            let transfer = packet.bytes.len() as f64 / bandwidth.max(1) as f64;
+            at = start + Duration::from_secs_f64(transfer);
+            self.free_at = Some(at);
+        }
+        let jitter = self.settings.jitter.as_secs_f64() * (rng.f64() * 2.0 - 1.0);
+        at += Duration::from_secs_f64((self.settings.latency.as_secs_f64() + jitter).max(0.0));
+
+        let reorder = rng.f32() < self.settings.reorder;
+        if self.held.is_none() && reorder {
+            self.held = Some((at, packet));
+            return;
+        }
+
+        let at = self.last_at.map_or(at, |last_at| at.max(last_at));
+        self.last_at = Some(at);
+        self.enqueue(at, packet);
+        if let Some((held_at, held)) = self.held.take() {
+            self.enqueue(held_at.max(at), held);
+        }
+    }
+
+    fn enqueue(&mut self, at: Instant, packet: NetworkRawPacket) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled {
+            at,
+            seq: self.seq,
+            packet,
+        }));
+    }
+
+    /// Earliest release, a held packet goes out on its own once due
+    fn next_at(&self) -> Option<Instant> {
+        let queued = self.queue.peek().map(|Reverse(scheduled)| scheduled.at);
+        let held = self.held.as_ref().map(|(at, _)| *at);
+        queued.into_iter().chain(held).min()
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<NetworkRawPacket> {
+        if self
+            .queue
+            .peek()
+            .is_some_and(|Reverse(scheduled)| scheduled.at <= now)
+        {
+            return self.queue.pop().map(|Reverse(scheduled)| scheduled.packet);
+        }
+        if self.held.as_ref().is_some_and(|(at, _)| *at <= now) {
+            return self.held.take().map(|(_, packet)| packet);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn packet(id: u8) -> NetworkRawPacket {
+        NetworkRawPacket {
+            addr: None,
+            bytes: vec![id; 100].into(),
+            text: None,
+        }
+    }
+
+    /// Ids of the packets released until `until`, checked every millisecond
+    fn drain(link: &mut Link, start: Instant, until: Duration) -> Vec<(u8, Duration)> {
+        let mut released = vec![];
+        let mut elapsed = Duration::ZERO;
+        while elapsed <= until {
+            while let Some(packet) = link.pop_due(start + elapsed) {
+                released.push((packet.bytes[0], elapsed));
+            }
+            elapsed += MS;
+        }
+
+        released
+    }
+
+    fn ids(released: &[(u8, Duration)]) -> Vec<u8> {
+        released.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn latency_delays_packets() {
+        let start = Instant::now();
+        let mut link = Link::new(NetworkConditioner::new(20 * MS, Duration::ZERO), 0);
+        link.push(packet(1), start);
+        link.push(packet(2), start + 5 * MS);
+
+        assert!(link.pop_due(start + 19 * MS).is_none());
+        assert_eq!(link.next_at(), Some(start + 20 * MS));
+        assert_eq!(
+            drain(&mut link, start, 30 * MS),
+            [(1, 20 * MS), (2, 25 * MS)]
+        );
+    }
+
+    #[test]
+    fn jitter_keeps_order() {
+        let start = Instant::now();
+        let mut link = Link::new(NetworkConditioner::new(50 * MS, 40 * MS), 7);
+        for id in 0..50 {
+            link.push(packet(id), start + u32::from(id) * MS);
+        }
+
+        let released = drain(&mut link, start, 200 * MS);
+        assert_eq!(ids(&released), (0..50).collect::<Vec<_>>());
+        assert!(released.iter().all(|(_, at)| *at >= 10 * MS));
+    }
+
+    #[test]
+    fn bandwidth_spaces_packets() {
+        let start = Instant::now();
+        let settings = NetworkConditioner::default().with_bandwidth(10_000);
+        let mut link = Link::new(settings, 0);
+        link.push(packet(1), start);
+        link.push(packet(2), start);
+
+        assert_eq!(
+            drain(&mut link, start, 30 * MS),
+            [(1, 10 * MS), (2, 20 * MS)]
+        );
+    }
+
+    #[test]
+    fn reordered_packets_follow_the_next_one() {
+        let start = Instant::now();
+        let settings = NetworkConditioner::new(10 * MS, Duration::ZERO).with_reorder(1.0);
+        let mut link = Link::new(settings, 0);
+        link.push(packet(1), start);
+        link.push(packet(2), start + MS);
+
+        assert_eq!(ids(&drain(&mut link, start, 20 * MS)), [2, 1]);
+
+        // without a next packet the held one goes out once due
+        link.push(packet(3), start + 30 * MS);
+        assert_eq!(ids(&drain(&mut link, start, 50 * MS)), [3]);
+    }
+
+    #[test]
+    fn decisions_do_not_depend_on_timing() {
+        let settings = NetworkConditioner::new(5 * MS, 5 * MS)
+            .with_loss(0.3)
+            .with_duplicate(0.3);
+        let run = |seed: u64, spacing: Duration| {
+            let start = Instant::now();
+            let mut link = Link::new(settings.clone(), seed);
+            for id in 0..100 {
+                link.push(packet(id), start + u32::from(id) * spacing);
+            }
+            ids(&drain(&mut link, start, 100 * spacing + 20 * MS))
+        };
+
+        let delivered = run(1, MS);
+        assert!(delivered.len() < 100);
+        assert!(delivered.windows(2).any(|pair| pair[0] == pair[1]));
+        assert_eq!(run(1, 20 * MS), delivered);
+        assert_ne!(run(2, MS), delivered);
+    }
+
+    #[test]
+    fn peers_get_their_own_seeds() {
+        let server = NetworkConditioner::default().with_seed(42);
+        let first = server.for_peer();
+        let second = server.for_peer();
+
+        assert_ne!(first.seed, server.seed);
+        assert_ne!(first.seed, second.seed);
+        assert_eq!(first.seed, mix(42, 1));
+        assert_eq!(first.for_peer().seed, mix(first.seed, 1));
+    }
+}
@@ -0,0 +1,230 @@
+use bevy::prelude::*;
+
+use crate::{
+    client::StartClient,
+    network_node::{NetworkEvent, NodeEvent},
+    server::StartServer,
+};
+
+pub(super) fn plugin(app: &mut App) {
+    app.register_type::<ConnectionState>()
+        .add_observer(on_start_client)
+        .add_observer(on_start_server)
+        .add_observer(on_node_event);
+}
+
+/// Lifecycle of a [`NetworkNode`](crate::network_node::NetworkNode), required
+/// by it and only written when the state actually changes
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Component)]
+pub enum ConnectionState {
+    #[default]
+    Idle,
+    /// A server is binding its socket
+    Binding,
+    Listening,
+    /// A client is connecting or waiting for its next reconnect
+    Connecting,
+    Connected,
+    /// Flushing queued sends before closing
+    Disconnecting,
+    Disconnected,
+    /// Binding or connecting failed, or the client gave up reconnecting
+    Failed,
+}
+
+impl ConnectionState {
+    /// Whether the node can send, listening or connected
+    pub fn is_open(&self) -> bool {
+        matches!(self, Self::Listening | Self::Connected)
+    }
+}
+
+/// Run condition true while any node is in `state`
+pub fn in_connection_state(
+    state: ConnectionState,
+) -> impl FnMut(Query<&ConnectionState>) -> bool + Clone {
+    move |q_state: Query<&ConnectionState>| q_state.iter().any(|current| *current == state)
+}
+
+/// Run condition true when a node entered `state` since the system last ran
+pub fn entered_connection_state(
+    state: ConnectionState,
+) -> impl FnMut(Query<&ConnectionState, Changed<ConnectionState>>) -> bool + Clone {
+    move |q_state: Query<&ConnectionState, Changed<ConnectionState>>| {
+        q_state.iter().any(|current| *current == state)
+    }
+}
+
+fn on_start_client(on: On<StartClient>, mut q_state: Query<&mut ConnectionState>) {
+    if let Ok(mut state) = q_state.get_mut(on.event().entity) {
+        state.set_if_neq(ConnectionState::Connecting);
+    }
+}
+
+fn on_start_server(on: On<StartServer>, mut q_state: Query<&mut ConnectionState>) {
+    if let Ok(mut state) = q_state.get_mut(on.event().entity) {
+        state.set_if_neq(ConnectionState::Binding);
+    }
+}
+
+fn on_node_event(on: On<NodeEvent>, mut q_state: Query<&mut ConnectionState>) {
+    let ev = on.event();
+    let Ok(mut state) = q_state.get_mut(ev.entity) else {
+        return;
+    };
+    let next = match &ev.event {
+        NetworkEvent::Listen(_) => ConnectionState::Listening,
+        NetworkEvent::Connected => ConnectionState::Connected,
+        NetworkEvent::Disconnected(_) => ConnectionState::Disconnected,
+        NetworkEvent::Error(_)
+            if matches!(
+                *state,
+                ConnectionState::Binding | ConnectionState::Connecting
+            ) =>
+        {
+            ConnectionState::Failed
+        }
+        NetworkEvent::Reconnecting { .. } => ConnectionState::Connecting,
+        NetworkEvent::ReconnectExhausted => ConnectionState::Failed,
+        NetworkEvent::Error(_) | NetworkEvent::PacketsDropped { .. } => return,
+    };
+    state.set_if_neq(next);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::*;
+    use crate::{error::NetworkError, network_node::DisconnectReason};
+
+    #[derive(Resource, Default)]
+    struct Ran(Vec<&'static str>);
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.init_resource::<Ran>().add_plugins(plugin).add_systems(
+            Update,
+            (
+                (|mut ran: ResMut<Ran>| ran.0.push("connected"))
+                    .run_if(in_connection_state(ConnectionState::Connected)),
+                (|mut ran: ResMut<Ran>| ran.0.push("entered connected"))
+                    .run_if(entered_connection_state(ConnectionState::Connected)),
+            )
+                .chain(),
+        );
+        app
+    }
+
+    fn trigger(app: &mut App, entity: Entity, event: NetworkEvent) {
+        app.world_mut().trigger(NodeEvent { entity, event });
+    }
+
+    fn state(app: &App, entity: Entity) -> ConnectionState {
+        *app.world().get::<ConnectionState>(entity).unwrap()
+    }
+
+    #[test]
+    fn client_lifecycle() {
+        let mut app = app();
+        let entity = app.world_mut().spawn(ConnectionState::default()).id();
+
+        app.world_mut().trigger(StartClient { entity });
+        assert_eq!(state(&app, entity), ConnectionState::Connecting);
+        trigger(
+            &mut app,
+            entity,
+            NetworkEvent::Error(NetworkError::Connection("refused".into())),
+        );
+        assert_eq!(state(&app, entity), ConnectionState::Failed);
+        trigger(
+            &mut app,
+            entity,
+            NetworkEvent::Reconnecting {
+                attempt: 1,
+                next_delay: Duration::ZERO,
+            },
+        );
+        assert_eq!(state(&app, entity), ConnectionState::Connecting);
+        trigger(&mut app, entity, NetworkEvent::Connected);
+        assert_eq!(state(&app, entity), ConnectionState::Connected);
+
+        // errors of an open connection and queue reports keep the state
+        trigger(
+            &mut app,
+            entity,
+            NetworkEvent::Error(NetworkError::Common("bad message".into())),
+        );
+        trigger(
+            &mut app,
+            entity,
+            NetworkEvent::PacketsDropped {
+                sent: 1,
+                received: 0,
+            },
+        );
+        assert_eq!(state(&app, entity), ConnectionState::Connected);
+
+        trigger(
+            &mut app,
+            entity,
+            NetworkEvent::Disconnected(DisconnectReason::RemoteClosed),
+        );
+        assert_eq!(state(&app, entity), ConnectionState::Disconnected);
+        trigger(&mut app, entity, NetworkEvent::ReconnectExhausted);
+        assert_eq!(state(&app, entity), ConnectionState::Failed);
+    }
+
+    #[test]
+    fn server_lifecycle() {
+        let mut app = app();
+        let entity = app.world_mut().spawn(ConnectionState::default()).id();
+
+        app.world_mut().trigger(StartServer { entity });
+        assert_eq!(state(&app, entity), ConnectionState::Binding);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6000));
+        trigger(&mut app, entity, NetworkEvent::Listen(addr));
+        assert_eq!(state(&app, entity), ConnectionState::Listening);
+        assert!(state(&app, entity).is_open());
+
+        let failed = app.world_mut().spawn(ConnectionState::default()).id();
+        app.world_mut().trigger(StartServer { entity: failed });
+        trigger(
+            &mut app,
+            failed,
+            NetworkEvent::Error(NetworkError::Common("address in use".into())),
+        );
+        assert_eq!(state(&app, failed), ConnectionState::Failed);
+        assert!(!state(&app, failed).is_open());
+    }
+
+    #[test]
+    fn run_conditions_follow_the_state() {
+        let mut app = app();
+        let entity = app.world_mut().spawn(ConnectionState::default()).id();
+        let ran = |app: &mut App| std::mem::take(&mut app.world_mut().resource_mut::<Ran>().0);
+
+        app.update();
+        assert!(ran(&mut app).is_empty());
+
+        trigger(&mut app, entity, NetworkEvent::Connected);
+        app.update();
+        assert_eq!(ran(&mut app), ["connected", "entered connected"]);
+        app.update();
+        assert_eq!(ran(&mut app), ["connected"]);
+
+        // the same state again is not a change
+        trigger(&mut app, entity, NetworkEvent::Connected);
+        app.update();
+        assert_eq!(ran(&mut app), ["connected"]);
+
+        trigger(
+            &mut app,
+            entity,
+            NetworkEvent::Disconnected(DisconnectReason::Closed),
+        );
+        app.update();
+        assert!(ran(&mut app).is_empty());
+    }
+}
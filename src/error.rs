@@ -13,6 +13,12 @@ pub enum NetworkError {
     SerializeError(String),
     #[error("Failed to deserialize data: {0}")]
     DeserializeError(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("Frame of {size} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge { size: usize, max: usize },
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
     #[error("Failed to read/write file(s)")]
     IoError(#[from] io::Error),
 }
@@ -0,0 +1,471 @@
+use std::{fmt::Debug, sync::Arc};
+
+use bevy::prelude::*;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::error::NetworkError;
+
+/// Default upper bound for a single frame payload (1 MiB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Splits a byte stream into whole messages and adds framing to outgoing messages.
+pub trait Framer: Debug + Send + Sync + 'static {
+    /// Append `payload` with its framing to `dst`
+    fn encode(
+        &self,
+        payload: &[u8],
+        dst: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<(), NetworkError>;
+
+    /// Try to take one complete frame payload from the front of `src`.
+    ///
+    /// Returns `Ok(None)` when more bytes are needed.
+    fn decode(
+        &self,
+        src: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<Option<Bytes>, NetworkError>;
+}
+
+/// Stream framing for a node, frames are reassembled on read and added on write.
+///
+/// Without this component stream transports forward every socket read as is.
+#[derive(Component, Clone, Debug)]
+pub struct FrameCodec {
+    pub framer: Arc<dyn Framer>,
+    pub max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(framer: impl Framer) -> Self {
+        Self {
+            framer: Arc::new(framer),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn length_prefixed(prefix: LengthPrefix) -> Self {
+        Self::new(LengthPrefixed(prefix))
+    }
+
+    pub fn newline_delimited() -> Self {
+        Self::new(NewlineDelimited)
+    }
+
+    pub fn cobs() -> Self {
+        Self::new(Cobs)
+    }
+
+    pub fn encode(&self, payload: &[u8], dst: &mut BytesMut) -> Result<(), NetworkError> {
+        self.framer.encode(payload, dst, self.max_frame_size)
+    }
+
+    pub fn decode(&self, src: &mut BytesMut) -> Result<Option<Bytes>, NetworkError> {
+        self.framer.decode(src, self.max_frame_size)
+    }
+}
+
+fn check_frame_size(size: usize, max: usize) -> Result<(), NetworkError> {
+    if size > max {
+        Err(NetworkError::FrameTooLarge { size, max })
+    } else {
+        Ok(())
+    }
+}
+
+/// Encoding of the length header, `U16` and `U32` are big endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    U16,
+    U32,
+    /// LEB128 encoded unsigned integer
+    Varint,
+}
+
+/// Every frame starts with the payload length
+#[derive(Debug, Clone, Copy)]
+pub struct LengthPrefixed(pub LengthPrefix);
+
+impl Framer for LengthPrefixed {
+    fn encode(
+        &self,
+        payload: &[u8],
+        dst: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<(), NetworkError> {
+        check_frame_size(payload.len(), max_frame_size)?;
+        match self.0 {
+            LengthPrefix::U16 => {
+                let len =
+                    u16::try_from(payload.len()).map_err(|_| NetworkError::FrameTooLarge {
+                        size: payload.len(),
+                        max: u16::MAX as usize,
+                    })?;
+                dst.put_u16(len);
+            }
+            LengthPrefix::U32 => {
+                let len =
+                    u32::try_from(payload.len()).map_err(|_| NetworkError::FrameTooLarge {
+                        size: payload.len(),
+                        max: u32::MAX as usize,
+                    })?;
+                dst.put_u32(len);
+            }
+            LengthPrefix::Varint => {
+                let mut len = payload.len() as u64;
+                while len >= 0x80 {
+                    dst.put_u8((len as u8) | 0x80);
+                    len >>= 7;
+                }
+                dst.put_u8(len as u8);
+            }
+        }
+        dst.extend_from_slice(payload);
+
+        Ok(())
+    }
+
+    fn decode(
+        &self,
+        src: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<Option<Bytes>, NetworkError> {
+        let (header_len, len) = match self.0 {
+            LengthPrefix::U16 => {
+                if src.len() < 2 {
+                    return Ok(None);
+                }
+                (2, u16::from_be_bytes([src[0], src[1]]) as usize)
+            }
+            LengthPrefix::U32 => {
+                if src.len() < 4 {
+                    return Ok(None);
+                }
+                (
+                    4,
+                    u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize,
+                )
+            }
+            LengthPrefix::Varint => {
+                let mut len: u64 = 0;
+                let mut header_len = None;
+                for (i, byte) in src.iter().enumerate().take(10) {
+                    // the 10th byte only holds the highest bit of a u64
+                    if i == 9 && *byte > 1 {
+                        return Err(NetworkError::InvalidFrame(
+                            "varint length header overflow".to_string(),
+                        ));
+                    }
+                    len |= ((byte & 0x7f) as u64) << (7 * i);
+                    if byte & 0x80 == 0 {
+                        header_len = Some(i + 1);
+                        break;
+                    }
+                }
+                match header_len {
+                    Some(header_len) => (header_len, len as usize),
+                    None if src.len() >= 10 => {
+                        return Err(NetworkError::InvalidFrame(
+                            "varint length header overflow".to_string(),
+                        ));
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        check_frame_size(len, max_frame_size)?;
+
+        if src.len() < header_len + len {
+            src.reserve(header_len + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(header_len);
+        Ok(Some(src.split_to(len).freeze()))
+    }
+}
+
+/// Frames end with `\n`, a trailing `\r` is stripped as well
+#[derive(Debug, Clone, Copy)]
+pub struct NewlineDelimited;
+
+impl Framer for NewlineDelimited {
+    fn encode(
+        &self,
+        payload: &[u8],
+        dst: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<(), NetworkError> {
+        check_frame_size(payload.len(), max_frame_size)?;
+        if payload.contains(&b'\n') {
+            return Err(NetworkError::InvalidFrame(
+                "payload contains a newline".to_string(),
+            ));
+        }
+        dst.extend_from_slice(payload);
+        dst.put_u8(b'\n');
+
+        Ok(())
+    }
+
+    fn decode(
+        &self,
+        src: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<Option<Bytes>, NetworkError> {
+        match src.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                check_frame_size(pos, max_frame_size)?;
+                let mut line = src.split_to(pos + 1);
+                line.truncate(pos);
+                if line.last() == Some(&b'\r') {
+                    line.truncate(pos - 1);
+                }
+                Ok(Some(line.freeze()))
+            }
+            None => {
+                check_frame_size(src.len(), max_frame_size)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Consistent Overhead Byte Stuffing, frames are terminated by a zero byte
+#[derive(Debug, Clone, Copy)]
+pub struct Cobs;
+
+impl Framer for Cobs {
+    fn encode(
+        &self,
+        payload: &[u8],
+        dst: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<(), NetworkError> {
+        check_frame_size(payload.len(), max_frame_size)?;
+        dst.reserve(payload.len() + payload.len() / 254 + 2);
+
+        let mut code_idx = dst.len();
+        dst.put_u8(0);
+        let mut code: u8 = 1;
+        for &byte in payload {
+            if byte == 0 {
+                dst[code_idx] = code;
+                code_idx = dst.len();
+                dst.put_u8(0);
+                code = 1;
+            } else {
+                dst.put_u8(byte);
+                code += 1;
+                if code == 0xff {
+                    dst[code_idx] = code;
+                    code_idx = dst.len();
+                    dst.put_u8(0);
+                    code = 1;
+                }
+            }
+        }
+        dst[code_idx] = code;
+        dst.put_u8(0);
+
+        Ok(())
+    }
+
+    fn decode(
+        &self,
+        src: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<Option<Bytes>, NetworkError> {
+        let Some(end) = src.iter().position(|b| *b == 0) else {
+            // encoded frames are at most one byte per 254 larger than the payload
+            check_frame_size(src.len() - src.len() / 255, max_frame_size)?;
+            return Ok(None);
+        };
+        let encoded = src.split_to(end + 1);
+        let encoded = &encoded[..end];
+
+        let mut frame = BytesMut::with_capacity(encoded.len());
+        let mut i = 0;
+        while i < encoded.len() {
+            let code = encoded[i] as usize;
+            if i + code > encoded.len() {
+                return Err(NetworkError::InvalidFrame(
+                    "truncated COBS block".to_string(),
+                ));
+            }
+            frame.extend_from_slice(&encoded[i + 1..i + code]);
+            i += code;
+            if code < 0xff && i < encoded.len() {
+                frame.put_u8(0);
+            }
+        }
+        check_frame_size(frame.len(), max_frame_size)?;
+
+        Ok(Some(frame.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(framer: impl Framer, payload: &[u8]) -> BytesMut {
+        let mut dst = BytesMut::new();
+        framer
+            .encode(payload, &mut dst, DEFAULT_MAX_FRAME_SIZE)
+            .unwrap();
+        dst
+    }
+
+    /// Encode `payloads` back to back and decode them again
+    fn round_trip(framer: impl Framer + Copy, payloads: &[&[u8]]) {
+        let mut src = BytesMut::new();
+        for payload in payloads {
+            src.extend_from_slice(&encode(framer, payload));
+        }
+
+        for payload in payloads {
+            let frame = framer.decode(&mut src, DEFAULT_MAX_FRAME_SIZE).unwrap();
+            assert_eq!(frame.as_deref(), Some(*payload));
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn length_prefixed_round_trip() {
+        let long = vec![7; 300];
+        for prefix in [LengthPrefix::U16, LengthPrefix::U32, LengthPrefix::Varint] {
+            round_trip(LengthPrefixed(prefix), &[b"hello", b"", &long]);
+        }
+    }
+
+    #[test]
+    fn length_prefixed_waits_for_whole_frame() {
+        let encoded = encode(LengthPrefixed(LengthPrefix::U32), b"hello");
+        let mut src = BytesMut::from(&encoded[..6]);
+        let framer = LengthPrefixed(LengthPrefix::U32);
+
+        assert_eq!(
+            framer.decode(&mut src, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+        src.extend_from_slice(&encoded[6..]);
+        assert_eq!(
+            framer.decode(&mut src, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+            Some(Bytes::from_static(b"hello"))
+        );
+    }
+
+    #[test]
+    fn varint_header() {
+        assert_eq!(
+            &encode(LengthPrefixed(LengthPrefix::Varint), &[0; 300])[..2],
+            &[0xac, 0x02]
+        );
+
+        let framer = LengthPrefixed(LengthPrefix::Varint);
+        let mut partial = BytesMut::from(&[0x80, 0x80][..]);
+        assert_eq!(framer.decode(&mut partial, usize::MAX).unwrap(), None);
+
+        let mut too_long = BytesMut::from(&[0x80; 10][..]);
+        assert!(matches!(
+            framer.decode(&mut too_long, usize::MAX),
+            Err(NetworkError::InvalidFrame(_))
+        ));
+
+        let mut overflow =
+            BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02][..]);
+        assert!(matches!(
+            framer.decode(&mut overflow, usize::MAX),
+            Err(NetworkError::InvalidFrame(_))
+        ));
+    }
+
+    #[test]
+    fn length_prefixed_frame_too_large() {
+        let framer = LengthPrefixed(LengthPrefix::U16);
+        let mut dst = BytesMut::new();
+        assert!(matches!(
+            framer.encode(&[0; 100], &mut dst, 10),
+            Err(NetworkError::FrameTooLarge { size: 100, max: 10 })
+        ));
+        assert!(matches!(
+            framer.encode(&[0; 70_000], &mut dst, usize::MAX),
+            Err(NetworkError::FrameTooLarge { .. })
+        ));
+
+        let mut src = encode(framer, &[0; 100]);
+        assert!(matches!(
+            framer.decode(&mut src, 10),
+            Err(NetworkError::FrameTooLarge { size: 100, max: 10 })
+        ));
+    }
+
+    #[test]
+    fn newline_delimited() {
+        round_trip(NewlineDelimited, &[b"hello", b"", b"world"]);
+
+        let mut src = BytesMut::from(&b"crlf\r\npartial"[..]);
+        assert_eq!(
+            NewlineDelimited
+                .decode(&mut src, DEFAULT_MAX_FRAME_SIZE)
+                .unwrap(),
+            Some(Bytes::from_static(b"crlf"))
+        );
+        assert_eq!(
+            NewlineDelimited
+                .decode(&mut src, DEFAULT_MAX_FRAME_SIZE)
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            NewlineDelimited.decode(&mut src, 3),
+            Err(NetworkError::FrameTooLarge { .. })
+        ));
+
+        let mut dst = BytesMut::new();
+        assert!(matches!(
+            NewlineDelimited.encode(b"two\nlines", &mut dst, DEFAULT_MAX_FRAME_SIZE),
+            Err(NetworkError::InvalidFrame(_))
+        ));
+    }
+
+    #[test]
+    fn cobs() {
+        assert_eq!(
+            &encode(Cobs, &[0x11, 0x00, 0x22])[..],
+            &[0x02, 0x11, 0x02, 0x22, 0x00]
+        );
+        assert_eq!(&encode(Cobs, &[])[..], &[0x01, 0x00]);
+
+        let long: Vec<u8> = (1..=255).collect();
+        round_trip(Cobs, &[b"\0\0", &[1, 2, 0, 3], &long, &[]]);
+    }
+
+    #[test]
+    fn cobs_invalid_frames() {
+        let mut truncated = BytesMut::from(&[0x05, 0x11, 0x00][..]);
+        assert!(matches!(
+            Cobs.decode(&mut truncated, DEFAULT_MAX_FRAME_SIZE),
+            Err(NetworkError::InvalidFrame(_))
+        ));
+
+        let mut src = encode(Cobs, &[1; 20]);
+        assert!(matches!(
+            Cobs.decode(&mut src, 10),
+            Err(NetworkError::FrameTooLarge { .. })
+        ));
+        let mut unterminated = BytesMut::from(&[0x01; 20][..]);
+        assert!(matches!(
+            Cobs.decode(&mut unterminated, 10),
+            Err(NetworkError::FrameTooLarge { .. })
+        ));
+    }
+}
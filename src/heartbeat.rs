@@ -0,0 +1,374 @@
+use std::{
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use bevy::prelude::*;
+use bytes::{BufMut, BytesMut};
+use futures::{future, pin_mut};
+use kanal::{AsyncReceiver, AsyncSender};
+
+use crate::network_node::{AsyncChannel, DisconnectReason, NetworkEvent, NetworkRawPacket};
+
+const DATA: u8 = 0x00;
+const PING: u8 = 0x01;
+const PONG: u8 = 0x02;
+
+/// Send pings every `interval` and disconnect after `timeout` without
+/// anything received from the remote.
+///
+/// Both sides need the component, every message then carries a one byte
+/// control header. TCP nodes also need a
+/// [`FrameCodec`](crate::framing::FrameCodec), without one the heartbeat is
+/// disabled with an error. UDP servers need a
+/// [`UdpSession`](crate::transports::udp::session::UdpSession) to watch their
+/// peers and WebSocket nodes use native ping frames. A silent remote ends with
+/// [`DisconnectReason::Timeout`], there is no separate error.
+#[derive(Component, Debug, Clone, Reflect)]
+#[require(NetworkRtt)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self { interval, timeout }
+    }
+
+    /// Call `ping` every interval until the remote was silent for `timeout`.
+    ///
+    /// Reports the timeout on `event_tx`, returns early once `ping` fails.
+    pub async fn watch<F, Fut>(
+        &self,
+        last_seen: &LastSeen,
+        mut ping: F,
+        event_tx: &AsyncSender<NetworkEvent>,
+    ) where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        loop {
+            task::sleep(self.interval).await;
+            let silence = last_seen.silence();
+            if silence >= self.timeout {
+                let reason = DisconnectReason::Timeout(silence);
+                let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                return;
+            }
+            if !ping().await {
+                return;
+            }
+        }
+    }
+}
+
+/// When anything was last received from the remote
+#[derive(Debug)]
+pub struct LastSeen(AtomicU64);
+
+impl Default for LastSeen {
+    fn default() -> Self {
+        Self(AtomicU64::new(clock().as_micros() as u64))
+    }
+}
+
+impl LastSeen {
+    pub fn touch(&self) {
+        self.0.store(clock().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn silence(&self) -> Duration {
+        clock().saturating_sub(Duration::from_micros(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/// Smoothed round trip time and jitter measured by [`Heartbeat`] pings
+#[derive(Component, Debug, Clone, Default)]
+pub struct NetworkRtt(Arc<RttState>);
+
+#[derive(Debug, Default)]
+struct RttState {
+    rtt_micros: AtomicU64,
+    jitter_micros: AtomicU64,
+}
+
+impl NetworkRtt {
+    pub fn rtt(&self) -> Duration {
+        Duration::from_micros(self.0.rtt_micros.load(Ordering::Relaxed))
+    }
+
+    /// Mean deviation of the round trip time
+    pub fn jitter(&self) -> Duration {
+        Duration::from_micros(self.0.jitter_micros.load(Ordering::Relaxed))
+    }
+
+    /// Add a round trip sample
+    pub fn record(&self, sample: Duration) {
+        let sample = sample.as_micros() as u64;
+        let rtt = self.0.rtt_micros.load(Ordering::Relaxed);
+        if rtt == 0 {
+            self.0.rtt_micros.store(sample, Ordering::Relaxed);
+            self.0.jitter_micros.store(sample / 2, Ordering::Relaxed);
+            return;
+        }
+        let jitter = self.0.jitter_micros.load(Ordering::Relaxed);
+        self.0
+            .jitter_micros
+            .store((jitter * 3 + rtt.abs_diff(sample)) / 4, Ordering::Relaxed);
+        self.0
+            .rtt_micros
+            .store((rtt * 7 + sample) / 8, Ordering::Relaxed);
+    }
+}
+
+fn clock() -> Duration {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed()
+}
+
+/// Payload of a ping, the pong echoes it back
+pub fn ping_payload() -> [u8; 8] {
+    (clock().as_micros() as u64).to_be_bytes()
+}
+
+/// Round trip time of the ping answered by a pong with `payload`
+pub fn pong_rtt(payload: &[u8]) -> Option<Duration> {
+    let sent = u64::from_be_bytes(payload.try_into().ok()?);
+    clock().checked_sub(Duration::from_micros(sent))
+}
+
+fn control(kind: u8, addr: Option<std::net::SocketAddr>, payload: &[u8]) -> NetworkRawPacket {
+    let mut bytes = BytesMut::with_capacity(1 + payload.len());
+    bytes.put_u8(kind);
+    bytes.extend_from_slice(payload);
+    NetworkRawPacket {
+        addr,
+        bytes: bytes.freeze(),
+        text: None,
+    }
+}
+
+/// Put a heartbeat between a node and its transport.
+///
+/// Returns the channels the transport uses instead of the node ones, the
+/// layer stops when the transport drops them or the remote timed out.
+pub(crate) fn spawn_layer(
+    heartbeat: Heartbeat,
+    rtt: NetworkRtt,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    message_rx: AsyncReceiver<NetworkRawPacket>,
+    event_tx: AsyncSender<NetworkEvent>,
+) -> (
+    AsyncSender<NetworkRawPacket>,
+    AsyncReceiver<NetworkRawPacket>,
+) {
+    let inbound: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+    let outbound: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+    let inbound_rx = inbound.receiver.clone_async();
+    let outbound_tx = outbound.sender.clone_async();
+
+    task::spawn(async move {
+        let last_seen = LastSeen::default();
+
+        let receive = async {
+            while let Ok(packet) = inbound_rx.recv().await {
+                last_seen.touch();
+                let Some((&kind, payload)) = packet.bytes.split_first() else {
+                    continue;
+                };
+                match kind {
+                    DATA => {
+                        let bytes = packet.bytes.slice(1..);
+                        let _ = recv_tx.send(NetworkRawPacket { bytes, ..packet }).await;
+                    }
+                    PING => {
+                        let _ = outbound_tx.send(control(PONG, packet.addr, payload)).await;
+                    }
+                    PONG => {
+                        if let Some(sample) = pong_rtt(payload) {
+                            rtt.record(sample);
+                        }
+                    }
+                    _ => trace!("unknown heartbeat control {:#04x}", kind),
+                }
+            }
+        };
+
+        let send = async {
+            while let Ok(packet) = message_rx.recv().await {
+                let mut bytes = BytesMut::with_capacity(1 + packet.bytes.len());
+                bytes.put_u8(DATA);
+                bytes.extend_from_slice(&packet.bytes);
+                let packet = NetworkRawPacket {
+                    bytes: bytes.freeze(),
+                    ..packet
+                };
+                if outbound_tx.send(packet).await.is_err() {
+                    break;
+                }
+            }
+        };
+
+        let ping = || async {
+            outbound_tx
+                .send(control(PING, None, &ping_payload()))
+                .await
+                .is_ok()
+        };
+        let watch = heartbeat.watch(&last_seen, ping, &event_tx);
+
+        pin_mut!(receive, send, watch);
+        future::select(future::select(receive, send), watch).await;
+    });
+
+    (
+        inbound.sender.clone_async(),
+        outbound.receiver.clone_async(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::{future::timeout, task::block_on};
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(1);
+
+    struct Layer {
+        /// Packets arriving from the transport
+        inbound: AsyncSender<NetworkRawPacket>,
+        /// Packets handed to the transport
+        outbound: AsyncReceiver<NetworkRawPacket>,
+        /// Messages of the node
+        messages: AsyncChannel<NetworkRawPacket>,
+        received: AsyncChannel<NetworkRawPacket>,
+        events: AsyncChannel<NetworkEvent>,
+    }
+
+    fn layer(heartbeat: Heartbeat, rtt: NetworkRtt) -> Layer {
+        let (messages, received, events) = (
+            AsyncChannel::new(),
+            AsyncChannel::new(),
+            AsyncChannel::new(),
+        );
+        let (inbound, outbound) = spawn_layer(
+            heartbeat,
+            rtt,
+            received.sender.clone_async(),
+            messages.receiver.clone_async(),
+            events.sender.clone_async(),
+        );
+
+        Layer {
+            inbound,
+            outbound,
+            messages,
+            received,
+            events,
+        }
+    }
+
+    fn packet(bytes: &[u8]) -> NetworkRawPacket {
+        NetworkRawPacket {
+            addr: None,
+            bytes: bytes.to_vec().into(),
+            text: None,
+        }
+    }
+
+    async fn recv<T>(rx: &AsyncReceiver<T>) -> T {
+        timeout(WAIT, rx.recv()).await.unwrap().unwrap()
+    }
+
+    /// Never pings during the test
+    fn quiet() -> Heartbeat {
+        Heartbeat::new(Duration::from_secs(60), Duration::from_secs(60))
+    }
+
+    #[test]
+    fn data_carries_a_control_header() {
+        let layer = layer(quiet(), default());
+
+        block_on(async {
+            layer.messages.sender.send(packet(b"up")).unwrap();
+            assert_eq!(&recv(&layer.outbound).await.bytes[..], b"\x00up");
+
+            layer.inbound.send(packet(b"\x00down")).await.unwrap();
+            let received = recv(layer.received.receiver.as_async()).await;
+            assert_eq!(&received.bytes[..], b"down");
+        });
+    }
+
+    #[test]
+    fn pings_are_answered_and_pongs_measure_rtt() {
+        let rtt = NetworkRtt::default();
+        let layer = layer(quiet(), rtt.clone());
+
+        block_on(async {
+            layer.inbound.send(packet(b"\x01payload")).await.unwrap();
+            assert_eq!(&recv(&layer.outbound).await.bytes[..], b"\x02payload");
+
+            let sent = ping_payload();
+            task::sleep(Duration::from_millis(5)).await;
+            let mut pong = vec![PONG];
+            pong.extend_from_slice(&sent);
+            layer.inbound.send(packet(&pong)).await.unwrap();
+            task::sleep(Duration::from_millis(20)).await;
+
+            assert!(rtt.rtt() >= Duration::from_millis(5), "{:?}", rtt.rtt());
+            assert!(layer.received.receiver.is_empty());
+        });
+    }
+
+    #[test]
+    fn silent_remotes_time_out() {
+        let heartbeat = Heartbeat::new(Duration::from_millis(10), Duration::from_millis(30));
+        let layer = layer(heartbeat, default());
+
+        block_on(async {
+            assert_eq!(recv(&layer.outbound).await.bytes[0], PING);
+            let event = recv(layer.events.receiver.as_async()).await;
+            assert!(matches!(
+                event,
+                NetworkEvent::Disconnected(DisconnectReason::Timeout(silence))
+                    if silence >= Duration::from_millis(30)
+            ));
+        });
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let rtt = NetworkRtt::default();
+
+        rtt.record(Duration::from_millis(80));
+        assert_eq!(rtt.rtt(), Duration::from_millis(80));
+        assert_eq!(rtt.jitter(), Duration::from_millis(40));
+
+        rtt.record(Duration::from_millis(160));
+        assert_eq!(rtt.rtt(), Duration::from_millis(90));
+        assert_eq!(rtt.jitter(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn pongs_of_the_wrong_size_are_ignored() {
+        assert!(pong_rtt(&ping_payload()).is_some());
+        assert!(pong_rtt(&[1, 2, 3]).is_none());
+        // sent in the future
+        assert!(pong_rtt(&u64::MAX.to_be_bytes()).is_none());
+    }
+}
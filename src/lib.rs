@@ -2,10 +2,19 @@
 
 pub mod channels;
 pub mod client;
+pub mod conditioner;
+pub mod connection_state;
 pub mod error;
+pub mod framing;
+pub mod heartbeat;
 pub mod network_node;
 pub mod plugin;
 pub mod prelude;
+pub mod queue;
 pub mod server;
+pub mod shutdown;
+pub mod stats;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod transformer;
 pub mod transports;
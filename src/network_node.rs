@@ -1,13 +1,29 @@
-use crate::{client::ReconnectSetting, error::NetworkError, prelude::ChannelId};
+use crate::{
+    client::{ReconnectSetting, ReconnectTimer},
+    conditioner::NetworkConditioner,
+    connection_state::ConnectionState,
+    error::NetworkError,
+    heartbeat::{self, Heartbeat, NetworkRtt},
+    prelude::ChannelId,
+    queue::{OverflowPolicy, QueueSettings, bound_node},
+    shutdown::ShutdownSettings,
+    stats::{NetworkStats, TrafficCounters},
+};
 use bevy::{
-    ecs::component::{Mutable, StorageType},
+    ecs::component::{ComponentId, Mutable, RequiredComponentsRegistrator, StorageType},
     prelude::*,
 };
 use bytes::Bytes;
-use kanal::{Receiver, Sender, unbounded};
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use kanal::{AsyncReceiver, AsyncSender, Receiver, Sender, bounded, unbounded};
 use std::{
     fmt::Debug,
     net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 pub trait NetworkAddress: Debug + Clone + Send + Sync {
@@ -17,6 +33,64 @@ pub trait NetworkAddress: Debug + Clone + Send + Sync {
         Self: Sized;
 }
 
+/// Layers a transport puts between the channels of a node and the wire,
+/// taken from the components of the node
+#[derive(Debug, Clone, Default)]
+pub struct TransportLayers {
+    pub queue: Option<QueueSettings>,
+    pub heartbeat: Option<(Heartbeat, NetworkRtt)>,
+    pub conditioner: Option<NetworkConditioner>,
+}
+
+impl TransportLayers {
+    pub fn new(
+        queue: Option<&QueueSettings>,
+        heartbeat: Option<(&Heartbeat, &NetworkRtt)>,
+        conditioner: Option<&NetworkConditioner>,
+    ) -> Self {
+        Self {
+            queue: queue.cloned(),
+            heartbeat: heartbeat.map(|(heartbeat, rtt)| (heartbeat.clone(), rtt.clone())),
+            conditioner: conditioner.cloned(),
+        }
+    }
+
+    /// Layers of the next peer of a server with these layers, the peer gets
+    /// its own RTT and conditioner seed
+    pub fn for_peer(&self) -> Self {
+        Self {
+            queue: self.queue.clone(),
+            heartbeat: self
+                .heartbeat
+                .as_ref()
+                .map(|(heartbeat, _)| (heartbeat.clone(), NetworkRtt::default())),
+            conditioner: self.conditioner.as_ref().map(NetworkConditioner::for_peer),
+        }
+    }
+
+    /// Node of a peer with these layers, its channels bounded by the queue
+    pub fn node(&self) -> NetworkNode {
+        let mut net_node = NetworkNode::default();
+        if let Some(queue) = &self.queue {
+            queue.bound(&mut net_node);
+        }
+        net_node
+    }
+
+    /// Insert the components of the layers on the entity of a peer
+    pub fn insert_on(&self, entity: &mut EntityCommands) {
+        if let Some(queue) = &self.queue {
+            entity.insert(queue.clone());
+        }
+        if let Some((heartbeat, rtt)) = &self.heartbeat {
+            entity.insert((heartbeat.clone(), rtt.clone()));
+        }
+        if let Some(conditioner) = &self.conditioner {
+            entity.insert(conditioner.clone());
+        }
+    }
+}
+
 /// [`NetworkRawPacket`]s are raw packets that are sent over the network.
 #[derive(Clone)]
 pub struct NetworkRawPacket {
@@ -49,6 +123,11 @@ impl NetworkBundle {
             reconnect: ReconnectSetting::default(),
         }
     }
+
+    pub fn with_reconnect(mut self, reconnect: ReconnectSetting) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
 }
 
 #[derive(Default, Reflect)]
@@ -62,11 +141,14 @@ pub struct NetworkNode {
     /// Channel for events
     #[reflect(ignore)]
     pub event_channel: AsyncChannel<NetworkEvent>,
-    /// Channel for shutdown
+    /// Channel for shutdown, carrying why the node is closed
+    #[reflect(ignore)]
+    pub shutdown_channel: AsyncChannel<DisconnectReason>,
     #[reflect(ignore)]
-    pub shutdown_channel: AsyncChannel<()>,
-    /// Whether the node is running or not
-    pub running: bool,
+    pub(crate) traffic: Arc<TrafficCounters>,
+    /// Policy of the bounded channels, from [`QueueSettings`]
+    #[reflect(ignore)]
+    pub(crate) overflow: Option<OverflowPolicy>,
 }
 
 impl Component for NetworkNode {
@@ -74,28 +156,121 @@ impl Component for NetworkNode {
 
     type Mutability = Mutable;
 
+    fn register_required_components(
+        _component_id: ComponentId,
+        required_components: &mut RequiredComponentsRegistrator,
+    ) {
+        required_components.register_required(ConnectionState::default);
+        required_components.register_required(NetworkStats::default);
+    }
+
+    fn on_add() -> Option<bevy::ecs::lifecycle::ComponentHook> {
+        Some(bound_node)
+    }
+
     fn on_remove() -> Option<bevy::ecs::lifecycle::ComponentHook> {
         Some(|world, ctx| {
             if let Some(node) = world.get::<NetworkNode>(ctx.entity) {
-                node.shutdown_channel.sender.try_send(()).unwrap();
+                node.shutdown_channel
+                    .sender
+                    .try_send(DisconnectReason::Closed)
+                    .unwrap();
             }
         })
     }
 }
 
+/// Clones share the channels of the node
+impl Clone for NetworkNode {
+    fn clone(&self) -> Self {
+        Self {
+            recv_message_channel: self.recv_message_channel.clone(),
+            send_message_channel: self.send_message_channel.clone(),
+            event_channel: AsyncChannel {
+                sender: self.event_channel.sender.clone(),
+                receiver: self.event_channel.receiver.clone(),
+            },
+            shutdown_channel: self.shutdown_channel.clone(),
+            traffic: self.traffic.clone(),
+            overflow: self.overflow,
+        }
+    }
+}
+
 impl NetworkNode {
-    pub fn start(&mut self) {
-        self.running = true;
+    /// Whether a transport took the channels of the node
+    pub(crate) fn is_started(&self) -> bool {
+        self.recv_message_channel.sender.sender_count() > 1
+            || self.send_message_channel.receiver.receiver_count() > 1
+    }
+
+    /// Flush queued sends and close the connection, the transport reports a
+    /// [`NetworkEvent::Disconnected`] with `reason` once done
+    pub fn close(&self, reason: DisconnectReason) {
+        let _ = self.shutdown_channel.sender.try_send(reason);
+    }
+
+    /// Ends of the node channels a transport writes received packets to and
+    /// reads packets to send from, through the `layers` of the node
+    pub fn transport_channels(
+        &self,
+        layers: &TransportLayers,
+    ) -> (
+        AsyncSender<NetworkRawPacket>,
+        AsyncReceiver<NetworkRawPacket>,
+    ) {
+        let (mut recv_tx, mut message_rx) = match &layers.queue {
+            Some(queue) => queue.spawn_layer(self),
+            None => (
+                self.recv_message_channel.sender.clone_async(),
+                self.send_message_channel.receiver.clone_async(),
+            ),
+        };
+        if let Some((heartbeat, rtt)) = &layers.heartbeat {
+            (recv_tx, message_rx) = heartbeat::spawn_layer(
+                heartbeat.clone(),
+                rtt.clone(),
+                recv_tx,
+                message_rx,
+                self.event_channel.sender.clone_async(),
+            );
+        }
+        // closest to the wire, pings see the conditions too
+        if let Some(conditioner) = &layers.conditioner {
+            (recv_tx, message_rx) = conditioner.spawn_layer(recv_tx, message_rx);
+        }
+
+        (recv_tx, message_rx)
+    }
+
+    /// Queue a packet for the transport, counted in [`NetworkStats`]. A full
+    /// queue applies the [`OverflowPolicy`] of the node
+    pub fn send(&self, packet: NetworkRawPacket) {
+        self.traffic.sent(&packet);
+        match self.overflow {
+            Some(policy) => policy.send(self, packet),
+            None => {
+                let _ = self.send_message_channel.sender.try_send(packet);
+            }
+        }
     }
 
-    pub fn stop(&mut self) {
-        self.running = false;
+    /// Next received packet, counted in [`NetworkStats`]
+    pub fn try_recv(&self) -> Option<NetworkRawPacket> {
+        let packet = self
+            .recv_message_channel
+            .receiver
+            .try_recv()
+            .ok()
+            .flatten()?;
+        self.traffic.received(&packet);
+        Some(packet)
     }
 
     /// Send text message
     pub fn send_text_to(&self, text: String, remote_addr: impl ToSocketAddrs) {
         let addr = remote_addr.to_socket_addrs().unwrap().next().unwrap();
-        let _ = self.send_message_channel.sender.try_send(NetworkRawPacket {
+        self.send(NetworkRawPacket {
             addr: Some(addr),
             bytes: Bytes::new(),
             text: Some(text),
@@ -103,7 +278,7 @@ impl NetworkNode {
     }
 
     pub fn send_bytes_to(&self, bytes: &[u8], addr: impl ToSocketAddrs) {
-        let _ = self.send_message_channel.sender.try_send(NetworkRawPacket {
+        self.send(NetworkRawPacket {
             addr: Some(addr.to_socket_addrs().unwrap().next().unwrap()),
             bytes: Bytes::copy_from_slice(bytes),
             text: None,
@@ -111,7 +286,7 @@ impl NetworkNode {
     }
 
     pub fn send_bytes(&self, bytes: &[u8]) {
-        let _ = self.send_message_channel.sender.try_send(NetworkRawPacket {
+        self.send(NetworkRawPacket {
             addr: None,
             bytes: Bytes::copy_from_slice(bytes),
             text: None,
@@ -123,6 +298,14 @@ impl NetworkNode {
 #[derive(Component)]
 pub struct NetworkPeer;
 
+/// Remote socket address of a connected peer
+#[derive(Component, Deref, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Address a server node is bound to, with the real port when binding port 0
+#[derive(Component, Deref, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalAddr(pub SocketAddr);
+
 #[derive(Reflect, Debug, Clone)]
 pub struct AsyncChannel<T> {
     pub sender: Sender<T>,
@@ -141,15 +324,57 @@ impl<T> AsyncChannel<T> {
 
         Self { sender, receiver }
     }
+
+    pub fn bounded(capacity: usize) -> Self {
+        let (sender, receiver) = bounded(capacity);
+
+        Self { sender, receiver }
+    }
+}
+
+/// Why a connection ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The remote closed the connection
+    RemoteClosed,
+    /// The node was closed or despawned locally
+    Closed,
+    /// Nothing was received from the remote for the heartbeat timeout
+    Timeout(Duration),
+    /// Kicked with a message, only WebSocket close frames and the memory
+    /// transport carry it to the remote, others just close the connection
+    Kicked(String),
+    /// The remote sent data violating the protocol
+    ProtocolError(String),
+    /// The server shut down
+    ServerShutdown,
+    /// Reading or writing failed, the preceding [`NetworkEvent::Error`] has details
+    ConnectionLost,
+    /// A queue of the node was full with
+    /// [`OverflowPolicy::Disconnect`](crate::queue::OverflowPolicy::Disconnect)
+    QueueOverflow,
 }
 
 #[derive(Debug)]
 /// 来自网络节点后台的原始事件（线程通道）
 pub enum NetworkEvent {
-    Listen,
+    /// The node is bound to the address
+    Listen(SocketAddr),
     Connected,
-    Disconnected,
+    Disconnected(DisconnectReason),
     Error(NetworkError),
+    /// The client retries attempt `attempt` after `next_delay`
+    Reconnecting {
+        attempt: usize,
+        next_delay: Duration,
+    },
+    /// The client gave up after `ReconnectSetting::max_retries` attempts
+    ReconnectExhausted,
+    /// Packets dropped by full queues since the last report, see [`QueueSettings`]
+    PacketsDropped {
+        sent: u64,
+        received: u64,
+    },
 }
 
 #[derive(EntityEvent, Debug)]
@@ -160,22 +385,337 @@ pub struct NodeEvent {
 }
 
 /// send network node error channel to events
-#[allow(clippy::type_complexity)]
-pub(crate) fn network_node_event(
+pub(crate) fn network_node_event(mut commands: Commands, q_net: Query<(Entity, &NetworkNode)>) {
+    for (entity, net_node) in q_net.iter() {
+        while let Ok(Some(event)) = net_node.event_channel.receiver.try_recv() {
+            if let NetworkEvent::Listen(local_addr) = event {
+                commands.entity(entity).insert(LocalAddr(local_addr));
+            }
+            commands.trigger(NodeEvent { entity, event });
+        }
+    }
+}
+
+/// Close a node gracefully.
+///
+/// The node and the peers of a closed server enter
+/// [`ConnectionState::Disconnecting`] while their queued sends are flushed and
+/// the remotes get a close notice where the transport has one. Each reports a
+/// [`NetworkEvent::Disconnected`] once its transport is done, then the entity
+/// is despawned. Nodes still flushing after [`ShutdownSettings::deadline`] get
+/// the event with `reason` and are despawned anyway.
+#[derive(EntityEvent, Debug, Clone)]
+pub struct CloseNode {
+    pub entity: Entity,
+    pub reason: DisconnectReason,
+}
+
+/// A node closed with [`CloseNode`], waiting for it and its peers to disconnect
+#[derive(Component, Debug)]
+pub(crate) struct Closing {
+    reason: DisconnectReason,
+    deadline: Instant,
+}
+
+pub(crate) fn on_close_node(
+    on: On<CloseNode>,
     mut commands: Commands,
-    mut q_net: Query<(Entity, &mut NetworkNode)>,
+    settings: Res<ShutdownSettings>,
+    mut q_net: Query<(&NetworkNode, &mut ConnectionState)>,
+    q_children: Query<&Children>,
 ) {
-    for (entity, mut net_node) in q_net.iter_mut() {
-        while let Ok(Some(event)) = net_node.event_channel.receiver.try_recv() {
-            match event {
-                NetworkEvent::Listen | NetworkEvent::Connected => {
-                    net_node.start();
-                }
-                NetworkEvent::Disconnected | NetworkEvent::Error(_) => {
-                    net_node.stop();
+    let ev = on.event();
+    if !q_net.contains(ev.entity) {
+        return;
+    }
+    let peers = q_children.get(ev.entity).into_iter().flatten().copied();
+    for entity in peers.chain([ev.entity]) {
+        let Ok((node, mut state)) = q_net.get_mut(entity) else {
+            continue;
+        };
+        // nodes without a running transport have nothing to flush
+        if matches!(
+            *state,
+            ConnectionState::Idle | ConnectionState::Disconnected | ConnectionState::Failed
+        ) {
+            continue;
+        }
+        node.close(ev.reason.clone());
+        state.set_if_neq(ConnectionState::Disconnecting);
+    }
+    commands
+        .entity(ev.entity)
+        .remove::<ReconnectTimer>()
+        .insert(Closing {
+            reason: ev.reason.clone(),
+            deadline: Instant::now() + settings.deadline,
+        });
+}
+
+/// Despawn closed nodes once they and their peers are disconnected
+pub(crate) fn despawn_closed_nodes(
+    mut commands: Commands,
+    q_closing: Query<(Entity, &Closing, Option<&Children>)>,
+    q_state: Query<&ConnectionState>,
+) {
+    let now = Instant::now();
+    for (entity, closing, opt_children) in q_closing.iter() {
+        let flushing: Vec<Entity> = opt_children
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain([entity])
+            .filter(|&node| q_state.get(node) == Ok(&ConnectionState::Disconnecting))
+            .collect();
+        if !flushing.is_empty() && now < closing.deadline {
+            continue;
+        }
+        for node in flushing {
+            warn!("network node {:?} did not close in time", node);
+            commands.trigger(NodeEvent {
+                entity: node,
+                event: NetworkEvent::Disconnected(closing.reason.clone()),
+            });
+        }
+        commands.entity(entity).try_despawn();
+    }
+}
+
+/// Outgoing side of a transport
+pub enum Outgoing {
+    Packet(NetworkRawPacket),
+    /// The node was closed and every queued packet handed out
+    Close(DisconnectReason),
+}
+
+/// Packets a transport has to send, still handing out the queued ones after
+/// the node was closed
+pub struct SendQueue {
+    message_rx: AsyncReceiver<NetworkRawPacket>,
+    shutdown_rx: AsyncReceiver<DisconnectReason>,
+    closing: Option<DisconnectReason>,
+}
+
+impl SendQueue {
+    pub fn new(
+        message_rx: AsyncReceiver<NetworkRawPacket>,
+        shutdown_rx: AsyncReceiver<DisconnectReason>,
+    ) -> Self {
+        Self {
+            message_rx,
+            shutdown_rx,
+            closing: None,
+        }
+    }
+
+    /// Next packet to send, `None` once the node is gone without a close
+    pub async fn next(&mut self) -> Option<Outgoing> {
+        if self.closing.is_none() {
+            self.closing = self.shutdown_rx.try_recv().ok().flatten();
+        }
+        if self.closing.is_none() {
+            let packet = self.message_rx.recv();
+            let shutdown = self.shutdown_rx.recv();
+            pin_mut!(packet, shutdown);
+            match future::select(packet, shutdown).await {
+                Either::Left((Ok(packet), _)) => return Some(Outgoing::Packet(packet)),
+                Either::Left((Err(_), _)) => {
+                    self.closing = self.shutdown_rx.try_recv().ok().flatten();
                 }
+                Either::Right((Ok(reason), _)) => self.closing = Some(reason),
+                Either::Right((Err(_), _)) => return None,
             }
-            commands.trigger(NodeEvent { entity, event });
         }
+
+        let reason = self.closing.take()?;
+        match self.message_rx.try_recv() {
+            Ok(Some(packet)) => {
+                self.closing = Some(reason);
+                Some(Outgoing::Packet(packet))
+            }
+            _ => Some(Outgoing::Close(reason)),
+        }
+    }
+}
+
+/// Run `task` until it ends or the node shuts down, reporting either on `event_tx`
+pub async fn until_shutdown<F>(
+    task: F,
+    shutdown_rx: AsyncReceiver<DisconnectReason>,
+    event_tx: AsyncSender<NetworkEvent>,
+) where
+    F: Future<Output = Result<(), NetworkError>>,
+{
+    let shutdown = shutdown_rx.recv();
+    pin_mut!(task, shutdown);
+    match future::select(task, shutdown).await {
+        Either::Left((Err(e), _)) => {
+            let _ = event_tx.send(NetworkEvent::Error(e)).await;
+        }
+        Either::Right((Ok(reason), _)) => {
+            let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+        }
+        Either::Left((Ok(()), _)) | Either::Right((Err(_), _)) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::client_reconnect, connection_state};
+
+    #[derive(Resource, Default)]
+    struct Disconnects(Vec<(Entity, DisconnectReason)>);
+
+    #[derive(Resource, Default)]
+    struct Reconnects(Vec<Entity>);
+
+    fn app(deadline: Duration) -> App {
+        let mut app = App::new();
+        app.insert_resource(ShutdownSettings { deadline })
+            .init_resource::<Disconnects>()
+            .init_resource::<Reconnects>()
+            .add_plugins(connection_state::plugin)
+            .add_observer(on_close_node)
+            .add_observer(client_reconnect)
+            .add_observer(
+                |on: On<NodeEvent>,
+                 mut disconnects: ResMut<Disconnects>,
+                 mut reconnects: ResMut<Reconnects>| match &on.event().event {
+                    NetworkEvent::Disconnected(reason) => {
+                        disconnects.0.push((on.event().entity, reason.clone()));
+                    }
+                    NetworkEvent::Reconnecting { .. } => reconnects.0.push(on.event().entity),
+                    _ => {}
+                },
+            )
+            .add_systems(Update, (network_node_event, despawn_closed_nodes).chain());
+        app
+    }
+
+    /// A connected server with one connected peer
+    fn spawn_server(app: &mut App) -> (Entity, Entity) {
+        let world = app.world_mut();
+        let server = world
+            .spawn((NetworkNode::default(), ConnectionState::Listening))
+            .id();
+        let peer = world
+            .spawn((
+                NetworkNode::default(),
+                ConnectionState::Connected,
+                NetworkPeer,
+                ChildOf(server),
+            ))
+            .id();
+        (server, peer)
+    }
+
+    fn kick(app: &mut App, entity: Entity) {
+        let reason = DisconnectReason::Kicked("bye".into());
+        app.world_mut().trigger(CloseNode { entity, reason });
+    }
+
+    #[test]
+    fn closed_nodes_wait_for_their_transport() {
+        let mut app = app(Duration::from_secs(60));
+        let (server, peer) = spawn_server(&mut app);
+
+        kick(&mut app, server);
+        app.update();
+        for entity in [server, peer] {
+            assert_eq!(
+                app.world().get::<ConnectionState>(entity),
+                Some(&ConnectionState::Disconnecting)
+            );
+            let node = app.world().get::<NetworkNode>(entity).unwrap();
+            assert_eq!(
+                node.shutdown_channel.receiver.try_recv().unwrap(),
+                Some(DisconnectReason::Kicked("bye".into()))
+            );
+        }
+        assert!(app.world().resource::<Disconnects>().0.is_empty());
+
+        // the transport of the peer is done
+        let reason = DisconnectReason::Kicked("bye".into());
+        let peer_node = app.world().get::<NetworkNode>(peer).unwrap();
+        peer_node
+            .event_channel
+            .sender
+            .send(NetworkEvent::Disconnected(reason.clone()))
+            .unwrap();
+        app.update();
+        assert!(app.world().get_entity(server).is_ok());
+
+        let server_node = app.world().get::<NetworkNode>(server).unwrap();
+        server_node
+            .event_channel
+            .sender
+            .send(NetworkEvent::Disconnected(reason.clone()))
+            .unwrap();
+        app.update();
+        assert!(app.world().get_entity(server).is_err());
+        assert!(app.world().get_entity(peer).is_err());
+        assert_eq!(
+            app.world().resource::<Disconnects>().0,
+            [(peer, reason.clone()), (server, reason)]
+        );
+    }
+
+    #[test]
+    fn stuck_nodes_are_despawned_after_the_deadline() {
+        let mut app = app(Duration::ZERO);
+        let (server, peer) = spawn_server(&mut app);
+        let idle = app.world_mut().spawn(NetworkNode::default()).id();
+
+        kick(&mut app, server);
+        kick(&mut app, idle);
+        app.update();
+
+        for entity in [server, peer, idle] {
+            assert!(app.world().get_entity(entity).is_err());
+        }
+        let mut disconnected: Vec<Entity> = app
+            .world()
+            .resource::<Disconnects>()
+            .0
+            .iter()
+            .map(|(entity, _)| *entity)
+            .collect();
+        disconnected.sort();
+        let mut expected = vec![server, peer];
+        expected.sort();
+        // never started nodes have nothing to report
+        assert_eq!(disconnected, expected);
+    }
+
+    #[test]
+    fn closed_clients_do_not_reconnect() {
+        let mut app = app(Duration::from_secs(60));
+        let client = app
+            .world_mut()
+            .spawn((
+                NetworkNode::default(),
+                ConnectionState::Connected,
+                ReconnectSetting::default(),
+            ))
+            .id();
+
+        kick(&mut app, client);
+        app.update();
+        // the connection breaks while the queue is flushed
+        let node = app.world().get::<NetworkNode>(client).unwrap();
+        let reset = NetworkError::Connection("reset".into());
+        node.event_channel
+            .sender
+            .send(NetworkEvent::Error(reset))
+            .unwrap();
+        node.event_channel
+            .sender
+            .send(NetworkEvent::Disconnected(DisconnectReason::RemoteClosed))
+            .unwrap();
+        app.update();
+
+        assert!(app.world().get_entity(client).is_err());
+        assert!(app.world().resource::<Reconnects>().0.is_empty());
     }
 }
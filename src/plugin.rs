@@ -1,12 +1,21 @@
 use crate::{
     channels::{ChannelId, ChannelPacket, send_channel_message_system},
     client,
-    network_node::{NetworkNode, network_node_event},
-    transformer::{DecoderChannels, EncoderChannels},
-    transports::{tcp::TcpPlugin, udp::UdpPlugin},
+    conditioner::NetworkConditioner,
+    connection_state,
+    heartbeat::Heartbeat,
+    network_node::{NetworkNode, despawn_closed_nodes, network_node_event, on_close_node},
+    queue::QueueSettings,
+    shutdown::{ShutdownSettings, graceful_shutdown},
+    stats,
+    transformer::{
+        DecoderChannels, EncoderChannels, MessageTags, TaggedChannels, demultiplex_system,
+        inherit_encryption_key,
+    },
+    transports::{memory::MemoryPlugin, tcp::TcpPlugin, udp::UdpPlugin},
 };
 use bevy::{
-    app::{App, Plugin, PostUpdate, PreUpdate},
+    app::{App, Last, Plugin, PostUpdate, PreUpdate},
     prelude::{IntoScheduleConfigs, SystemSet},
 };
 
@@ -17,20 +26,36 @@ impl Plugin for OctopusPlugin {
         let app = register_reflect_types(app);
         app.init_resource::<EncoderChannels>()
             .init_resource::<DecoderChannels>()
+            .init_resource::<MessageTags>()
+            .init_resource::<TaggedChannels>()
+            .init_resource::<ShutdownSettings>()
             .add_message::<ChannelPacket>()
             .configure_sets(
                 PreUpdate,
                 (NetworkSet::Receive, NetworkSet::Decoding).chain(),
             )
             .configure_sets(PostUpdate, (NetworkSet::Encoding, NetworkSet::Send).chain())
-            .add_systems(PreUpdate, network_node_event.in_set(NetworkSet::Decoding))
+            .add_systems(PreUpdate, demultiplex_system.in_set(NetworkSet::Receive))
+            .add_systems(
+                PreUpdate,
+                (network_node_event, despawn_closed_nodes)
+                    .chain()
+                    .in_set(NetworkSet::Decoding),
+            )
             .add_systems(
                 PostUpdate,
                 send_channel_message_system.in_set(NetworkSet::Send),
             )
-            .add_plugins(client::plugin);
+            .add_systems(Last, graceful_shutdown)
+            .add_observer(on_close_node)
+            .add_observer(inherit_encryption_key)
+            .add_plugins((client::plugin, connection_state::plugin, stats::plugin));
 
-        app.add_plugins(UdpPlugin).add_plugins(TcpPlugin);
+        app.add_plugins(UdpPlugin)
+            .add_plugins(TcpPlugin)
+            .add_plugins(MemoryPlugin);
+        #[cfg(unix)]
+        app.add_plugins(crate::transports::unix::UnixPlugin);
     }
 }
 
@@ -45,5 +70,9 @@ pub enum NetworkSet {
 fn register_reflect_types(app: &mut App) -> &mut App {
     app.register_type::<ChannelId>()
         .register_type::<NetworkNode>()
+        .register_type::<Heartbeat>()
+        .register_type::<NetworkConditioner>()
+        .register_type::<QueueSettings>()
+        .register_type::<ShutdownSettings>()
         .register_type::<&'static str>()
 }
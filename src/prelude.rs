@@ -1,17 +1,38 @@
 #[cfg(feature = "inspect")]
 pub use bevy_inspector_egui;
 
+#[cfg(feature = "tls")]
+pub use crate::tls::TlsSettings;
 #[cfg(feature = "bincode")]
 pub use crate::transformer::BincodeTransformer;
+#[cfg(feature = "cbor")]
+pub use crate::transformer::CborTransformer;
+#[cfg(feature = "encryption")]
+pub use crate::transformer::Encrypted;
 #[cfg(feature = "serde_json")]
 pub use crate::transformer::JsonTransformer;
+#[cfg(feature = "msgpack")]
+pub use crate::transformer::MsgPackTransformer;
+#[cfg(feature = "postcard")]
+pub use crate::transformer::PostcardTransformer;
+#[cfg(feature = "protobuf")]
+pub use crate::transformer::ProtobufTransformer;
+#[cfg(unix)]
+pub use crate::transports::unix::{UnixAddress, UnixDatagramAddress, UnixPath};
 pub use crate::{
     channels::*,
     client::*,
+    conditioner::NetworkConditioner,
+    connection_state::{ConnectionState, entered_connection_state, in_connection_state},
     error::NetworkError,
+    framing::{FrameCodec, Framer, LengthPrefix},
+    heartbeat::{Heartbeat, LastSeen, NetworkRtt},
     network_node::*,
     plugin::OctopusPlugin,
+    queue::{OverflowPolicy, QueueSettings},
     server::*,
+    shutdown::ShutdownSettings,
+    stats::NetworkStats,
     transformer::*,
-    transports::{tcp::TcpAddress, udp::UdpAddress},
+    transports::{memory::MemoryAddress, tcp::TcpAddress, udp::UdpAddress},
 };
@@ -0,0 +1,372 @@
+use async_std::task;
+use bevy::{
+    ecs::{lifecycle::HookContext, world::DeferredWorld},
+    prelude::*,
+};
+use kanal::{AsyncReceiver, AsyncSender};
+
+use crate::network_node::{AsyncChannel, DisconnectReason, NetworkNode, NetworkRawPacket};
+
+/// What happens to a packet arriving at a full queue
+#[derive(Reflect, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the arriving packet
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued packet to make room
+    DropOldest,
+    /// Wait for room on the receive side, the transport stops reading the
+    /// socket while the receive queue is full. Sending never waits on the
+    /// calling system and drops the arriving packet like `DropNewest`
+    Block,
+    /// Close the node with [`DisconnectReason::QueueOverflow`]
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    /// Queue `packet` on the bounded send channel of `net_node`
+    pub(crate) fn send(self, net_node: &NetworkNode, packet: NetworkRawPacket) {
+        let channel = &net_node.send_message_channel;
+        let mut packet = Some(packet);
+        match self {
+            // waiting here would stall the app until a transport drains the queue
+            Self::DropNewest | Self::Block => {
+                if !matches!(channel.sender.try_send_option(&mut packet), Ok(true)) {
+                    net_node.traffic.dropped_sent();
+                }
+            }
+            Self::DropOldest => {
+                while let Ok(false) = channel.sender.try_send_option(&mut packet) {
+                    if let Ok(Some(_)) = channel.receiver.try_recv() {
+                        net_node.traffic.dropped_sent();
+                    }
+                }
+            }
+            Self::Disconnect => {
+                if !matches!(channel.sender.try_send_option(&mut packet), Ok(true)) {
+                    net_node.traffic.dropped_sent();
+                    net_node.close(DisconnectReason::QueueOverflow);
+                }
+            }
+        }
+    }
+}
+
+/// Bound the send and receive queues of a node.
+///
+/// Insert it together with the `ServerNode` or `ClientNode`, the channels of
+/// the node are bounded then, peers of a server inherit it. Nodes that
+/// already started keep their channels. Dropped packets are counted in
+/// [`NetworkStats`](crate::stats::NetworkStats) and reported as
+/// [`NetworkEvent::PacketsDropped`](crate::network_node::NetworkEvent::PacketsDropped).
+#[derive(Component, Reflect, Debug, Clone)]
+#[component(on_add = bound_node)]
+#[reflect(Component)]
+pub struct QueueSettings {
+    /// Packets waiting for the transport
+    pub send_capacity: usize,
+    /// Packets received and not consumed yet
+    pub recv_capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            send_capacity: 1024,
+            recv_capacity: 1024,
+            policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl QueueSettings {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            send_capacity: capacity,
+            recv_capacity: capacity,
+            policy,
+        }
+    }
+
+    /// Replace the channels of `net_node` with bounded ones
+    pub(crate) fn bound(&self, net_node: &mut NetworkNode) {
+        net_node.recv_message_channel = AsyncChannel::bounded(self.recv_capacity.max(1));
+        net_node.send_message_channel = AsyncChannel::bounded(self.send_capacity.max(1));
+        net_node.overflow = Some(self.policy);
+    }
+
+    /// Apply the policy to the packets a transport received, returning the
+    /// ends of the bounded node channels the transport uses.
+    ///
+    /// Sends face the policy in [`NetworkNode::send`] already.
+    pub fn spawn_layer(
+        &self,
+        net_node: &NetworkNode,
+    ) -> (
+        AsyncSender<NetworkRawPacket>,
+        AsyncReceiver<NetworkRawPacket>,
+    ) {
+        let recv_tx = net_node.recv_message_channel.sender.clone_async();
+        let message_rx = net_node.send_message_channel.receiver.clone_async();
+        // a full node channel makes the transport wait by itself
+        if self.policy == OverflowPolicy::Block {
+            return (recv_tx, message_rx);
+        }
+
+        let inbound: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+        let inbound_rx = inbound.receiver.clone_async();
+        // only held for dropping, it would keep the channel open
+        let oldest_received = (self.policy == OverflowPolicy::DropOldest)
+            .then(|| net_node.recv_message_channel.receiver.clone_async());
+        let shutdown_tx = net_node.shutdown_channel.sender.clone_async();
+        let traffic = net_node.traffic.clone();
+        let policy = self.policy;
+
+        task::spawn(async move {
+            while let Ok(packet) = inbound_rx.recv().await {
+                let mut packet = Some(packet);
+                match recv_tx.try_send_option(&mut packet) {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(_) => break,
+                }
+                match policy {
+                    OverflowPolicy::DropOldest => {
+                        while let Ok(false) = recv_tx.try_send_option(&mut packet) {
+                            if let Some(Ok(Some(_))) =
+                                oldest_received.as_ref().map(AsyncReceiver::try_recv)
+                            {
+                                traffic.dropped_received();
+                            }
+                        }
+                    }
+                    OverflowPolicy::Disconnect => {
+                        traffic.dropped_received();
+                        let _ = shutdown_tx.send(DisconnectReason::QueueOverflow).await;
+                        break;
+                    }
+                    _ => traffic.dropped_received(),
+                }
+            }
+        });
+
+        (inbound.sender.clone_async(), message_rx)
+    }
+}
+
+/// Bound the channels of a node given [`QueueSettings`] before it started
+pub(crate) fn bound_node(mut world: DeferredWorld, ctx: HookContext) {
+    let Some(settings) = world.get::<QueueSettings>(ctx.entity).cloned() else {
+        return;
+    };
+    let Some(mut net_node) = world.get_mut::<NetworkNode>(ctx.entity) else {
+        return;
+    };
+    if net_node.overflow.is_some() {
+        return;
+    }
+    if net_node.is_started() {
+        warn!(
+            "QueueSettings on {:?} ignored, the node already started",
+            ctx.entity
+        );
+        return;
+    }
+    settings.bound(&mut net_node);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use async_std::{future::timeout, task::block_on};
+
+    use super::*;
+    use crate::stats::NetworkStats;
+
+    const WAIT: Duration = Duration::from_secs(1);
+
+    fn node(policy: OverflowPolicy) -> NetworkNode {
+        let mut net_node = NetworkNode::default();
+        QueueSettings::new(2, policy).bound(&mut net_node);
+        net_node
+    }
+
+    fn packet(id: u8) -> NetworkRawPacket {
+        NetworkRawPacket {
+            addr: None,
+            bytes: vec![id].into(),
+            text: None,
+        }
+    }
+
+    fn ids(channel: &AsyncChannel<NetworkRawPacket>) -> Vec<u8> {
+        let mut ids = vec![];
+        while let Ok(Some(packet)) = channel.receiver.try_recv() {
+            ids.push(packet.bytes[0]);
+        }
+        ids
+    }
+
+    fn stats(net_node: &NetworkNode) -> NetworkStats {
+        let mut stats = NetworkStats::default();
+        net_node.traffic.snapshot(&mut stats);
+        stats
+    }
+
+    /// Hand `count` packets to the receive layer of `net_node`
+    fn receive(net_node: &NetworkNode, policy: OverflowPolicy, count: u8) {
+        let (recv_tx, _) = QueueSettings::new(2, policy).spawn_layer(net_node);
+        block_on(async {
+            for id in 1..=count {
+                recv_tx.send(packet(id)).await.unwrap();
+            }
+        });
+    }
+
+    /// Wait for the layer task to get `done`
+    fn eventually(done: impl Fn() -> bool) {
+        block_on(timeout(WAIT, async {
+            while !done() {
+                task::sleep(Duration::from_millis(1)).await;
+            }
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn full_send_queue_drops_the_newest() {
+        let net_node = node(OverflowPolicy::DropNewest);
+        for id in 1..=3 {
+            net_node.send(packet(id));
+        }
+
+        assert_eq!(ids(&net_node.send_message_channel), [1, 2]);
+        assert_eq!(stats(&net_node).dropped_sent, 1);
+    }
+
+    #[test]
+    fn full_send_queue_drops_the_oldest() {
+        let net_node = node(OverflowPolicy::DropOldest);
+        for id in 1..=4 {
+            net_node.send(packet(id));
+        }
+
+        assert_eq!(ids(&net_node.send_message_channel), [3, 4]);
+        assert_eq!(stats(&net_node).dropped_sent, 2);
+    }
+
+    #[test]
+    fn full_send_queue_closes_the_node() {
+        let net_node = node(OverflowPolicy::Disconnect);
+        for id in 1..=3 {
+            net_node.send(packet(id));
+        }
+
+        assert_eq!(
+            net_node.shutdown_channel.receiver.try_recv(),
+            Ok(Some(DisconnectReason::QueueOverflow))
+        );
+        assert_eq!(stats(&net_node).dropped_sent, 1);
+    }
+
+    #[test]
+    fn full_send_queue_does_not_wait_for_a_transport() {
+        let net_node = node(OverflowPolicy::Block);
+        let channel = net_node.send_message_channel.clone();
+        let traffic = net_node.traffic.clone();
+        // no transport ever takes the packets
+        let sending = thread::spawn(move || {
+            for id in 1..=3 {
+                net_node.send(packet(id));
+            }
+        });
+        eventually(|| sending.is_finished());
+
+        assert_eq!(ids(&channel), [1, 2]);
+        let mut stats = NetworkStats::default();
+        traffic.snapshot(&mut stats);
+        assert_eq!(stats.dropped_sent, 1);
+    }
+
+    #[test]
+    fn full_receive_queue_drops_the_newest() {
+        let net_node = node(OverflowPolicy::DropNewest);
+        receive(&net_node, OverflowPolicy::DropNewest, 3);
+        eventually(|| stats(&net_node).dropped_received == 1);
+
+        assert_eq!(ids(&net_node.recv_message_channel), [1, 2]);
+        assert_eq!(stats(&net_node).dropped_received, 1);
+    }
+
+    #[test]
+    fn full_receive_queue_drops_the_oldest() {
+        let net_node = node(OverflowPolicy::DropOldest);
+        receive(&net_node, OverflowPolicy::DropOldest, 4);
+        eventually(|| {
+            stats(&net_node).dropped_received == 2
+                && net_node.recv_message_channel.receiver.len() == 2
+        });
+
+        assert_eq!(ids(&net_node.recv_message_channel), [3, 4]);
+        assert_eq!(stats(&net_node).dropped_received, 2);
+    }
+
+    #[test]
+    fn full_receive_queue_closes_the_node() {
+        let net_node = node(OverflowPolicy::Disconnect);
+        receive(&net_node, OverflowPolicy::Disconnect, 3);
+        eventually(|| !net_node.shutdown_channel.receiver.is_empty());
+
+        assert_eq!(
+            net_node.shutdown_channel.receiver.try_recv(),
+            Ok(Some(DisconnectReason::QueueOverflow))
+        );
+        assert_eq!(stats(&net_node).dropped_received, 1);
+    }
+
+    #[test]
+    fn full_receive_queue_makes_the_transport_wait() {
+        let net_node = node(OverflowPolicy::Block);
+        let (recv_tx, _) = QueueSettings::new(2, OverflowPolicy::Block).spawn_layer(&net_node);
+
+        block_on(async {
+            recv_tx.send(packet(1)).await.unwrap();
+            recv_tx.send(packet(2)).await.unwrap();
+            let blocked = timeout(Duration::from_millis(50), recv_tx.send(packet(3))).await;
+            assert!(blocked.is_err());
+
+            assert_eq!(net_node.try_recv().unwrap().bytes[0], 1);
+            timeout(WAIT, recv_tx.send(packet(3)))
+                .await
+                .unwrap()
+                .unwrap();
+        });
+
+        assert_eq!(ids(&net_node.recv_message_channel), [2, 3]);
+        assert_eq!(stats(&net_node).dropped_received, 0);
+    }
+
+    #[test]
+    fn settings_bound_nodes_that_did_not_start() {
+        let mut world = World::new();
+        let idle = world
+            .spawn((NetworkNode::default(), QueueSettings::default()))
+            .id();
+        let started = world.spawn(NetworkNode::default()).id();
+        let (recv_tx, _) = world
+            .get::<NetworkNode>(started)
+            .unwrap()
+            .transport_channels(&Default::default());
+        world.entity_mut(started).insert(QueueSettings::default());
+
+        let capacity = |entity| {
+            let net_node = world.get::<NetworkNode>(entity).unwrap();
+            net_node.send_message_channel.sender.capacity()
+        };
+        assert_eq!(capacity(idle), 1024);
+        assert_eq!(capacity(started), usize::MAX);
+        drop(recv_tx);
+    }
+}
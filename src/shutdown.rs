@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use async_std::{future::timeout, task};
+use bevy::prelude::*;
+use futures::future;
+use kanal::AsyncReceiver;
+
+use crate::{
+    connection_state::ConnectionState,
+    network_node::{DisconnectReason, NetworkEvent, NetworkNode, NetworkPeer, NodeEvent},
+};
+
+/// How long nodes get to flush their sends and close, on exit and after a
+/// [`CloseNode`](crate::network_node::CloseNode)
+#[derive(Resource, Reflect, Debug, Clone)]
+#[reflect(Resource)]
+pub struct ShutdownSettings {
+    pub deadline: Duration,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Close every open node once [`AppExit`] is written and block until their
+/// transports are done or the deadline passed.
+///
+/// Peers are closed with [`DisconnectReason::ServerShutdown`], every other
+/// node with [`DisconnectReason::Closed`]. Events reported meanwhile are still
+/// triggered as [`NodeEvent`]s, nodes that did not finish in time are logged.
+pub fn graceful_shutdown(
+    mut commands: Commands,
+    mut exit: MessageReader<AppExit>,
+    settings: Res<ShutdownSettings>,
+    mut q_net: Query<(Entity, &NetworkNode, &mut ConnectionState, Has<NetworkPeer>)>,
+) {
+    if exit.read().last().is_none() {
+        return;
+    }
+
+    let mut pending = vec![];
+    for (entity, net_node, mut state, is_peer) in q_net.iter_mut() {
+        if !state.is_open() {
+            continue;
+        }
+        let reason = if is_peer {
+            DisconnectReason::ServerShutdown
+        } else {
+            DisconnectReason::Closed
+        };
+        net_node.close(reason);
+        *state = ConnectionState::Disconnecting;
+        pending.push(Closing {
+            entity,
+            event_rx: net_node.event_channel.receiver.clone_async(),
+            events: vec![],
+            closed: false,
+        });
+    }
+    debug!("waiting for {} network nodes to close", pending.len());
+
+    let closed = future::join_all(pending.iter_mut().map(Closing::wait));
+    let _ = task::block_on(timeout(settings.deadline, closed));
+
+    for node in pending {
+        if !node.closed {
+            warn!(
+                "network node {:?} did not close within {:?}",
+                node.entity, settings.deadline
+            );
+        }
+        for event in node.events {
+            commands.trigger(NodeEvent {
+                entity: node.entity,
+                event,
+            });
+        }
+    }
+}
+
+/// A node closing on exit and the events its transport reported meanwhile
+struct Closing {
+    entity: Entity,
+    event_rx: AsyncReceiver<NetworkEvent>,
+    events: Vec<NetworkEvent>,
+    closed: bool,
+}
+
+impl Closing {
+    /// Collect events until the transport reported the node disconnected
+    async fn wait(&mut self) {
+        while let Ok(event) = self.event_rx.recv().await {
+            let disconnected = matches!(event, NetworkEvent::Disconnected(_));
+            self.events.push(event);
+            if disconnected {
+                break;
+            }
+        }
+        self.closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+
+    use super::*;
+    use crate::connection_state;
+
+    #[derive(Resource, Default)]
+    struct Events(Vec<(Entity, String)>);
+
+    fn app(deadline: Duration) -> App {
+        let mut app = App::new();
+        app.insert_resource(ShutdownSettings { deadline })
+            .init_resource::<Events>()
+            .add_plugins(connection_state::plugin)
+            .add_observer(|on: On<NodeEvent>, mut events: ResMut<Events>| {
+                let ev = on.event();
+                events.0.push((ev.entity, format!("{:?}", ev.event)));
+            })
+            .add_systems(Last, graceful_shutdown);
+        app
+    }
+
+    fn state(app: &App, entity: Entity) -> ConnectionState {
+        *app.world().get::<ConnectionState>(entity).unwrap()
+    }
+
+    #[test]
+    fn waits_for_transports_without_losing_events() {
+        let mut app = app(Duration::from_secs(10));
+        let client = app
+            .world_mut()
+            .spawn((NetworkNode::default(), ConnectionState::Connected))
+            .id();
+        let node = app.world().get::<NetworkNode>(client).unwrap();
+        let shutdown_rx = node.shutdown_channel.receiver.clone();
+        let event_tx = node.event_channel.sender.clone();
+        // a transport flushing on another thread
+        let transport = thread::spawn(move || {
+            let reason = shutdown_rx.recv().unwrap();
+            event_tx
+                .send(NetworkEvent::PacketsDropped {
+                    sent: 1,
+                    received: 0,
+                })
+                .unwrap();
+            thread::sleep(Duration::from_millis(20));
+            event_tx.send(NetworkEvent::Disconnected(reason)).unwrap();
+        });
+
+        app.world_mut().write_message(AppExit::Success);
+        app.update();
+        transport.join().unwrap();
+
+        assert_eq!(state(&app, client), ConnectionState::Disconnected);
+        assert_eq!(
+            app.world().resource::<Events>().0,
+            [
+                (
+                    client,
+                    "PacketsDropped { sent: 1, received: 0 }".to_string()
+                ),
+                (client, "Disconnected(Closed)".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stuck_transports_are_given_up() {
+        let deadline = Duration::from_millis(50);
+        let mut app = app(deadline);
+        let server = app
+            .world_mut()
+            .spawn((NetworkNode::default(), ConnectionState::Listening))
+            .id();
+        let idle = app.world_mut().spawn(NetworkNode::default()).id();
+
+        app.world_mut().write_message(AppExit::Success);
+        let started = Instant::now();
+        app.update();
+
+        assert!(started.elapsed() >= deadline);
+        assert_eq!(state(&app, server), ConnectionState::Disconnecting);
+        assert_eq!(state(&app, idle), ConnectionState::Idle);
+        assert!(app.world().resource::<Events>().0.is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic},
+    prelude::*,
+};
+
+use crate::{
+    error::NetworkError,
+    network_node::{NetworkEvent, NetworkNode, NetworkRawPacket, NodeEvent},
+    plugin::NetworkSet,
+};
+
+pub(super) fn plugin(app: &mut App) {
+    app.register_type::<NetworkStats>()
+        .register_diagnostic(Diagnostic::new(NetworkStats::PACKETS_SENT).with_suffix("/s"))
+        .register_diagnostic(Diagnostic::new(NetworkStats::PACKETS_RECEIVED).with_suffix("/s"))
+        .register_diagnostic(Diagnostic::new(NetworkStats::BYTES_SENT).with_suffix(" B/s"))
+        .register_diagnostic(Diagnostic::new(NetworkStats::BYTES_RECEIVED).with_suffix(" B/s"))
+        .register_diagnostic(Diagnostic::new(NetworkStats::ERRORS).with_suffix("/s"))
+        .register_diagnostic(Diagnostic::new(NetworkStats::ENCODE_TIME).with_suffix("ms"))
+        .register_diagnostic(Diagnostic::new(NetworkStats::DECODE_TIME).with_suffix("ms"))
+        .register_diagnostic(Diagnostic::new(NetworkStats::SEND_QUEUE))
+        .register_diagnostic(Diagnostic::new(NetworkStats::RECV_QUEUE))
+        .add_systems(PostUpdate, update_network_stats.after(NetworkSet::Send))
+        .add_observer(count_errors);
+}
+
+/// Traffic of a node, required by [`NetworkNode`] and updated every frame.
+///
+/// Packets are counted when they pass [`NetworkNode::send`] and
+/// [`NetworkNode::try_recv`], which the transformers use. Totals of all nodes
+/// are measured as diagnostics under `network/`.
+#[derive(Component, Reflect, Debug, Default, Clone, PartialEq)]
+#[reflect(Component)]
+pub struct NetworkStats {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Packets dropped by the [`QueueSettings`](crate::queue::QueueSettings) of a full send queue
+    pub dropped_sent: u64,
+    /// Packets dropped by the [`QueueSettings`](crate::queue::QueueSettings) of a full receive queue
+    pub dropped_received: u64,
+    /// Every [`NetworkEvent::Error`], decode failures included
+    pub errors: u64,
+    pub decode_errors: u64,
+    /// Total time spent encoding messages for the node, a message encoded
+    /// once for several nodes counts for the first of them
+    pub encode_time: Duration,
+    /// Total time spent decoding messages of the node
+    pub decode_time: Duration,
+    /// Packets waiting for the transport
+    pub send_queue: usize,
+    /// Packets received and not consumed yet
+    pub recv_queue: usize,
+}
+
+impl NetworkStats {
+    pub const PACKETS_SENT: DiagnosticPath = DiagnosticPath::const_new("network/packets_sent");
+    pub const PACKETS_RECEIVED: DiagnosticPath =
+        DiagnosticPath::const_new("network/packets_received");
+    pub const BYTES_SENT: DiagnosticPath = DiagnosticPath::const_new("network/bytes_sent");
+    pub const BYTES_RECEIVED: DiagnosticPath = DiagnosticPath::const_new("network/bytes_received");
+    pub const ERRORS: DiagnosticPath = DiagnosticPath::const_new("network/errors");
+    /// Milliseconds spent encoding in the frame
+    pub const ENCODE_TIME: DiagnosticPath = DiagnosticPath::const_new("network/encode_time");
+    /// Milliseconds spent decoding in the frame
+    pub const DECODE_TIME: DiagnosticPath = DiagnosticPath::const_new("network/decode_time");
+    pub const SEND_QUEUE: DiagnosticPath = DiagnosticPath::const_new("network/send_queue");
+    pub const RECV_QUEUE: DiagnosticPath = DiagnosticPath::const_new("network/recv_queue");
+}
+
+/// Counters of a node written from any system or task
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounters {
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    dropped_sent: AtomicU64,
+    dropped_received: AtomicU64,
+    errors: AtomicU64,
+    decode_errors: AtomicU64,
+    encode_nanos: AtomicU64,
+    decode_nanos: AtomicU64,
+}
+
+impl TrafficCounters {
+    pub(crate) fn sent(&self, packet: &NetworkRawPacket) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(size(packet), Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, packet: &NetworkRawPacket) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(size(packet), Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped_sent(&self) {
+        self.dropped_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped_received(&self) {
+        self.dropped_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn encoded(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.encode_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn decoded(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.decode_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, stats: &mut NetworkStats) {
+        stats.packets_sent = self.packets_sent.load(Ordering::Relaxed);
+        stats.packets_received = self.packets_received.load(Ordering::Relaxed);
+        stats.bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        stats.bytes_received = self.bytes_received.load(Ordering::Relaxed);
+        stats.dropped_sent = self.dropped_sent.load(Ordering::Relaxed);
+        stats.dropped_received = self.dropped_received.load(Ordering::Relaxed);
+        stats.errors = self.errors.load(Ordering::Relaxed);
+        stats.decode_errors = self.decode_errors.load(Ordering::Relaxed);
+        stats.encode_time = Duration::from_nanos(self.encode_nanos.load(Ordering::Relaxed));
+        stats.decode_time = Duration::from_nanos(self.decode_nanos.load(Ordering::Relaxed));
+    }
+}
+
+fn size(packet: &NetworkRawPacket) -> u64 {
+    let text = packet.text.as_ref().map_or(0, String::len);
+    (packet.bytes.len() + text) as u64
+}
+
+fn count_errors(on: On<NodeEvent>, q_net: Query<&NetworkNode>) {
+    let ev = on.event();
+    let (NetworkEvent::Error(error), Ok(net_node)) = (&ev.event, q_net.get(ev.entity)) else {
+        return;
+    };
+    net_node.traffic.errors.fetch_add(1, Ordering::Relaxed);
+    if matches!(error, NetworkError::DeserializeError(_)) {
+        net_node
+            .traffic
+            .decode_errors
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Copy the counters into [`NetworkStats`], report dropped packets and
+/// measure the totals of the frame
+fn update_network_stats(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    mut diagnostics: Diagnostics,
+    mut q_net: Query<(Entity, &NetworkNode, &mut NetworkStats)>,
+) {
+    let mut frame = NetworkStats::default();
+    for (entity, net_node, mut stats) in q_net.iter_mut() {
+        let mut current = NetworkStats {
+            send_queue: net_node.send_message_channel.receiver.len(),
+            recv_queue: net_node.recv_message_channel.receiver.len(),
+            ..default()
+        };
+        net_node.traffic.snapshot(&mut current);
+
+        let sent = current.dropped_sent.saturating_sub(stats.dropped_sent);
+        let received = current
+            .dropped_received
+            .saturating_sub(stats.dropped_received);
+        if sent > 0 || received > 0 {
+            commands.trigger(NodeEvent {
+                entity,
+                event: NetworkEvent::PacketsDropped { sent, received },
+            });
+        }
+
+        frame.packets_sent += current.packets_sent.saturating_sub(stats.packets_sent);
+        frame.packets_received += current
+            .packets_received
+            .saturating_sub(stats.packets_received);
+        frame.bytes_sent += current.bytes_sent.saturating_sub(stats.bytes_sent);
+        frame.bytes_received += current.bytes_received.saturating_sub(stats.bytes_received);
+        frame.errors += current.errors.saturating_sub(stats.errors);
+        frame.encode_time += current.encode_time.saturating_sub(stats.encode_time);
+        frame.decode_time += current.decode_time.saturating_sub(stats.decode_time);
+        frame.send_queue += current.send_queue;
+        frame.recv_queue += current.recv_queue;
+        stats.set_if_neq(current);
+    }
+
+    let seconds = time.delta_secs_f64();
+    if seconds > 0.0 {
+        let rate = |count: u64| count as f64 / seconds;
+        diagnostics.add_measurement(&NetworkStats::PACKETS_SENT, || rate(frame.packets_sent));
+        diagnostics.add_measurement(&NetworkStats::PACKETS_RECEIVED, || {
+            rate(frame.packets_received)
+        });
+        diagnostics.add_measurement(&NetworkStats::BYTES_SENT, || rate(frame.bytes_sent));
+        diagnostics.add_measurement(&NetworkStats::BYTES_RECEIVED, || rate(frame.bytes_received));
+        diagnostics.add_measurement(&NetworkStats::ERRORS, || rate(frame.errors));
+    }
+    diagnostics.add_measurement(&NetworkStats::ENCODE_TIME, || {
+        frame.encode_time.as_secs_f64() * 1000.0
+    });
+    diagnostics.add_measurement(&NetworkStats::DECODE_TIME, || {
+        frame.decode_time.as_secs_f64() * 1000.0
+    });
+    diagnostics.add_measurement(&NetworkStats::SEND_QUEUE, || frame.send_queue as f64);
+    diagnostics.add_measurement(&NetworkStats::RECV_QUEUE, || frame.recv_queue as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{diagnostic::DiagnosticsPlugin, time::TimePlugin};
+
+    use super::*;
+
+    #[derive(Resource, Default)]
+    struct Dropped(Vec<(u64, u64)>);
+
+    fn record_dropped(on: On<NodeEvent>, mut dropped: ResMut<Dropped>) {
+        if let NetworkEvent::PacketsDropped { sent, received } = on.event().event {
+            dropped.0.push((sent, received));
+        }
+    }
+
+    fn packet(len: usize) -> NetworkRawPacket {
+        NetworkRawPacket {
+            addr: None,
+            bytes: vec![0; len].into(),
+            text: None,
+        }
+    }
+
+    #[test]
+    fn counters_are_copied_and_drops_reported() {
+        let mut app = App::new();
+        app.add_plugins((TimePlugin, DiagnosticsPlugin, plugin))
+            .init_resource::<Dropped>()
+            .add_observer(record_dropped);
+        let entity = app.world_mut().spawn(NetworkNode::default()).id();
+
+        let net_node = app.world().get::<NetworkNode>(entity).unwrap();
+        net_node.send(packet(10));
+        net_node.send(packet(20));
+        net_node.traffic.dropped_sent();
+        net_node.traffic.dropped_received();
+        net_node.traffic.dropped_received();
+        net_node.traffic.encoded(Duration::from_millis(5));
+        app.update();
+
+        let stats = app.world().get::<NetworkStats>(entity).unwrap();
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.bytes_sent, 30);
+        assert_eq!(stats.send_queue, 2);
+        assert_eq!(stats.dropped_sent, 1);
+        assert_eq!(stats.dropped_received, 2);
+        assert_eq!(stats.encode_time, Duration::from_millis(5));
+        assert_eq!(app.world().resource::<Dropped>().0, [(1, 2)]);
+
+        // only new drops are reported
+        app.update();
+        assert_eq!(app.world().resource::<Dropped>().0, [(1, 2)]);
+
+        let net_node = app.world().get::<NetworkNode>(entity).unwrap();
+        net_node.traffic.dropped_sent();
+        app.update();
+        assert_eq!(app.world().resource::<Dropped>().0, [(1, 2), (1, 0)]);
+        let stats = app.world().get::<NetworkStats>(entity).unwrap();
+        assert_eq!(stats.dropped_sent, 2);
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use async_std::future::timeout;
+use bevy::prelude::*;
+use futures::{AsyncRead, AsyncWrite};
+pub use futures_rustls::{
+    self, TlsAcceptor, TlsConnector,
+    rustls::{
+        self, ClientConfig, RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    },
+};
+use futures_rustls::{client, rustls::server::WebPkiClientVerifier, server};
+
+use crate::error::NetworkError;
+
+/// Time a remote gets to complete the TLS handshake
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS configuration of a stream node.
+///
+/// Servers need a certificate chain and private key, clients need the roots
+/// trusted to verify the server. Setting `client_auth_roots` on a server
+/// requires clients to present a certificate signed by one of them (mTLS).
+///
+/// The rustls configs are built on first use and shared by the clones of the
+/// settings, so every connection of a node reuses them. Change the fields
+/// before the node starts.
+#[derive(Component, Clone)]
+pub struct TlsSettings {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub private_key: Option<Arc<PrivateKeyDer<'static>>>,
+    /// Roots used by clients to verify the server
+    pub root_certs: Vec<CertificateDer<'static>>,
+    /// Roots used by servers to verify client certificates
+    pub client_auth_roots: Vec<CertificateDer<'static>>,
+    /// Name checked against the server certificate, defaults to the remote ip
+    pub server_name: Option<String>,
+    /// Handshakes not finished in time fail with [`NetworkError::Tls`]
+    pub handshake_timeout: Duration,
+    configs: Arc<Configs>,
+}
+
+/// rustls configs built from the settings
+#[derive(Default)]
+struct Configs {
+    server: OnceLock<Arc<ServerConfig>>,
+    client: OnceLock<Arc<ClientConfig>>,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            cert_chain: vec![],
+            private_key: None,
+            root_certs: vec![],
+            client_auth_roots: vec![],
+            server_name: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            configs: default(),
+        }
+    }
+}
+
+impl Debug for TlsSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsSettings")
+            .field("cert_chain", &self.cert_chain.len())
+            .field("private_key", &self.private_key.is_some())
+            .field("root_certs", &self.root_certs.len())
+            .field("client_auth_roots", &self.client_auth_roots.len())
+            .field("server_name", &self.server_name)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish()
+    }
+}
+
+impl TlsSettings {
+    pub fn server(
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Self {
+        Self {
+            cert_chain,
+            private_key: Some(Arc::new(private_key)),
+            ..default()
+        }
+    }
+
+    pub fn client(root_certs: Vec<CertificateDer<'static>>) -> Self {
+        Self {
+            root_certs,
+            ..default()
+        }
+    }
+
+    /// Server settings from PEM encoded certificate chain and private key files
+    pub fn server_from_pem_files(
+        cert_chain: impl AsRef<Path>,
+        private_key: impl AsRef<Path>,
+    ) -> Result<Self, NetworkError> {
+        Ok(Self::server(
+            load_certs(cert_chain)?,
+            PrivateKeyDer::from_pem_file(private_key).map_err(tls_error)?,
+        ))
+    }
+
+    /// Client settings trusting the certificates of a PEM file
+    pub fn client_from_pem_file(root_certs: impl AsRef<Path>) -> Result<Self, NetworkError> {
+        Ok(Self::client(load_certs(root_certs)?))
+    }
+
+    pub fn with_server_name(mut self, server_name: impl ToString) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self.configs = default();
+        self
+    }
+
+    /// Certificate presented by a client to servers requiring client authentication
+    pub fn with_client_cert(
+        mut self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.cert_chain = cert_chain;
+        self.private_key = Some(Arc::new(private_key));
+        self.configs = default();
+        self
+    }
+
+    /// Only accept clients with a certificate signed by one of these roots
+    pub fn with_client_auth(mut self, roots: Vec<CertificateDer<'static>>) -> Self {
+        self.client_auth_roots = roots;
+        self.configs = default();
+        self
+    }
+
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, NetworkError> {
+        if let Some(config) = self.configs.server.get() {
+            return Ok(config.clone());
+        }
+        let config = Arc::new(self.build_server_config()?);
+
+        Ok(self.configs.server.get_or_init(|| config).clone())
+    }
+
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, NetworkError> {
+        if let Some(config) = self.configs.client.get() {
+            return Ok(config.clone());
+        }
+        let config = Arc::new(self.build_client_config()?);
+
+        Ok(self.configs.client.get_or_init(|| config).clone())
+    }
+
+    fn build_server_config(&self) -> Result<ServerConfig, NetworkError> {
+        let Some(private_key) = &self.private_key else {
+            return Err(NetworkError::Tls(
+                "server requires a private key".to_string(),
+            ));
+        };
+        let builder = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = if self.client_auth_roots.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(root_store(&self.client_auth_roots)?),
+                crypto_provider(),
+            )
+            .build()
+            .map_err(tls_error)?;
+            builder.with_client_cert_verifier(verifier)
+        };
+        builder
+            .with_single_cert(self.cert_chain.clone(), private_key.clone_key())
+            .map_err(tls_error)
+    }
+
+    fn build_client_config(&self) -> Result<ClientConfig, NetworkError> {
+        let builder = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(root_store(&self.root_certs)?);
+        match &self.private_key {
+            Some(private_key) => builder
+                .with_client_auth_cert(self.cert_chain.clone(), private_key.clone_key())
+                .map_err(tls_error),
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+
+    /// Server side handshake on an accepted stream
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> Result<server::TlsStream<S>, NetworkError> {
+        let handshake = TlsAcceptor::from(self.server_config()?).accept(stream);
+        timeout(self.handshake_timeout, handshake)
+            .await
+            .map_err(|_| NetworkError::Tls("handshake timed out".to_string()))?
+            .map_err(tls_error)
+    }
+
+    /// Client side handshake, `remote_addr` is verified when no server name is set
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        remote_addr: SocketAddr,
+        stream: S,
+    ) -> Result<client::TlsStream<S>, NetworkError> {
+        let server_name = match &self.server_name {
+            Some(name) => ServerName::try_from(name.clone()).map_err(tls_error)?,
+            None => ServerName::IpAddress(remote_addr.ip().into()),
+        };
+
+        let handshake = TlsConnector::from(self.client_config()?).connect(server_name, stream);
+        timeout(self.handshake_timeout, handshake)
+            .await
+            .map_err(|_| NetworkError::Tls("handshake timed out".to_string()))?
+            .map_err(tls_error)
+    }
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn root_store(certs: &[CertificateDer<'static>]) -> Result<RootCertStore, NetworkError> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(cert.clone()).map_err(tls_error)?;
+    }
+
+    Ok(roots)
+}
+
+fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>, NetworkError> {
+    CertificateDer::pem_file_iter(path)
+        .map_err(tls_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_error)
+}
+
+fn tls_error(e: impl ToString) -> NetworkError {
+    NetworkError::Tls(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::{
+        net::{TcpListener, TcpStream},
+        task::block_on,
+    };
+    use futures::{AsyncReadExt, AsyncWriteExt, join};
+
+    use super::*;
+
+    /// Self signed certificate for `localhost` and its key
+    fn identity() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivateKeyDer::try_from(cert.signing_key.serialize_der()).unwrap();
+
+        (cert.cert.der().clone(), key)
+    }
+
+    /// Runs both sides of a handshake over loopback and sends one byte through
+    fn handshake(
+        server: &TlsSettings,
+        client: &TlsSettings,
+    ) -> (Result<(), NetworkError>, Result<(), NetworkError>) {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let server_side = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = server.accept(stream).await?;
+                let mut byte = [0];
+                stream.read_exact(&mut byte).await.map_err(tls_error)?;
+                assert_eq!(byte, [7]);
+                Ok(())
+            };
+            let client_side = async {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let mut stream = client.connect(addr, stream).await?;
+                stream.write_all(&[7]).await.map_err(tls_error)?;
+                stream.flush().await.map_err(tls_error)?;
+                Ok(())
+            };
+
+            join!(server_side, client_side)
+        })
+    }
+
+    #[test]
+    fn handshake_succeeds() {
+        let (cert, key) = identity();
+        let server = TlsSettings::server(vec![cert.clone()], key);
+        let client = TlsSettings::client(vec![cert]).with_server_name("localhost");
+
+        let (server_result, client_result) = handshake(&server, &client);
+        assert!(server_result.is_ok(), "{server_result:?}");
+        assert!(client_result.is_ok(), "{client_result:?}");
+    }
+
+    #[test]
+    fn untrusted_server_is_rejected() {
+        let (cert, key) = identity();
+        let (other_cert, _) = identity();
+        let server = TlsSettings::server(vec![cert], key);
+        let client = TlsSettings::client(vec![other_cert]).with_server_name("localhost");
+
+        let (_, client_result) = handshake(&server, &client);
+        assert!(matches!(client_result, Err(NetworkError::Tls(_))));
+    }
+
+    #[test]
+    fn mutual_tls_requires_a_client_cert() {
+        let (server_cert, server_key) = identity();
+        let (client_cert, client_key) = identity();
+        let server = TlsSettings::server(vec![server_cert.clone()], server_key)
+            .with_client_auth(vec![client_cert.clone()]);
+        let client = TlsSettings::client(vec![server_cert]).with_server_name("localhost");
+
+        let (server_result, _) = handshake(&server, &client);
+        assert!(matches!(server_result, Err(NetworkError::Tls(_))));
+
+        let client = client.with_client_cert(vec![client_cert], client_key);
+        let (server_result, client_result) = handshake(&server, &client);
+        assert!(server_result.is_ok(), "{server_result:?}");
+        assert!(client_result.is_ok(), "{client_result:?}");
+    }
+
+    #[test]
+    fn silent_remote_times_out() {
+        let (cert, key) = identity();
+        let server =
+            TlsSettings::server(vec![cert], key).with_handshake_timeout(Duration::from_millis(50));
+
+        let result = block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let _client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            server.accept(stream).await.map(|_| ())
+        });
+
+        assert!(matches!(result, Err(NetworkError::Tls(e)) if e.contains("timed out")));
+    }
+
+    #[test]
+    fn configs_are_shared_by_clones() {
+        let (cert, key) = identity();
+        let server = TlsSettings::server(vec![cert.clone()], key);
+        let client = TlsSettings::client(vec![cert]);
+
+        assert!(Arc::ptr_eq(
+            &server.server_config().unwrap(),
+            &server.clone().server_config().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            &client.client_config().unwrap(),
+            &client.clone().client_config().unwrap()
+        ));
+        // builders start over
+        let renamed = client.clone().with_server_name("localhost");
+        assert!(!Arc::ptr_eq(
+            &client.client_config().unwrap(),
+            &renamed.client_config().unwrap()
+        ));
+    }
+}
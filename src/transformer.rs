@@ -1,26 +1,73 @@
-use std::{any::TypeId, collections::HashMap, fmt::Debug, marker::PhantomData};
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    fmt::Debug,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
 
 use bevy::{prelude::*, reflect::GetTypeRegistration};
 use bytes::Bytes;
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde::{
+    Deserialize, Serialize,
+    de::{DeserializeOwned, DeserializeSeed},
+};
 
 #[cfg(feature = "bincode")]
 pub use bincode::BincodeTransformer;
+#[cfg(feature = "cbor")]
+pub use cbor::CborTransformer;
+#[cfg(feature = "lz4")]
+pub use compression::Lz4;
+#[cfg(feature = "zstd")]
+pub use compression::Zstd;
+pub use compression::{Compressed, Compression, DEFAULT_COMPRESSION_THRESHOLD};
+pub use dynamic::ReflectTransformer;
+#[cfg(feature = "encryption")]
+pub use encryption::Encrypted;
+pub use encryption::EncryptionKey;
+pub(crate) use encryption::inherit_encryption_key;
+pub use envelope::{MessageInbox, message_tag};
+pub(crate) use envelope::{MessageTags, TaggedChannels, demultiplex_system};
+#[cfg(feature = "msgpack")]
+pub use msgpack::MsgPackTransformer;
+#[cfg(feature = "postcard")]
+pub use postcard::PostcardTransformer;
+#[cfg(feature = "protobuf")]
+pub use protobuf::ProtobufTransformer;
 #[cfg(feature = "serde_json")]
 pub use serde_json::JsonTransformer;
 
 use crate::{
-    channels::{ChannelId, ReceiveChannelMessage, SendChannelMessage},
+    channels::{
+        ChannelId, ReceiveChannelMessage, SendChannelMessage, SendTo, TargetNode, resolve_target,
+    },
     client::ClientTag,
+    connection_state::ConnectionState,
     error::NetworkError,
-    network_node::{NetworkEvent, NetworkNode, NetworkRawPacket},
+    network_node::{NetworkEvent, NetworkNode, NetworkPeer, NetworkRawPacket},
+    plugin::NetworkSet,
 };
 
 #[cfg(feature = "bincode")]
 mod bincode;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod compression;
+mod dynamic;
+mod encryption;
+mod envelope;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+#[cfg(feature = "postcard")]
+mod postcard;
+#[cfg(feature = "protobuf")]
+mod protobuf;
 
 #[cfg(feature = "serde_json")]
 mod serde_json;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub trait Transformer:
     'static + Send + Sync + Reflect + Resource + Default + GetTypeRegistration
@@ -30,37 +77,173 @@ pub trait Transformer:
     fn decode<T: for<'a> Deserialize<'a>>(&self, bytes: &[u8]) -> Result<T, NetworkError>;
 }
 
+/// Formats that decode through a [`DeserializeSeed`], needed by [`ReflectTransformer`]
+pub trait SeededTransformer: Transformer {
+    fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        &self,
+        seed: S,
+        bytes: &'de [u8],
+    ) -> Result<S::Value, NetworkError>;
+}
+
+/// Encodes and decodes messages of type `M` on a channel.
+///
+/// Every serde [`Transformer`] is a codec for all serde types, implement it
+/// directly for formats with their own message traits like protobuf. The
+/// resource is created with [`FromWorld`] unless it was inserted before.
+pub trait MessageCodec<M>:
+    'static + Send + Sync + Reflect + Resource + FromWorld + GetTypeRegistration
+{
+    const NAME: &'static str;
+    fn encode_message(&self, message: &M) -> Result<Vec<u8>, NetworkError>;
+    fn decode_message(&self, bytes: &[u8]) -> Result<M, NetworkError>;
+
+    /// Encode for the node of `context`, codecs that do not depend on it ignore it
+    fn encode_for(&self, message: &M, _context: &CodecContext) -> Result<Vec<u8>, NetworkError> {
+        self.encode_message(message)
+    }
+
+    /// Decode a packet received by the node of `context`, codecs that do not
+    /// depend on it ignore it
+    fn decode_for(&self, bytes: &[u8], _context: &CodecContext) -> Result<M, NetworkError> {
+        self.decode_message(bytes)
+    }
+}
+
+/// Side of the connection a node is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeSide {
+    Client,
+    /// Server nodes and their peers
+    Server,
+}
+
+impl NodeSide {
+    pub(crate) fn of(client: bool, peer: bool) -> Self {
+        if client && !peer {
+            NodeSide::Client
+        } else {
+            NodeSide::Server
+        }
+    }
+
+    /// Side of the other end
+    pub fn remote(self) -> Self {
+        match self {
+            NodeSide::Client => NodeSide::Server,
+            NodeSide::Server => NodeSide::Client,
+        }
+    }
+}
+
+/// Node a message is encoded for or decoded on
+#[derive(Debug, Clone, Copy)]
+pub struct CodecContext<'a> {
+    pub channel_id: ChannelId,
+    pub side: NodeSide,
+    /// Key of a node with its own [`EncryptionKey`]
+    pub key: Option<&'a EncryptionKey>,
+}
+
+impl<M: Serialize + DeserializeOwned, T: Transformer> MessageCodec<M> for T {
+    const NAME: &'static str = T::NAME;
+
+    fn encode_message(&self, message: &M) -> Result<Vec<u8>, NetworkError> {
+        self.encode(message)
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> Result<M, NetworkError> {
+        self.decode(bytes)
+    }
+}
+
+/// Longest name a wrapping codec builds from the names it wraps
+const CODEC_NAME_CAPACITY: usize = 128;
+
+/// Name of a codec wrapping others, joined at compile time.
+///
+/// ```ignore,rust
+/// const NAME: &'static str = CodecName::join(&["Compressed<", T::NAME, ">"]).as_str();
+/// ```
+pub(crate) struct CodecName([u8; CODEC_NAME_CAPACITY], usize);
+
+impl CodecName {
+    /// Concatenate `parts`, cut at the capacity
+    pub(crate) const fn join(parts: &[&str]) -> Self {
+        let mut bytes = [0; CODEC_NAME_CAPACITY];
+        let mut len = 0;
+        let mut i = 0;
+        while i < parts.len() {
+            let part = parts[i].as_bytes();
+            let mut j = 0;
+            while j < part.len() && len < CODEC_NAME_CAPACITY {
+                bytes[len] = part[j];
+                len += 1;
+                j += 1;
+            }
+            i += 1;
+        }
+
+        Self(bytes, len)
+    }
+
+    pub(crate) const fn as_str(&self) -> &str {
+        let (name, _) = self.0.split_at(self.1);
+        match std::str::from_utf8(name) {
+            Ok(name) => name,
+            // cut inside a character
+            Err(e) => match std::str::from_utf8(name.split_at(e.valid_up_to()).0) {
+                Ok(name) => name,
+                Err(_) => "",
+            },
+        }
+    }
+}
+
 pub trait NetworkMessageTransformer {
-    fn add_transformer<
-        M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-        T: Transformer,
-    >(
+    fn add_transformer<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
         &mut self,
         channel_id: ChannelId,
     ) -> &mut Self;
 
-    fn add_encoder<
-        M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-        T: Transformer,
-    >(
+    fn add_encoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
         &mut self,
         channel_id: ChannelId,
     ) -> &mut Self;
 
-    fn add_decoder<
-        M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-        T: Transformer,
-    >(
+    fn add_decoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
         &mut self,
         channel_id: ChannelId,
     ) -> &mut Self;
+
+    /// Multiplex `M` with other tagged message types on one channel.
+    ///
+    /// Every payload is prefixed with `tag`, see [`message_tag`] for a name based tag.
+    /// Conflicting registrations are logged as errors and ignored: a tag used by
+    /// another type of the channel, a second tag for `M` and channels that
+    /// already have an untagged decoder. Untagged decoders added to a
+    /// multiplexed channel are ignored the same way.
+    fn add_tagged_transformer<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
+        &mut self,
+        channel_id: ChannelId,
+        tag: u32,
+    ) -> &mut Self;
+
+    fn add_tagged_encoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
+        &mut self,
+        channel_id: ChannelId,
+        tag: u32,
+    ) -> &mut Self;
+
+    fn add_tagged_decoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
+        &mut self,
+        channel_id: ChannelId,
+        tag: u32,
+    ) -> &mut Self;
 }
 
 impl NetworkMessageTransformer for App {
-    fn add_transformer<
-        M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-        T: Transformer,
-    >(
+    fn add_transformer<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
         &mut self,
         channel_id: ChannelId,
     ) -> &mut Self {
@@ -68,10 +251,7 @@ impl NetworkMessageTransformer for App {
             .add_decoder::<M, T>(channel_id)
     }
 
-    fn add_encoder<
-        M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-        T: Transformer,
-    >(
+    fn add_encoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
         &mut self,
         channel_id: ChannelId,
     ) -> &mut Self {
@@ -96,18 +276,19 @@ impl NetworkMessageTransformer for App {
         } else {
             encoder_channels.insert((message_type_id, transform_type_id), vec![channel_id]);
             self.add_systems(PostUpdate, spawn_encoder_marker::<M, T>);
-            self.add_systems(PostUpdate, encode_system::<M, T>);
+            self.add_systems(
+                PostUpdate,
+                encode_system::<M, T>.in_set(NetworkSet::Encoding),
+            );
         }
 
-        self.add_message::<ReceiveChannelMessage<M>>();
+        self.add_message::<SendChannelMessage<M>>()
+            .add_message::<SendTo<M>>();
 
         self
     }
 
-    fn add_decoder<
-        M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-        T: Transformer,
-    >(
+    fn add_decoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
         &mut self,
         channel_id: ChannelId,
     ) -> &mut Self {
@@ -126,19 +307,125 @@ impl NetworkMessageTransformer for App {
         let transform_type_id = TypeId::of::<T>();
         let message_type_id = TypeId::of::<M>();
 
+        if self
+            .world()
+            .resource::<TaggedChannels>()
+            .contains_key(&channel_id)
+            && !self
+                .world()
+                .resource::<MessageTags>()
+                .contains_key(&(message_type_id, channel_id))
+        {
+            error!(
+                "{channel_id} is multiplexed, register {} with add_tagged_decoder, decoder ignored",
+                std::any::type_name::<M>()
+            );
+            return self;
+        }
+
         let mut decoder_channels = self.world_mut().resource_mut::<DecoderChannels>();
         if let Some(ids) = decoder_channels.get_mut(&(message_type_id, transform_type_id)) {
             ids.push(channel_id);
         } else {
             decoder_channels.insert((message_type_id, transform_type_id), vec![channel_id]);
-            self.add_systems(PreUpdate, decode_system::<M, T>);
+            self.add_systems(
+                PreUpdate,
+                decode_system::<M, T>.in_set(NetworkSet::Decoding),
+            );
             self.add_systems(PostUpdate, spawn_decoder_marker::<M, T>);
         }
 
-        self.add_message::<SendChannelMessage<M>>();
+        self.add_message::<ReceiveChannelMessage<M>>();
 
         self
     }
+
+    fn add_tagged_transformer<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
+        &mut self,
+        channel_id: ChannelId,
+        tag: u32,
+    ) -> &mut Self {
+        self.add_tagged_encoder::<M, T>(channel_id, tag)
+            .add_tagged_decoder::<M, T>(channel_id, tag)
+    }
+
+    fn add_tagged_encoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
+        &mut self,
+        channel_id: ChannelId,
+        tag: u32,
+    ) -> &mut Self {
+        if let Err(e) = check_message_tag::<M>(self.world(), channel_id, tag) {
+            error!("{e}, encoder ignored");
+            return self;
+        }
+        register_message_tag::<M>(self.world_mut(), channel_id, tag);
+
+        self.add_encoder::<M, T>(channel_id)
+    }
+
+    fn add_tagged_decoder<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
+        &mut self,
+        channel_id: ChannelId,
+        tag: u32,
+    ) -> &mut Self {
+        let world = self.world();
+        let message_tags = world.resource::<MessageTags>();
+        if let Some((message_type_id, _)) =
+            world
+                .resource::<DecoderChannels>()
+                .iter()
+                .find(|((message_type_id, _), channels)| {
+                    channels.contains(&channel_id)
+                        && !message_tags.contains_key(&(*message_type_id, channel_id))
+                })
+        {
+            error!(
+                "{channel_id} already has an untagged decoder for {message_type_id:?}, decoder for {} ignored",
+                std::any::type_name::<M>()
+            );
+            return self;
+        }
+        if let Err(e) = check_message_tag::<M>(world, channel_id, tag).and_then(|_| {
+            world
+                .resource::<TaggedChannels>()
+                .check::<M>(channel_id, tag)
+        }) {
+            error!("{e}, decoder ignored");
+            return self;
+        }
+
+        let world = self.world_mut();
+        register_message_tag::<M>(world, channel_id, tag);
+        world
+            .resource_mut::<TaggedChannels>()
+            .register::<M>(channel_id, tag);
+
+        self.add_decoder::<M, T>(channel_id)
+    }
+}
+
+/// A message type keeps one tag per channel
+fn check_message_tag<M: 'static>(
+    world: &World,
+    channel_id: ChannelId,
+    tag: u32,
+) -> Result<(), String> {
+    match world
+        .resource::<MessageTags>()
+        .get(&(TypeId::of::<M>(), channel_id))
+    {
+        Some(&existing) if existing != tag => Err(format!(
+            "{} is already tagged {existing:#010x} on {channel_id}, not {tag:#010x}",
+            std::any::type_name::<M>()
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn register_message_tag<M: 'static>(world: &mut World, channel_id: ChannelId, tag: u32) {
+    world
+        .resource_mut::<MessageTags>()
+        .insert((TypeId::of::<M>(), channel_id), tag);
 }
 
 pub(crate) type TransformerTypeId = TypeId;
@@ -162,17 +449,12 @@ pub struct TransformerSenderMarker {
 }
 
 #[derive(Component, Debug)]
-pub struct EncoderMarker<
-    M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-    T: Transformer,
-> {
+pub struct EncoderMarker<M: Send + Sync + Debug + 'static, T: MessageCodec<M>> {
     _message: PhantomData<M>,
     _transformer: PhantomData<T>,
 }
 
-impl<M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static, T: Transformer> Default
-    for EncoderMarker<M, T>
-{
+impl<M: Send + Sync + Debug + 'static, T: MessageCodec<M>> Default for EncoderMarker<M, T> {
     fn default() -> Self {
         Self {
             _message: PhantomData,
@@ -182,17 +464,12 @@ impl<M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static, T: Transfo
 }
 
 #[derive(Component, Debug)]
-pub struct DecoderMarker<
-    M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-    T: Transformer,
-> {
+pub struct DecoderMarker<M: Send + Sync + Debug + 'static, T: MessageCodec<M>> {
     _message: PhantomData<M>,
     _transformer: PhantomData<T>,
 }
 
-impl<M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static, T: Transformer> Default
-    for DecoderMarker<M, T>
-{
+impl<M: Send + Sync + Debug + 'static, T: MessageCodec<M>> Default for DecoderMarker<M, T> {
     fn default() -> Self {
         Self {
             _message: PhantomData,
@@ -203,19 +480,95 @@ impl<M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static, T: Transfo
 
 /// encode system fro encoder marker
 #[allow(clippy::type_complexity)]
-fn encode_system<
-    M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-    T: Transformer + bevy::prelude::Resource,
->(
+fn encode_system<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
     mut message_ev: MessageReader<SendChannelMessage<M>>,
+    mut send_to_ev: MessageReader<SendTo<M>>,
     transformer: Res<T>,
-    query: Query<(&ChannelId, &NetworkNode), (With<EncoderMarker<M, T>>, With<ClientTag>)>,
+    message_tags: Res<MessageTags>,
+    query: Query<
+        (
+            &ChannelId,
+            &NetworkNode,
+            &ConnectionState,
+            Option<&EncryptionKey>,
+            Has<NetworkPeer>,
+        ),
+        (With<EncoderMarker<M, T>>, With<ClientTag>),
+    >,
+    q_target: Query<TargetNode, With<EncoderMarker<M, T>>>,
+    q_node: Query<(Option<&EncryptionKey>, Has<ClientTag>, Has<NetworkPeer>)>,
 ) {
+    for message in send_to_ev.read() {
+        let nodes = q_target.iter().filter(|node| node.state.is_open());
+        let targets = resolve_target(&message.target, &message.channel_id, nodes);
+        if targets.is_empty() {
+            trace!(
+                "{} no node matches {:?} for {}",
+                message.channel_id,
+                message.target,
+                std::any::type_name::<M>(),
+            );
+            continue;
+        }
+
+        let tag = envelope::tag_of::<M>(&message_tags, &message.channel_id);
+        let encode = |context: CodecContext| {
+            let started = Instant::now();
+            let encoded = transformer
+                .encode_for(&message.message, &context)
+                .map(|bytes| match tag {
+                    Some(tag) => envelope::wrap_tagged(tag, &bytes),
+                    None => Bytes::from(bytes),
+                })
+                .map_err(|e| e.to_string());
+            (encoded, started.elapsed())
+        };
+        // nodes with their own key get their own encoding, the others share
+        // one per side, its time is counted for the node it was encoded for
+        let mut shared = HashMap::new();
+        for (entity, net_node, addr) in targets {
+            let (key, client, peer) = q_node.get(entity).unwrap_or_default();
+            let context = CodecContext {
+                channel_id: message.channel_id,
+                side: NodeSide::of(client, peer),
+                key,
+            };
+            let (encoded, elapsed) = match (key, shared.get(&context.side)) {
+                (Some(_), _) => encode(context),
+                (None, Some(encoded)) => (Clone::clone(encoded), Duration::ZERO),
+                (None, None) => {
+                    let (encoded, elapsed) = encode(context);
+                    shared.insert(context.side, encoded.clone());
+                    (encoded, elapsed)
+                }
+            };
+            net_node.traffic.encoded(elapsed);
+            match encoded {
+                Ok(bytes) => net_node.send(NetworkRawPacket {
+                    addr,
+                    bytes,
+                    text: None,
+                }),
+                Err(e) => {
+                    let _ = net_node
+                        .event_channel
+                        .sender
+                        .send(NetworkEvent::Error(NetworkError::SerializeError(e)));
+                }
+            }
+        }
+    }
+
     for message in message_ev.read() {
-        for (channel_id, net_node) in query.iter() {
-            if channel_id != &message.channel_id || !net_node.running {
+        for (channel_id, net_node, state, key, peer) in query.iter() {
+            if channel_id != &message.channel_id || !state.is_open() {
                 continue;
             }
+            let context = CodecContext {
+                channel_id: *channel_id,
+                side: NodeSide::of(true, peer),
+                key,
+            };
 
             trace!(
                 "{} {} Encoding message for {}",
@@ -223,11 +576,18 @@ fn encode_system<
                 T::NAME,
                 std::any::type_name::<M>(),
             );
-            match transformer.encode(&message.message) {
+            let started = Instant::now();
+            let encoded = transformer.encode_for(&message.message, &context);
+            net_node.traffic.encoded(started.elapsed());
+            match encoded {
                 Ok(bytes) => {
-                    let _ = net_node.send_message_channel.sender.send(NetworkRawPacket {
+                    let bytes = match envelope::tag_of::<M>(&message_tags, channel_id) {
+                        Some(tag) => envelope::wrap_tagged(tag, &bytes),
+                        None => Bytes::from(bytes),
+                    };
+                    net_node.send(NetworkRawPacket {
                         addr: None,
-                        bytes: Bytes::from_iter(bytes),
+                        bytes,
                         text: None,
                     });
                 }
@@ -244,26 +604,53 @@ fn encode_system<
 
 /// decode system with decoder marker
 #[allow(clippy::type_complexity)]
-fn decode_system<
-    M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-    T: Transformer + bevy::prelude::Resource,
->(
+fn decode_system<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
     mut channel_message: MessageWriter<ReceiveChannelMessage<M>>,
     mut commands: Commands,
     transformer: Res<T>,
-    query: Query<(Entity, &ChannelId, &NetworkNode), With<DecoderMarker<M, T>>>,
+    message_tags: Res<MessageTags>,
+    mut query: Query<
+        (
+            Entity,
+            &ChannelId,
+            &NetworkNode,
+            Option<&EncryptionKey>,
+            Has<ClientTag>,
+            Has<NetworkPeer>,
+            Option<&mut MessageInbox>,
+        ),
+        With<DecoderMarker<M, T>>,
+    >,
 ) {
-    for (entity, channel_id, network_node) in query.iter() {
+    for (entity, channel_id, network_node, key, client, peer, inbox) in query.iter_mut() {
+        let context = CodecContext {
+            channel_id: *channel_id,
+            side: NodeSide::of(client, peer),
+            key,
+        };
         let mut packets = vec![];
-        while let Ok(Some(packet)) = network_node.recv_message_channel.receiver.try_recv() {
-            packets.push(packet.bytes);
+        match (envelope::tag_of::<M>(&message_tags, channel_id), inbox) {
+            (Some(tag), Some(mut inbox)) => packets = inbox.take(tag),
+            // packets wait in the channel until the inbox is inserted
+            (Some(_), None) => continue,
+            (None, _) => {
+                while let Some(packet) = network_node.try_recv() {
+                    packets.push(packet);
+                }
+            }
         }
 
         if !packets.is_empty() {
+            let started = Instant::now();
             let (messages, errors): (Vec<_>, Vec<_>) = packets
                 .into_iter()
-                .map(|msg| transformer.decode::<M>(&msg))
+                .map(|packet| {
+                    transformer
+                        .decode_for(&packet.bytes, &context)
+                        .map(|m| (m, packet.addr))
+                })
                 .partition(Result::is_ok);
+            network_node.traffic.decoded(started.elapsed());
             trace!(
                 "{} decoding {} {} packets error {} for {}",
                 channel_id,
@@ -276,7 +663,9 @@ fn decode_system<
                 messages
                     .into_iter()
                     .map(Result::unwrap)
-                    .map(|m| ReceiveChannelMessage::new(*channel_id, m))
+                    .map(|(m, addr)| {
+                        ReceiveChannelMessage::new(*channel_id, m).with_sender(entity, addr)
+                    })
                     .collect::<Vec<_>>(),
             );
             for error in errors.into_iter().map(Result::unwrap_err) {
@@ -289,10 +678,7 @@ fn decode_system<
     }
 }
 
-fn spawn_encoder_marker<
-    M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-    T: Transformer,
->(
+fn spawn_encoder_marker<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
     mut commands: Commands,
     mt_ids: Res<EncoderChannels>,
     q_channel: Query<(Entity, &ChannelId), Added<ChannelId>>,
@@ -314,12 +700,10 @@ fn spawn_encoder_marker<
     }
 }
 
-fn spawn_decoder_marker<
-    M: Serialize + DeserializeOwned + Send + Sync + Debug + 'static,
-    T: Transformer,
->(
+fn spawn_decoder_marker<M: Send + Sync + Debug + 'static, T: MessageCodec<M>>(
     mut commands: Commands,
     mt_ids: Res<DecoderChannels>,
+    message_tags: Res<MessageTags>,
     q_channel: Query<(Entity, &ChannelId), Added<ChannelId>>,
 ) {
     for (entity, channel_id) in q_channel.iter() {
@@ -335,6 +719,11 @@ fn spawn_decoder_marker<
             commands
                 .entity(entity)
                 .insert(DecoderMarker::<M, T>::default());
+            if envelope::tag_of::<M>(&message_tags, channel_id).is_some() {
+                commands
+                    .entity(entity)
+                    .insert_if_new(MessageInbox::default());
+            }
         }
     }
 }
@@ -1,7 +1,11 @@
 use bevy::prelude::{Reflect, Resource};
-use serde::{Deserialize, Serialize};
+use bincode::Options;
+use serde::{Deserialize, Serialize, de::DeserializeSeed};
 
-use crate::{error::NetworkError, transformer::Transformer};
+use crate::{
+    error::NetworkError,
+    transformer::{SeededTransformer, Transformer},
+};
 
 #[derive(Resource, Default, Reflect)]
 pub struct BincodeTransformer;
@@ -20,3 +24,18 @@ impl Transformer for BincodeTransformer {
         }
     }
 }
+
+impl SeededTransformer for BincodeTransformer {
+    fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        &self,
+        seed: S,
+        bytes: &'de [u8],
+    ) -> Result<S::Value, NetworkError> {
+        // the options of `bincode::deserialize`
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .deserialize_seed(seed, bytes)
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
@@ -0,0 +1,201 @@
+use bevy::prelude::{Reflect, Resource};
+use ciborium::{Value, value};
+use serde::{
+    Deserialize, Serialize,
+    de::{
+        self, DeserializeSeed, Deserializer, IntoDeserializer, Visitor,
+        value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer},
+    },
+};
+
+use crate::{
+    error::NetworkError,
+    transformer::{SeededTransformer, Transformer},
+};
+
+#[derive(Resource, Default, Reflect)]
+pub struct CborTransformer;
+
+impl Transformer for CborTransformer {
+    const NAME: &'static str = "Cbor";
+
+    fn encode<T: Serialize>(&self, data: &T) -> Result<Vec<u8>, NetworkError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(data, &mut bytes)
+            .map_err(|e| NetworkError::SerializeError(e.to_string()))?;
+
+        Ok(bytes)
+    }
+
+    fn decode<T: for<'a> Deserialize<'a>>(&self, bytes: &[u8]) -> Result<T, NetworkError> {
+        ciborium::from_reader(bytes).map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+/// ciborium keeps its deserializers private, the seed reads the decoded [`Value`]
+impl SeededTransformer for CborTransformer {
+    fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        &self,
+        seed: S,
+        bytes: &'de [u8],
+    ) -> Result<S::Value, NetworkError> {
+        let value: Value = ciborium::from_reader(bytes)
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))?;
+
+        seed.deserialize(ValueDeserializer(value))
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+/// Deserializes a [`Value`] the way ciborium serialized it
+struct ValueDeserializer(Value);
+
+impl<'de> IntoDeserializer<'de, value::Error> for ValueDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> Deserializer<'de> for ValueDeserializer {
+    type Error = value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Integer(integer) => match (u64::try_from(integer), i64::try_from(integer)) {
+                (Ok(unsigned), _) => visitor.visit_u64(unsigned),
+                (_, Ok(signed)) => visitor.visit_i64(signed),
+                _ => visitor.visit_i128(integer.into()),
+            },
+            Value::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            Value::Float(float) => visitor.visit_f64(float),
+            Value::Text(text) => visitor.visit_string(text),
+            Value::Bool(bool) => visitor.visit_bool(bool),
+            Value::Null => visitor.visit_unit(),
+            Value::Tag(_, value) => ValueDeserializer(*value).deserialize_any(visitor),
+            Value::Array(values) => {
+                let mut seq = SeqDeserializer::new(values.into_iter().map(ValueDeserializer));
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Value::Map(entries) => {
+                let mut map = MapDeserializer::new(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (ValueDeserializer(key), ValueDeserializer(value))),
+                );
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            _ => Err(de::Error::custom("unsupported CBOR value")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    /// Unit variants are text, the others a map of the variant to its content
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Text(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Value::Map(entries) if entries.len() == 1 => {
+                let map = MapDeserializer::new(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (ValueDeserializer(key), ValueDeserializer(value))),
+                );
+                visitor.visit_enum(MapAccessDeserializer::new(map))
+            }
+            Value::Tag(_, value) => {
+                ValueDeserializer(*value).deserialize_enum(name, variants, visitor)
+            }
+            _ => Err(de::Error::custom("expected an enum variant")),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomData;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::CborTransformer;
+    use crate::transformer::{
+        SeededTransformer, Transformer,
+        testing::{assert_rejected, round_trip},
+    };
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Point,
+        Circle(f32),
+        Rect { width: u8, height: u8 },
+    }
+
+    #[test]
+    fn round_trip_player() {
+        round_trip(&CborTransformer);
+    }
+
+    #[test]
+    fn truncated_bytes() {
+        let bytes = round_trip(&CborTransformer);
+
+        assert_rejected(&CborTransformer, &bytes[..bytes.len() - 1]);
+    }
+
+    #[test]
+    fn seeded_round_trip() {
+        type Message = (Vec<Shape>, Option<i64>, Option<String>, Vec<u8>);
+        let message: Message = (
+            vec![
+                Shape::Point,
+                Shape::Circle(1.5),
+                Shape::Rect {
+                    width: 2,
+                    height: 3,
+                },
+            ],
+            Some(-7),
+            None,
+            vec![0, 255],
+        );
+        let bytes = CborTransformer.encode(&message).unwrap();
+
+        let decoded = CborTransformer
+            .decode_seed(PhantomData::<Message>, &bytes)
+            .unwrap();
+        assert_eq!(decoded, message);
+    }
+}
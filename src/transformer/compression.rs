@@ -0,0 +1,393 @@
+#[cfg(feature = "zstd")]
+use std::sync::Mutex;
+use std::{borrow::Cow, sync::OnceLock};
+
+use bevy::{
+    prelude::{FromReflect, Reflect, Resource},
+    reflect::{GetTypeRegistration, Typed},
+};
+
+use crate::{
+    error::NetworkError,
+    framing::DEFAULT_MAX_FRAME_SIZE,
+    transformer::{CodecContext, CodecName, MessageCodec},
+};
+
+/// Payloads shorter than this are not worth compressing
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 128;
+
+/// Flag byte in front of a payload sent as is
+const RAW: u8 = 0;
+/// Flag byte in front of a compressed payload
+const COMPRESSED: u8 = 1;
+
+/// Compression algorithm of a [`Compressed`] transformer
+pub trait Compression:
+    'static + Send + Sync + Default + FromReflect + Typed + GetTypeRegistration
+{
+    const NAME: &'static str;
+
+    /// Prepared dictionary and state reused by every message
+    type Context: Send + Sync;
+
+    fn context(&self, dictionary: Option<&[u8]>) -> Result<Self::Context, NetworkError>;
+
+    fn compress(&self, context: &Self::Context, bytes: &[u8]) -> Result<Vec<u8>, NetworkError>;
+
+    /// Decompress `bytes`, failing when the result exceeds `max_size`
+    fn decompress(
+        &self,
+        context: &Self::Context,
+        bytes: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, NetworkError>;
+}
+
+/// Compresses the payloads of the transformer `T` with `C`.
+///
+/// Every payload starts with a flag byte, payloads shorter than `threshold`
+/// are sent uncompressed. Insert it as a resource before registering the
+/// transformer to change the defaults, both sides need the same dictionary.
+/// The dictionary and `compression` are prepared with the first message.
+///
+/// ```ignore,rust
+/// app.insert_resource(Compressed::<BincodeTransformer, Zstd>::default().with_threshold(512))
+///     .add_transformer::<WorldState, Compressed<BincodeTransformer, Zstd>>(STATE_CHANNEL);
+/// ```
+#[derive(Resource, Reflect)]
+pub struct Compressed<T, C: Compression> {
+    pub inner: T,
+    pub compression: C,
+    pub threshold: usize,
+    /// Upper bound of a decompressed payload
+    pub max_size: usize,
+    /// Pre-trained dictionary
+    #[reflect(ignore)]
+    dictionary: Option<Vec<u8>>,
+    #[reflect(ignore)]
+    context: OnceLock<C::Context>,
+}
+
+impl<T: Default, C: Compression> Default for Compressed<T, C> {
+    fn default() -> Self {
+        Self {
+            inner: T::default(),
+            compression: C::default(),
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_size: DEFAULT_MAX_FRAME_SIZE,
+            dictionary: None,
+            context: OnceLock::new(),
+        }
+    }
+}
+
+impl<T, C: Compression> Compressed<T, C> {
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_dictionary(mut self, dictionary: impl Into<Vec<u8>>) -> Self {
+        self.dictionary = Some(dictionary.into());
+        self.context = OnceLock::new();
+        self
+    }
+
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_deref()
+    }
+
+    fn context(&self) -> Result<&C::Context, NetworkError> {
+        if let Some(context) = self.context.get() {
+            return Ok(context);
+        }
+        let context = self.compression.context(self.dictionary.as_deref())?;
+
+        Ok(self.context.get_or_init(|| context))
+    }
+
+    /// Flag and compress an encoded payload
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        if payload.len() < self.threshold {
+            return Ok([&[RAW], payload].concat());
+        }
+
+        let compressed = self.compression.compress(self.context()?, payload)?;
+        // incompressible payloads are sent as they are
+        if compressed.len() >= payload.len() {
+            return Ok([&[RAW], payload].concat());
+        }
+
+        Ok([&[COMPRESSED], &compressed[..]].concat())
+    }
+
+    /// Encoded payload of flagged `bytes`
+    fn decompress<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, NetworkError> {
+        match bytes.split_first() {
+            Some((&RAW, payload)) => Ok(Cow::Borrowed(payload)),
+            Some((&COMPRESSED, compressed)) => self
+                .compression
+                .decompress(self.context()?, compressed, self.max_size)
+                .map(Cow::Owned),
+            Some((flag, _)) => Err(NetworkError::DeserializeError(format!(
+                "unknown {} compression flag {:#04x}",
+                C::NAME,
+                flag
+            ))),
+            None => Err(NetworkError::DeserializeError(
+                "empty compressed payload".to_string(),
+            )),
+        }
+    }
+}
+
+impl<M, T, C> MessageCodec<M> for Compressed<T, C>
+where
+    T: MessageCodec<M> + Default + FromReflect + Typed,
+    C: Compression,
+{
+    const NAME: &'static str =
+        CodecName::join(&["Compressed<", T::NAME, ", ", C::NAME, ">"]).as_str();
+
+    fn encode_message(&self, message: &M) -> Result<Vec<u8>, NetworkError> {
+        let payload = self.inner.encode_message(message)?;
+        self.compress(&payload)
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> Result<M, NetworkError> {
+        let payload = self.decompress(bytes)?;
+        self.inner.decode_message(&payload)
+    }
+
+    fn encode_for(&self, message: &M, context: &CodecContext) -> Result<Vec<u8>, NetworkError> {
+        let payload = self.inner.encode_for(message, context)?;
+        self.compress(&payload)
+    }
+
+    fn decode_for(&self, bytes: &[u8], context: &CodecContext) -> Result<M, NetworkError> {
+        let payload = self.decompress(bytes)?;
+        self.inner.decode_for(&payload, context)
+    }
+}
+
+/// Zstandard at `level`, best ratio for large state messages
+#[cfg(feature = "zstd")]
+#[derive(Reflect, Debug, Clone)]
+pub struct Zstd {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Self {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+/// Zstandard contexts with the dictionary loaded
+#[cfg(feature = "zstd")]
+pub struct ZstdContext {
+    compressor: Mutex<zstd::bulk::Compressor<'static>>,
+    decompressor: Mutex<zstd::bulk::Decompressor<'static>>,
+}
+
+#[cfg(feature = "zstd")]
+impl Compression for Zstd {
+    const NAME: &'static str = "Zstd";
+
+    type Context = ZstdContext;
+
+    fn context(&self, dictionary: Option<&[u8]>) -> Result<ZstdContext, NetworkError> {
+        let dictionary = dictionary.unwrap_or_default();
+        let compressor = zstd::bulk::Compressor::with_dictionary(self.level, dictionary)
+            .map_err(|e| NetworkError::SerializeError(e.to_string()))?;
+        let decompressor = zstd::bulk::Decompressor::with_dictionary(dictionary)
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))?;
+
+        Ok(ZstdContext {
+            compressor: Mutex::new(compressor),
+            decompressor: Mutex::new(decompressor),
+        })
+    }
+
+    fn compress(&self, context: &ZstdContext, bytes: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        context
+            .compressor
+            .lock()
+            .unwrap()
+            .compress(bytes)
+            .map_err(|e| NetworkError::SerializeError(e.to_string()))
+    }
+
+    fn decompress(
+        &self,
+        context: &ZstdContext,
+        bytes: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, NetworkError> {
+        context
+            .decompressor
+            .lock()
+            .unwrap()
+            .decompress(bytes, max_size)
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+/// LZ4 block compression, fastest to compress and decompress
+#[cfg(feature = "lz4")]
+#[derive(Reflect, Debug, Default, Clone)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Compression for Lz4 {
+    const NAME: &'static str = "Lz4";
+
+    /// The dictionary, LZ4 has nothing to prepare
+    type Context = Option<Vec<u8>>;
+
+    fn context(&self, dictionary: Option<&[u8]>) -> Result<Self::Context, NetworkError> {
+        Ok(dictionary.map(<[u8]>::to_vec))
+    }
+
+    fn compress(&self, dictionary: &Self::Context, bytes: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        Ok(match dictionary {
+            Some(dictionary) => lz4_flex::block::compress_prepend_size_with_dict(bytes, dictionary),
+            None => lz4_flex::block::compress_prepend_size(bytes),
+        })
+    }
+
+    fn decompress(
+        &self,
+        dictionary: &Self::Context,
+        bytes: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, NetworkError> {
+        let (size, bytes) = lz4_flex::block::uncompressed_size(bytes)
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))?;
+        if size > max_size {
+            return Err(NetworkError::DeserializeError(format!(
+                "decompressed size {} exceeds the maximum of {} bytes",
+                size, max_size
+            )));
+        }
+
+        match dictionary {
+            Some(dictionary) => lz4_flex::block::decompress_with_dict(bytes, size, dictionary),
+            None => lz4_flex::block::decompress(bytes, size),
+        }
+        .map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+#[cfg(all(test, any(feature = "zstd", feature = "lz4")))]
+mod tests {
+    use super::*;
+    use crate::transformer::testing::Raw;
+
+    /// Repetitive payload that compresses well
+    fn state() -> Vec<u8> {
+        b"position 1 2 3 health 100 ".repeat(40)
+    }
+
+    fn decode<C: Compression>(
+        codec: &Compressed<Raw, C>,
+        bytes: &[u8],
+    ) -> Result<Vec<u8>, NetworkError> {
+        codec.decode_message(bytes)
+    }
+
+    fn round_trip<C: Compression>(codec: &Compressed<Raw, C>, message: Vec<u8>) -> Vec<u8> {
+        let bytes = codec.encode_message(&message).unwrap();
+        assert_eq!(decode(codec, &bytes).unwrap(), message);
+        bytes
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn small_payloads_are_sent_raw() {
+        let codec = Compressed::<Raw, Lz4>::default();
+        let bytes = round_trip(&codec, b"hello".to_vec());
+
+        assert_eq!(bytes, b"\0hello");
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trip() {
+        let codec = Compressed::<Raw, Lz4>::default();
+        let bytes = round_trip(&codec, state());
+
+        assert_eq!(bytes[0], COMPRESSED);
+        assert!(bytes.len() < state().len());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        let codec = Compressed::<Raw, Zstd>::default();
+        let bytes = round_trip(&codec, state());
+
+        assert_eq!(bytes[0], COMPRESSED);
+        assert!(bytes.len() < state().len());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_context_is_prepared_once() {
+        let codec = Compressed::<Raw, Zstd>::default();
+        round_trip(&codec, state());
+        let context = codec.context.get().map(|context| context as *const _);
+
+        round_trip(&codec, state());
+        assert!(context.is_some());
+        assert_eq!(
+            codec.context.get().map(|context| context as *const _),
+            context
+        );
+        let codec = codec.with_dictionary(state());
+        assert!(codec.context.get().is_none());
+        round_trip(&codec, state());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn name_shows_the_inner_codec() {
+        assert_eq!(
+            <Compressed<Raw, Lz4> as MessageCodec<Vec<u8>>>::NAME,
+            "Compressed<Raw, Lz4>"
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_dictionary() {
+        let codec = Compressed::<Raw, Zstd>::default().with_dictionary(state());
+        let bytes = round_trip(&codec, state());
+        let without = Compressed::<Raw, Zstd>::default();
+
+        assert!(bytes.len() < without.encode_message(&state()).unwrap().len());
+        assert!(decode(&without, &bytes).is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn max_size_is_enforced() {
+        let bytes = Compressed::<Raw, Lz4>::default()
+            .encode_message(&state())
+            .unwrap();
+        let codec = Compressed::<Raw, Lz4>::default().with_max_size(64);
+
+        assert!(matches!(
+            decode(&codec, &bytes),
+            Err(NetworkError::DeserializeError(_))
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashSet,
+    fmt::{self, Formatter},
+};
+
+use bevy::{
+    prelude::*,
+    reflect::{
+        TypeRegistration, TypeRegistry, TypeRegistryArc, Typed,
+        serde::{ReflectDeserializer, ReflectSerializer, TypedReflectDeserializer},
+    },
+};
+use serde::de::{DeserializeSeed, Deserializer, Error, IgnoredAny, MapAccess, Visitor};
+
+use crate::{
+    error::NetworkError,
+    transformer::{CodecName, MessageCodec, SeededTransformer},
+};
+
+/// Sends reflected values of types only known at runtime, encoded with the
+/// transformer `T`.
+///
+/// Messages are `Box<dyn PartialReflect>` of any type registered in the
+/// app's `TypeRegistry`, the type path travels with the value. Received values
+/// are dynamic, turn them into concrete types with `FromReflect` or the
+/// `ReflectFromReflect` type data. Restrict the types a peer may send with
+/// [`with_allowed_type`](Self::with_allowed_type).
+///
+/// ```ignore,rust
+/// app.register_type::<Spawn>()
+///     .add_transformer::<Box<dyn PartialReflect>, ReflectTransformer<JsonTransformer>>(EDITOR_CHANNEL);
+/// ```
+#[derive(Resource, Reflect)]
+pub struct ReflectTransformer<T> {
+    pub format: T,
+    #[reflect(ignore)]
+    registry: TypeRegistryArc,
+    /// Types received values may have, any registered type if `None`
+    #[reflect(ignore)]
+    allowed: Option<HashSet<TypeId>>,
+}
+
+impl<T> ReflectTransformer<T> {
+    pub fn new(format: T, registry: TypeRegistryArc) -> Self {
+        Self {
+            format,
+            registry,
+            allowed: None,
+        }
+    }
+
+    /// Only decode values of `A` and the other allowed types, values of other
+    /// types fail before they are deserialized
+    pub fn with_allowed_type<A: Any>(mut self) -> Self {
+        self.allowed
+            .get_or_insert_default()
+            .insert(TypeId::of::<A>());
+        self
+    }
+}
+
+/// Shares the `AppTypeRegistry`, inserting it if the world has none
+impl<T: Default> FromWorld for ReflectTransformer<T> {
+    fn from_world(world: &mut World) -> Self {
+        let registry = world.get_resource_or_init::<AppTypeRegistry>().0.clone();
+
+        Self::new(T::default(), registry)
+    }
+}
+
+impl<T> MessageCodec<Box<dyn PartialReflect>> for ReflectTransformer<T>
+where
+    T: SeededTransformer + FromReflect + Typed,
+{
+    const NAME: &'static str = CodecName::join(&["Reflect<", T::NAME, ">"]).as_str();
+
+    fn encode_message(&self, message: &Box<dyn PartialReflect>) -> Result<Vec<u8>, NetworkError> {
+        let registry = self.registry.read();
+        self.format
+            .encode(&ReflectSerializer::new(message.as_ref(), &registry))
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> Result<Box<dyn PartialReflect>, NetworkError> {
+        let registry = self.registry.read();
+        match &self.allowed {
+            Some(allowed) => self.format.decode_seed(
+                AllowedDeserializer {
+                    registry: &registry,
+                    allowed,
+                },
+                bytes,
+            ),
+            None => self
+                .format
+                .decode_seed(ReflectDeserializer::new(&registry), bytes),
+        }
+    }
+}
+
+/// [`ReflectDeserializer`] that checks the type path against the allowed types
+struct AllowedDeserializer<'a> {
+    registry: &'a TypeRegistry,
+    allowed: &'a HashSet<TypeId>,
+}
+
+impl<'de> DeserializeSeed<'de> for AllowedDeserializer<'_> {
+    type Value = Box<dyn PartialReflect>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for AllowedDeserializer<'_> {
+    type Value = Box<dyn PartialReflect>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("map with the type path and the value of an allowed type")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let type_path: String = map
+            .next_key()?
+            .ok_or_else(|| Error::invalid_length(0, &"a single entry"))?;
+        let registration = self
+            .registry
+            .get_with_type_path(&type_path)
+            .filter(|registration| {
+                self.allowed
+                    .contains(&TypeRegistration::type_id(registration))
+            })
+            .ok_or_else(|| Error::custom(format!("type `{}` is not allowed", type_path)))?;
+        let value =
+            map.next_value_seed(TypedReflectDeserializer::new(registration, self.registry))?;
+
+        if map.next_key::<IgnoredAny>()?.is_some() {
+            return Err(Error::invalid_length(2, &"a single entry"));
+        }
+
+        Ok(value)
+    }
+}
+
+#[cfg(all(
+    test,
+    any(
+        feature = "serde_json",
+        feature = "bincode",
+        feature = "cbor",
+        feature = "msgpack",
+        feature = "postcard"
+    )
+))]
+mod tests {
+    use super::*;
+
+    #[derive(Reflect, Debug, PartialEq)]
+    struct Spawn {
+        name: String,
+        position: (f32, f32),
+        hidden: Option<bool>,
+    }
+
+    fn spawn() -> Spawn {
+        Spawn {
+            name: "crate".to_string(),
+            position: (1.5, -2.0),
+            hidden: Some(false),
+        }
+    }
+
+    fn codec<T: Default>() -> ReflectTransformer<T> {
+        let registry = TypeRegistryArc::default();
+        registry.write().register::<Spawn>();
+
+        ReflectTransformer::new(T::default(), registry)
+    }
+
+    fn round_trip<T: SeededTransformer + FromReflect + Typed>(codec: &ReflectTransformer<T>) {
+        let message: Box<dyn PartialReflect> = Box::new(spawn());
+        let bytes = codec.encode_message(&message).unwrap();
+        let decoded = codec.decode_message(&bytes).unwrap();
+
+        assert_eq!(Spawn::from_reflect(decoded.as_ref()), Some(spawn()));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn json_round_trip() {
+        round_trip(&codec::<crate::transformer::JsonTransformer>());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() {
+        round_trip(&codec::<crate::transformer::BincodeTransformer>());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        round_trip(&codec::<crate::transformer::CborTransformer>());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trip() {
+        round_trip(&codec::<crate::transformer::MsgPackTransformer>());
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trip() {
+        round_trip(&codec::<crate::transformer::PostcardTransformer>());
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn only_allowed_types_are_decoded() {
+        #[derive(Reflect, Debug, PartialEq)]
+        struct Despawn(u32);
+
+        let codec = codec::<crate::transformer::JsonTransformer>();
+        codec.registry.write().register::<Despawn>();
+        let spawn_bytes = codec.encode_message(&(Box::new(spawn()) as _)).unwrap();
+        let despawn_bytes = codec.encode_message(&(Box::new(Despawn(7)) as _)).unwrap();
+        let codec = codec.with_allowed_type::<Spawn>();
+
+        round_trip(&codec);
+        let decoded = codec.decode_message(&spawn_bytes).unwrap();
+        assert_eq!(Spawn::from_reflect(decoded.as_ref()), Some(spawn()));
+        assert!(matches!(
+            codec.decode_message(&despawn_bytes),
+            Err(NetworkError::DeserializeError(e)) if e.contains("is not allowed")
+        ));
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn allowed_types_without_self_description() {
+        let codec = codec::<crate::transformer::PostcardTransformer>().with_allowed_type::<Spawn>();
+
+        round_trip(&codec);
+    }
+
+    #[test]
+    fn shares_the_app_registry() {
+        let mut world = World::new();
+        let codec = ReflectTransformer::<()>::from_world(&mut world);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Spawn>();
+
+        assert!(codec.registry.read().contains(TypeId::of::<Spawn>()));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn unregistered_types_fail_to_decode() {
+        let codec = codec::<crate::transformer::JsonTransformer>();
+        let message: Box<dyn PartialReflect> = Box::new(spawn());
+        let bytes = codec.encode_message(&message).unwrap();
+        let empty = ReflectTransformer::new(
+            crate::transformer::JsonTransformer,
+            TypeRegistryArc::default(),
+        );
+
+        assert!(matches!(
+            empty.decode_message(&bytes),
+            Err(NetworkError::DeserializeError(_))
+        ));
+    }
+}
@@ -0,0 +1,539 @@
+use std::fmt::{self, Debug};
+
+use bevy::prelude::*;
+
+use crate::network_node::NetworkPeer;
+#[cfg(feature = "encryption")]
+pub use encrypted::Encrypted;
+
+/// 256 bit key of an `Encrypted` transformer.
+///
+/// Insert it next to a `ServerNode` or `ClientNode` to use it instead of the
+/// key of the transformer resource, peers of a server inherit it.
+#[derive(Component, Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Random key from the operating system
+    #[cfg(feature = "encryption")]
+    pub fn generate() -> Self {
+        use chacha20poly1305::{KeyInit, XChaCha20Poly1305, aead::OsRng};
+
+        Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Give new peers the key of their server
+pub(crate) fn inherit_encryption_key(
+    on: On<Insert, ChildOf>,
+    mut commands: Commands,
+    q_peer: Query<&ChildOf, (With<NetworkPeer>, Without<EncryptionKey>)>,
+    q_key: Query<&EncryptionKey>,
+) {
+    if let Ok(child_of) = q_peer.get(on.entity)
+        && let Ok(key) = q_key.get(child_of.parent())
+    {
+        commands.entity(on.entity).insert(key.clone());
+    }
+}
+
+#[cfg(feature = "encryption")]
+mod encrypted {
+    use std::{
+        collections::HashMap,
+        sync::{
+            Mutex,
+            atomic::{AtomicU64, Ordering},
+        },
+    };
+
+    use bevy::{
+        prelude::{FromReflect, Reflect, Resource},
+        reflect::Typed,
+    };
+    use chacha20poly1305::{
+        KeyInit, XChaCha20Poly1305, XNonce,
+        aead::{Aead, OsRng, Payload, rand_core::RngCore},
+    };
+
+    use super::EncryptionKey;
+    use crate::{
+        channels::ChannelId,
+        error::NetworkError,
+        transformer::{CodecContext, CodecName, MessageCodec, NodeSide},
+    };
+
+    /// Random id of the sending side, the first part of every nonce
+    type SenderId = [u8; 16];
+
+    /// Sender id and counter in front of the ciphertext, also the nonce
+    const HEADER_LEN: usize = 24;
+
+    /// Counters this far behind the highest one received are rejected
+    const REPLAY_WINDOW: u64 = 64;
+
+    /// Senders with a replay window, the one heard from least recently is
+    /// forgotten for a new one
+    const MAX_SENDERS: usize = 1024;
+
+    /// Encrypts and authenticates the payloads of the transformer `T` with
+    /// XChaCha20-Poly1305.
+    ///
+    /// Every payload carries a nonce made of a random sender id and a counter,
+    /// tampered, truncated and replayed packets fail to decode, as do packets
+    /// reflected back to their sender. Replays are tracked for the 1024 senders
+    /// heard from last. Packets of a node are authenticated with the side that
+    /// sent them and the channel, they fail to decode on another channel or
+    /// when sent back in the other direction. The key of the resource is used
+    /// unless the node has its own [`EncryptionKey`], insert the resource
+    /// before registering the transformer.
+    ///
+    /// ```ignore,rust
+    /// app.insert_resource(Encrypted::<BincodeTransformer>::default().with_key(key))
+    ///     .add_transformer::<PlayerInput, Encrypted<BincodeTransformer>>(INPUT_CHANNEL);
+    /// ```
+    #[derive(Resource, Reflect)]
+    pub struct Encrypted<T> {
+        pub inner: T,
+        #[reflect(ignore)]
+        pub key: Option<EncryptionKey>,
+        /// Client and server nodes of one app send with their own id
+        #[reflect(ignore)]
+        senders: [Sender; 2],
+        #[reflect(ignore)]
+        replay: Mutex<Replay>,
+    }
+
+    impl<T: Default> Default for Encrypted<T> {
+        fn default() -> Self {
+            Self {
+                inner: T::default(),
+                key: None,
+                senders: Default::default(),
+                replay: Default::default(),
+            }
+        }
+    }
+
+    impl<T> Encrypted<T> {
+        pub fn with_key(mut self, key: EncryptionKey) -> Self {
+            self.key = Some(key);
+            self
+        }
+
+        fn encrypt(
+            &self,
+            payload: &[u8],
+            key: Option<&EncryptionKey>,
+            side: NodeSide,
+            aad: &[u8],
+        ) -> Result<Vec<u8>, NetworkError> {
+            let Some(key) = key.or(self.key.as_ref()) else {
+                return Err(NetworkError::SerializeError(
+                    "no encryption key".to_string(),
+                ));
+            };
+
+            let sender = &self.senders[side as usize];
+            let mut packet = Vec::with_capacity(HEADER_LEN + payload.len() + 16);
+            packet.extend_from_slice(&sender.id);
+            packet.extend_from_slice(&sender.next_counter().to_be_bytes());
+            let ciphertext = XChaCha20Poly1305::new(key.as_bytes().into())
+                .encrypt(XNonce::from_slice(&packet), Payload { msg: payload, aad })
+                .map_err(|_| NetworkError::SerializeError("encryption failed".to_string()))?;
+            packet.extend_from_slice(&ciphertext);
+
+            Ok(packet)
+        }
+
+        fn decrypt(
+            &self,
+            bytes: &[u8],
+            key: Option<&EncryptionKey>,
+            side: Option<NodeSide>,
+            aad: &[u8],
+        ) -> Result<Vec<u8>, NetworkError> {
+            let Some(key) = key.or(self.key.as_ref()) else {
+                return Err(NetworkError::DeserializeError(
+                    "no encryption key".to_string(),
+                ));
+            };
+            if bytes.len() < HEADER_LEN {
+                return Err(NetworkError::DeserializeError(format!(
+                    "encrypted packet of {} bytes is too short",
+                    bytes.len()
+                )));
+            }
+
+            let (nonce, ciphertext) = bytes.split_at(HEADER_LEN);
+            let (sender, counter) = nonce.split_at(16);
+            let sender: SenderId = sender.try_into().unwrap();
+            let counter = u64::from_be_bytes(counter.try_into().unwrap());
+            // the other side of the same app is a legitimate sender
+            let reflected = match side {
+                Some(side) => self.senders[side as usize].id == sender,
+                None => self.senders.iter().any(|own| own.id == sender),
+            };
+            if reflected {
+                return Err(NetworkError::DeserializeError(
+                    "packet reflected back to its sender".to_string(),
+                ));
+            }
+
+            if !self.replay.lock().unwrap().is_fresh(&sender, counter) {
+                return Err(NetworkError::DeserializeError(format!(
+                    "replayed packet {counter}"
+                )));
+            }
+
+            let payload = XChaCha20Poly1305::new(key.as_bytes().into())
+                .decrypt(
+                    XNonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad,
+                    },
+                )
+                .map_err(|_| {
+                    NetworkError::DeserializeError("packet authentication failed".to_string())
+                })?;
+
+            // only authenticated packets move the window
+            let mut replay = self.replay.lock().unwrap();
+            if !replay.is_fresh(&sender, counter) {
+                return Err(NetworkError::DeserializeError(format!(
+                    "replayed packet {counter}"
+                )));
+            }
+            replay.mark(sender, counter);
+
+            Ok(payload)
+        }
+    }
+
+    impl<M, T> MessageCodec<M> for Encrypted<T>
+    where
+        T: MessageCodec<M> + Default + FromReflect + Typed,
+    {
+        const NAME: &'static str = CodecName::join(&["Encrypted<", T::NAME, ">"]).as_str();
+
+        /// Without a node the packet is bound to no direction or channel
+        fn encode_message(&self, message: &M) -> Result<Vec<u8>, NetworkError> {
+            let payload = self.inner.encode_message(message)?;
+            self.encrypt(&payload, None, NodeSide::Client, &[])
+        }
+
+        fn decode_message(&self, bytes: &[u8]) -> Result<M, NetworkError> {
+            let payload = self.decrypt(bytes, None, None, &[])?;
+            self.inner.decode_message(&payload)
+        }
+
+        fn encode_for(&self, message: &M, context: &CodecContext) -> Result<Vec<u8>, NetworkError> {
+            let payload = self.inner.encode_for(message, context)?;
+            let aad = associated_data(context.side, &context.channel_id);
+            self.encrypt(&payload, context.key, context.side, &aad)
+        }
+
+        fn decode_for(&self, bytes: &[u8], context: &CodecContext) -> Result<M, NetworkError> {
+            let aad = associated_data(context.side.remote(), &context.channel_id);
+            let payload = self.decrypt(bytes, context.key, Some(context.side), &aad)?;
+            self.inner.decode_for(&payload, context)
+        }
+    }
+
+    /// Side that sent a packet and its channel, authenticated with the payload
+    fn associated_data(sender_side: NodeSide, channel_id: &ChannelId) -> Vec<u8> {
+        [&[sender_side as u8], channel_id.0.as_bytes()].concat()
+    }
+
+    /// Nonce source of the packets sent with one transformer
+    struct Sender {
+        id: SenderId,
+        counter: AtomicU64,
+    }
+
+    impl Default for Sender {
+        fn default() -> Self {
+            let mut id = SenderId::default();
+            OsRng.fill_bytes(&mut id);
+
+            Self {
+                id,
+                counter: AtomicU64::new(0),
+            }
+        }
+    }
+
+    impl Sender {
+        fn next_counter(&self) -> u64 {
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    /// Replay windows of the senders heard from
+    #[derive(Default)]
+    struct Replay {
+        windows: HashMap<SenderId, ReplayWindow>,
+        /// Counts the marked packets, orders the senders by their last packet
+        clock: u64,
+    }
+
+    impl Replay {
+        fn is_fresh(&self, sender: &SenderId, counter: u64) -> bool {
+            self.windows
+                .get(sender)
+                .is_none_or(|window| window.is_fresh(counter))
+        }
+
+        fn mark(&mut self, sender: SenderId, counter: u64) {
+            if self.windows.len() >= MAX_SENDERS && !self.windows.contains_key(&sender) {
+                let oldest = self
+                    .windows
+                    .iter()
+                    .min_by_key(|(_, window)| window.last_marked)
+                    .map(|(id, _)| *id);
+                if let Some(oldest) = oldest {
+                    self.windows.remove(&oldest);
+                }
+            }
+
+            self.clock += 1;
+            let window = self.windows.entry(sender).or_default();
+            window.mark(counter);
+            window.last_marked = self.clock;
+        }
+    }
+
+    /// Counters received from one sender, bit `n` of `seen` is `highest - n`
+    #[derive(Default)]
+    struct ReplayWindow {
+        highest: u64,
+        seen: u64,
+        last_marked: u64,
+    }
+
+    impl ReplayWindow {
+        fn is_fresh(&self, counter: u64) -> bool {
+            if counter > self.highest {
+                return true;
+            }
+            let offset = self.highest - counter;
+
+            offset < REPLAY_WINDOW && self.seen & (1 << offset) == 0
+        }
+
+        fn mark(&mut self, counter: u64) {
+            if counter > self.highest {
+                let shift = counter - self.highest;
+                self.seen = if shift < REPLAY_WINDOW {
+                    self.seen << shift
+                } else {
+                    0
+                };
+                self.seen |= 1;
+                self.highest = counter;
+            } else {
+                self.seen |= 1 << (self.highest - counter);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::transformer::testing::Raw;
+
+        const CHANNEL: ChannelId = ChannelId("encrypted");
+
+        fn codec(key: &EncryptionKey) -> Encrypted<Raw> {
+            Encrypted::default().with_key(key.clone())
+        }
+
+        fn message() -> Vec<u8> {
+            b"position 1 2 3".to_vec()
+        }
+
+        fn context(side: NodeSide, key: Option<&EncryptionKey>) -> CodecContext<'_> {
+            CodecContext {
+                channel_id: CHANNEL,
+                side,
+                key,
+            }
+        }
+
+        fn decode(codec: &Encrypted<Raw>, bytes: &[u8]) -> Result<Vec<u8>, NetworkError> {
+            codec.decode_message(bytes)
+        }
+
+        fn decode_for(
+            codec: &Encrypted<Raw>,
+            bytes: &[u8],
+            context: CodecContext,
+        ) -> Result<Vec<u8>, NetworkError> {
+            codec.decode_for(bytes, &context)
+        }
+
+        #[test]
+        fn round_trip() {
+            let key = EncryptionKey::generate();
+            let (sender, receiver) = (codec(&key), codec(&key));
+
+            let bytes = sender.encode_message(&message()).unwrap();
+            assert_ne!(
+                &bytes[HEADER_LEN..HEADER_LEN + message().len()],
+                &message()[..]
+            );
+            assert_eq!(decode(&receiver, &bytes).unwrap(), message());
+        }
+
+        #[test]
+        fn tampered_packets_are_rejected() {
+            let key = EncryptionKey::generate();
+            let (sender, receiver) = (codec(&key), codec(&key));
+            let bytes = sender.encode_message(&message()).unwrap();
+
+            for i in [0, HEADER_LEN - 1, HEADER_LEN, bytes.len() - 1] {
+                let mut tampered = bytes.clone();
+                tampered[i] ^= 1;
+                assert!(matches!(
+                    decode(&receiver, &tampered),
+                    Err(NetworkError::DeserializeError(_))
+                ));
+            }
+            assert!(decode(&receiver, &bytes[..HEADER_LEN - 1]).is_err());
+            assert!(decode(&receiver, &bytes).is_ok());
+        }
+
+        #[test]
+        fn wrong_key_is_rejected() {
+            let sender = codec(&EncryptionKey::generate());
+            let receiver = codec(&EncryptionKey::generate());
+            let bytes = sender.encode_message(&message()).unwrap();
+
+            assert!(decode(&receiver, &bytes).is_err());
+        }
+
+        #[test]
+        fn node_key_overrides_resource_key() {
+            let node_key = EncryptionKey::generate();
+            let sender = codec(&EncryptionKey::generate());
+            let receiver = Encrypted::<Raw>::default();
+            let bytes = sender
+                .encode_for(&message(), &context(NodeSide::Client, Some(&node_key)))
+                .unwrap();
+
+            assert!(decode_for(&receiver, &bytes, context(NodeSide::Server, None)).is_err());
+            assert_eq!(
+                decode_for(
+                    &receiver,
+                    &bytes,
+                    context(NodeSide::Server, Some(&node_key))
+                )
+                .unwrap(),
+                message()
+            );
+        }
+
+        #[test]
+        fn reflected_packets_are_rejected() {
+            let codec = codec(&EncryptionKey::generate());
+            let bytes = codec.encode_message(&message()).unwrap();
+            let sent = codec
+                .encode_for(&message(), &context(NodeSide::Server, None))
+                .unwrap();
+
+            for reflected in [
+                decode(&codec, &bytes),
+                decode_for(&codec, &sent, context(NodeSide::Server, None)),
+            ] {
+                assert!(matches!(
+                    reflected,
+                    Err(NetworkError::DeserializeError(e)) if e.contains("reflected")
+                ));
+            }
+        }
+
+        #[test]
+        fn packets_are_bound_to_direction_and_channel() {
+            let key = EncryptionKey::generate();
+            // server and client of one app share the resource
+            let (app, other) = (codec(&key), codec(&key));
+            let bytes = app
+                .encode_for(&message(), &context(NodeSide::Client, None))
+                .unwrap();
+            let other_channel = CodecContext {
+                channel_id: ChannelId("other"),
+                ..context(NodeSide::Server, None)
+            };
+
+            // sent on to another client
+            assert!(decode_for(&other, &bytes, context(NodeSide::Client, None)).is_err());
+            assert!(decode_for(&other, &bytes, other_channel).is_err());
+            assert!(decode(&other, &bytes).is_err());
+            assert_eq!(
+                decode_for(&app, &bytes, context(NodeSide::Server, None)).unwrap(),
+                message()
+            );
+        }
+
+        #[test]
+        fn replays_are_rejected() {
+            let key = EncryptionKey::generate();
+            let (sender, receiver) = (codec(&key), codec(&key));
+            let packets: Vec<_> = (0..100)
+                .map(|_| sender.encode_message(&message()).unwrap())
+                .collect();
+
+            // out of order within the window is fine
+            assert!(decode(&receiver, &packets[80]).is_ok());
+            assert!(decode(&receiver, &packets[79]).is_ok());
+            assert!(decode(&receiver, &packets[80]).is_err());
+            assert!(decode(&receiver, &packets[79]).is_err());
+            // too old to tell
+            assert!(decode(&receiver, &packets[10]).is_err());
+            assert!(decode(&receiver, &packets[99]).is_ok());
+            assert!(decode(&receiver, &packets[99]).is_err());
+        }
+
+        #[test]
+        fn replay_windows_are_bounded() {
+            let key = EncryptionKey::generate();
+            let receiver = codec(&key);
+            let senders: Vec<_> = (0..=MAX_SENDERS).map(|_| codec(&key)).collect();
+            let send = |sender: &Encrypted<Raw>| sender.encode_message(&message()).unwrap();
+            let tracked = |sender: &Encrypted<Raw>| {
+                let replay = receiver.replay.lock().unwrap();
+                replay.windows.contains_key(&sender.senders[0].id)
+            };
+
+            let first = send(&senders[0]);
+            decode(&receiver, &first).unwrap();
+            for sender in &senders[1..MAX_SENDERS] {
+                decode(&receiver, &send(sender)).unwrap();
+            }
+            // the first sender is heard again, the second is the oldest now
+            decode(&receiver, &send(&senders[0])).unwrap();
+            decode(&receiver, &send(&senders[MAX_SENDERS])).unwrap();
+
+            assert_eq!(receiver.replay.lock().unwrap().windows.len(), MAX_SENDERS);
+            assert!(!tracked(&senders[1]));
+            assert!(tracked(&senders[0]));
+            assert!(tracked(&senders[MAX_SENDERS]));
+            assert!(decode(&receiver, &first).is_err());
+        }
+    }
+}
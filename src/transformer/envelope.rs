@@ -0,0 +1,289 @@
+use std::{any::TypeId, collections::HashMap};
+
+use bevy::prelude::*;
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{
+    channels::ChannelId,
+    error::NetworkError,
+    network_node::{NetworkEvent, NetworkNode, NetworkRawPacket, NodeEvent},
+    queue::QueueSettings,
+    transformer::MessageTypeId,
+};
+
+/// Length of the message tag in front of every payload on a multiplexed channel
+const TAG_LEN: usize = 4;
+
+/// Stable wire id for `M` derived from its type name (FNV-1a).
+///
+/// The id changes when the type is renamed or moved, pass an explicit tag to
+/// `add_tagged_transformer` if the protocol is shared with other programs.
+pub fn message_tag<M: 'static>() -> u32 {
+    std::any::type_name::<M>()
+        .bytes()
+        .fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+/// Tag of every tagged message type per channel
+#[derive(Resource, Deref, DerefMut, Debug, Default)]
+pub(crate) struct MessageTags(pub(crate) HashMap<(MessageTypeId, ChannelId), u32>);
+
+/// Tags with a registered decoder per multiplexed channel
+#[derive(Resource, Deref, DerefMut, Debug, Default)]
+pub(crate) struct TaggedChannels(pub(crate) HashMap<ChannelId, HashMap<u32, &'static str>>);
+
+impl TaggedChannels {
+    /// Fails when `tag` is used by another type on the channel
+    pub(crate) fn check<M: 'static>(&self, channel_id: ChannelId, tag: u32) -> Result<(), String> {
+        let type_name = std::any::type_name::<M>();
+        match self.get(&channel_id).and_then(|tags| tags.get(&tag)) {
+            Some(existing) if *existing != type_name => Err(format!(
+                "{channel_id} tag {tag:#010x} is already used by {existing}"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn register<M: 'static>(&mut self, channel_id: ChannelId, tag: u32) {
+        self.entry(channel_id)
+            .or_default()
+            .insert(tag, std::any::type_name::<M>());
+    }
+}
+
+pub(crate) fn tag_of<M: 'static>(tags: &MessageTags, channel_id: &ChannelId) -> Option<u32> {
+    tags.get(&(TypeId::of::<M>(), *channel_id)).copied()
+}
+
+pub(crate) fn wrap_tagged(tag: u32, payload: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(TAG_LEN + payload.len());
+    bytes.put_u32(tag);
+    bytes.extend_from_slice(payload);
+    bytes.freeze()
+}
+
+/// Packets of a multiplexed channel sorted by message tag
+#[derive(Component, Deref, DerefMut, Debug, Default)]
+pub struct MessageInbox(HashMap<u32, Vec<NetworkRawPacket>>);
+
+impl MessageInbox {
+    pub(crate) fn take(&mut self, tag: u32) -> Vec<NetworkRawPacket> {
+        self.get_mut(&tag).map(std::mem::take).unwrap_or_default()
+    }
+}
+
+/// split packets of multiplexed channels into the inbox of their message type
+pub(crate) fn demultiplex_system(
+    mut commands: Commands,
+    tagged: Res<TaggedChannels>,
+    mut query: Query<(
+        Entity,
+        &ChannelId,
+        &NetworkNode,
+        &mut MessageInbox,
+        Option<&QueueSettings>,
+    )>,
+) {
+    for (entity, channel_id, net_node, mut inbox, opt_queue) in query.iter_mut() {
+        let Some(tags) = tagged.get(channel_id) else {
+            continue;
+        };
+        // a bounded node keeps the packets it has no room for in its channel
+        let capacity = opt_queue.map_or(usize::MAX, |queue| queue.recv_capacity);
+        let mut queued: usize = inbox.values().map(Vec::len).sum();
+
+        while queued < capacity
+            && let Some(packet) = net_node.try_recv()
+        {
+            let error = if packet.bytes.len() < TAG_LEN {
+                NetworkError::DeserializeError(format!(
+                    "{} packet of {} bytes has no message tag",
+                    channel_id,
+                    packet.bytes.len()
+                ))
+            } else {
+                let tag = u32::from_be_bytes(packet.bytes[..TAG_LEN].try_into().unwrap());
+                if tags.contains_key(&tag) {
+                    inbox.entry(tag).or_default().push(NetworkRawPacket {
+                        bytes: packet.bytes.slice(TAG_LEN..),
+                        ..packet
+                    });
+                    queued += 1;
+                    continue;
+                }
+                NetworkError::DeserializeError(format!(
+                    "{} unknown message tag {:#010x}",
+                    channel_id, tag
+                ))
+            };
+
+            commands.trigger(NodeEvent {
+                entity,
+                event: NetworkEvent::Error(error),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::{
+        queue::OverflowPolicy,
+        transformer::{DecoderChannels, EncoderChannels, NetworkMessageTransformer, testing::Raw},
+    };
+
+    const CHANNEL: ChannelId = ChannelId("multiplexed");
+
+    #[derive(Resource, Default)]
+    struct Errors(Vec<String>);
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.init_resource::<EncoderChannels>()
+            .init_resource::<DecoderChannels>()
+            .init_resource::<MessageTags>()
+            .init_resource::<TaggedChannels>()
+            .init_resource::<Errors>()
+            .add_observer(|on: On<NodeEvent>, mut errors: ResMut<Errors>| {
+                if let NetworkEvent::Error(e) = &on.event().event {
+                    errors.0.push(e.to_string());
+                }
+            });
+        app
+    }
+
+    /// Run the demultiplexer over `packets` received by a node of the channel
+    fn demultiplex(app: &mut App, packets: &[&[u8]]) -> MessageInbox {
+        let net_node = NetworkNode::default();
+        for packet in packets {
+            net_node
+                .recv_message_channel
+                .sender
+                .send(NetworkRawPacket {
+                    addr: None,
+                    bytes: Bytes::copy_from_slice(packet),
+                    text: None,
+                })
+                .unwrap();
+        }
+        let entity = app
+            .world_mut()
+            .spawn((CHANNEL, net_node, MessageInbox::default()))
+            .id();
+
+        app.world_mut().run_system_once(demultiplex_system).unwrap();
+        app.world_mut()
+            .entity_mut(entity)
+            .take::<MessageInbox>()
+            .unwrap()
+    }
+
+    #[test]
+    fn dispatches_by_tag() {
+        let mut app = app();
+        app.add_tagged_decoder::<Vec<u8>, Raw>(CHANNEL, 1)
+            .add_tagged_decoder::<Bytes, Raw>(CHANNEL, 2);
+
+        let mut inbox = demultiplex(
+            &mut app,
+            &[
+                &wrap_tagged(1, b"one"),
+                &wrap_tagged(2, b"two"),
+                &wrap_tagged(1, b""),
+            ],
+        );
+
+        let bytes = |packets: Vec<NetworkRawPacket>| -> Vec<Bytes> {
+            packets.into_iter().map(|packet| packet.bytes).collect()
+        };
+        assert_eq!(bytes(inbox.take(1)), [&b"one"[..], &b""[..]]);
+        assert_eq!(bytes(inbox.take(2)), [&b"two"[..]]);
+        assert!(inbox.take(1).is_empty());
+        assert!(app.world().resource::<Errors>().0.is_empty());
+    }
+
+    #[test]
+    fn inbox_is_bounded_by_the_receive_queue() {
+        let mut app = app();
+        app.add_tagged_decoder::<Vec<u8>, Raw>(CHANNEL, 1);
+        let mut net_node = NetworkNode::default();
+        let queue = QueueSettings::new(2, OverflowPolicy::Block);
+        queue.bound(&mut net_node);
+        let left = net_node.clone();
+        let entity = app
+            .world_mut()
+            .spawn((CHANNEL, net_node, queue, MessageInbox::default()))
+            .id();
+        for id in 0..2 {
+            left.recv_message_channel
+                .sender
+                .send(NetworkRawPacket {
+                    addr: None,
+                    bytes: wrap_tagged(1, &[id]),
+                    text: None,
+                })
+                .unwrap();
+        }
+
+        app.world_mut().run_system_once(demultiplex_system).unwrap();
+        left.recv_message_channel
+            .sender
+            .send(NetworkRawPacket {
+                addr: None,
+                bytes: wrap_tagged(1, &[2]),
+                text: None,
+            })
+            .unwrap();
+        app.world_mut().run_system_once(demultiplex_system).unwrap();
+
+        let mut inbox = app.world_mut().get_mut::<MessageInbox>(entity).unwrap();
+        assert_eq!(inbox.take(1).len(), 2);
+        assert_eq!(left.recv_message_channel.receiver.len(), 1);
+    }
+
+    #[test]
+    fn unknown_tags_and_short_packets_are_errors() {
+        let mut app = app();
+        app.add_tagged_decoder::<Vec<u8>, Raw>(CHANNEL, 1);
+
+        let mut inbox = demultiplex(&mut app, &[&wrap_tagged(3, b"three"), &[0, 0, 1]]);
+
+        assert!(inbox.take(1).is_empty());
+        let errors = &app.world().resource::<Errors>().0;
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("unknown message tag 0x00000003"));
+        assert!(errors[1].contains("has no message tag"));
+    }
+
+    #[test]
+    fn conflicting_registrations_are_ignored() {
+        let mut app = app();
+        app.add_tagged_transformer::<Vec<u8>, Raw>(CHANNEL, 1)
+            // tag taken by another type
+            .add_tagged_decoder::<Bytes, Raw>(CHANNEL, 1)
+            // second tag for the same type
+            .add_tagged_encoder::<Vec<u8>, Raw>(CHANNEL, 2)
+            // untagged decoder on a multiplexed channel
+            .add_decoder::<Box<[u8]>, Raw>(CHANNEL);
+
+        let world = app.world();
+        assert_eq!(tag_of::<Vec<u8>>(world.resource(), &CHANNEL), Some(1));
+        assert_eq!(tag_of::<Bytes>(world.resource(), &CHANNEL), None);
+        assert_eq!(world.resource::<TaggedChannels>()[&CHANNEL].len(), 1);
+        assert_eq!(world.resource::<DecoderChannels>().len(), 1);
+    }
+
+    #[test]
+    fn untagged_channels_can_not_be_multiplexed() {
+        let mut app = app();
+        app.add_decoder::<Vec<u8>, Raw>(CHANNEL)
+            .add_tagged_decoder::<Bytes, Raw>(CHANNEL, 1);
+
+        assert!(app.world().resource::<TaggedChannels>().is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+use bevy::prelude::{Reflect, Resource};
+use serde::{Deserialize, Serialize, de::DeserializeSeed};
+
+use crate::{
+    error::NetworkError,
+    transformer::{SeededTransformer, Transformer},
+};
+
+/// MessagePack with field names, so structs are maps other languages can read
+#[derive(Resource, Default, Reflect)]
+pub struct MsgPackTransformer;
+
+impl Transformer for MsgPackTransformer {
+    const NAME: &'static str = "MsgPack";
+
+    fn encode<T: Serialize>(&self, data: &T) -> Result<Vec<u8>, NetworkError> {
+        rmp_serde::to_vec_named(data).map_err(|e| NetworkError::SerializeError(e.to_string()))
+    }
+
+    fn decode<T: for<'a> Deserialize<'a>>(&self, bytes: &[u8]) -> Result<T, NetworkError> {
+        rmp_serde::from_slice(bytes).map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+impl SeededTransformer for MsgPackTransformer {
+    fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        &self,
+        seed: S,
+        bytes: &'de [u8],
+    ) -> Result<S::Value, NetworkError> {
+        seed.deserialize(&mut rmp_serde::Deserializer::from_read_ref(bytes))
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MsgPackTransformer;
+    use crate::transformer::testing::{assert_rejected, round_trip};
+
+    #[test]
+    fn round_trip_player() {
+        round_trip(&MsgPackTransformer);
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        assert_rejected(&MsgPackTransformer, &[0xc1]);
+    }
+}
@@ -0,0 +1,50 @@
+use bevy::prelude::{Reflect, Resource};
+use serde::{Deserialize, Serialize, de::DeserializeSeed};
+
+use crate::{
+    error::NetworkError,
+    transformer::{SeededTransformer, Transformer},
+};
+
+/// Compact `no_std` friendly format for embedded peers
+#[derive(Resource, Default, Reflect)]
+pub struct PostcardTransformer;
+
+impl Transformer for PostcardTransformer {
+    const NAME: &'static str = "Postcard";
+
+    fn encode<T: Serialize>(&self, data: &T) -> Result<Vec<u8>, NetworkError> {
+        postcard::to_allocvec(data).map_err(|e| NetworkError::SerializeError(e.to_string()))
+    }
+
+    fn decode<T: for<'a> Deserialize<'a>>(&self, bytes: &[u8]) -> Result<T, NetworkError> {
+        postcard::from_bytes(bytes).map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+impl SeededTransformer for PostcardTransformer {
+    fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        &self,
+        seed: S,
+        bytes: &'de [u8],
+    ) -> Result<S::Value, NetworkError> {
+        seed.deserialize(&mut postcard::Deserializer::from_bytes(bytes))
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PostcardTransformer;
+    use crate::transformer::testing::{assert_rejected, round_trip};
+
+    #[test]
+    fn round_trip_player() {
+        round_trip(&PostcardTransformer);
+    }
+
+    #[test]
+    fn truncated_bytes() {
+        assert_rejected(&PostcardTransformer, &[100]);
+    }
+}
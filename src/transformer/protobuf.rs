@@ -0,0 +1,64 @@
+use bevy::prelude::{Reflect, Resource};
+use prost::Message;
+
+use crate::{error::NetworkError, transformer::MessageCodec};
+
+/// Protobuf for prost generated messages, the wire format of other protobuf
+/// implementations
+#[derive(Resource, Default, Reflect)]
+pub struct ProtobufTransformer;
+
+impl<M: Message + Default> MessageCodec<M> for ProtobufTransformer {
+    const NAME: &'static str = "Protobuf";
+
+    fn encode_message(&self, message: &M) -> Result<Vec<u8>, NetworkError> {
+        Ok(message.encode_to_vec())
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> Result<M, NetworkError> {
+        M::decode(bytes).map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtobufTransformer;
+    use crate::{error::NetworkError, transformer::MessageCodec};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Position {
+        #[prost(uint32, tag = "1")]
+        x: u32,
+        #[prost(uint32, tag = "2")]
+        y: u32,
+        #[prost(uint32, tag = "3")]
+        z: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct PlayerInformation {
+        #[prost(uint64, tag = "1")]
+        health: u64,
+        #[prost(message, optional, tag = "2")]
+        position: Option<Position>,
+    }
+
+    #[test]
+    fn round_trip() {
+        let player = PlayerInformation {
+            health: 100,
+            position: Some(Position { x: 1, y: 2, z: 3 }),
+        };
+        let bytes = ProtobufTransformer.encode_message(&player).unwrap();
+        let decoded: PlayerInformation = ProtobufTransformer.decode_message(&bytes).unwrap();
+
+        assert_eq!(decoded, player);
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let result: Result<PlayerInformation, _> = ProtobufTransformer.decode_message(&[0x0a]);
+
+        assert!(matches!(result, Err(NetworkError::DeserializeError(_))));
+    }
+}
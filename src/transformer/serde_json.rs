@@ -1,7 +1,10 @@
 use bevy::prelude::{Reflect, Resource};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeSeed};
 
-use crate::{error::NetworkError, transformer::Transformer};
+use crate::{
+    error::NetworkError,
+    transformer::{SeededTransformer, Transformer},
+};
 
 #[derive(Resource, Default, Reflect)]
 pub struct JsonTransformer;
@@ -23,3 +26,16 @@ impl Transformer for JsonTransformer {
         }
     }
 }
+
+impl SeededTransformer for JsonTransformer {
+    fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        &self,
+        seed: S,
+        bytes: &'de [u8],
+    ) -> Result<S::Value, NetworkError> {
+        let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+        seed.deserialize(&mut deserializer)
+            .and_then(|value| deserializer.end().map(|_| value))
+            .map_err(|e| NetworkError::DeserializeError(e.to_string()))
+    }
+}
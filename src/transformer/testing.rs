@@ -0,0 +1,56 @@
+//! Fixtures shared by the transformer tests
+
+use bevy::prelude::{Reflect, Resource};
+#[cfg(any(feature = "cbor", feature = "msgpack", feature = "postcard"))]
+use serde::{Deserialize, Serialize};
+
+#[cfg(any(feature = "cbor", feature = "msgpack", feature = "postcard"))]
+use crate::transformer::Transformer;
+use crate::{error::NetworkError, transformer::MessageCodec};
+
+/// Sends the bytes of the message unchanged
+#[derive(Resource, Reflect, Default)]
+pub(crate) struct Raw;
+
+impl<M: AsRef<[u8]> + From<Vec<u8>>> MessageCodec<M> for Raw {
+    const NAME: &'static str = "Raw";
+
+    fn encode_message(&self, message: &M) -> Result<Vec<u8>, NetworkError> {
+        Ok(message.as_ref().to_vec())
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> Result<M, NetworkError> {
+        Ok(bytes.to_vec().into())
+    }
+}
+
+#[cfg(any(feature = "cbor", feature = "msgpack", feature = "postcard"))]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct PlayerInformation {
+    health: usize,
+    position: (u32, u32, u32),
+}
+
+/// Encode and decode a message with `transformer`, returning the encoding
+#[cfg(any(feature = "cbor", feature = "msgpack", feature = "postcard"))]
+pub(crate) fn round_trip<T: Transformer>(transformer: &T) -> Vec<u8> {
+    let player = PlayerInformation {
+        health: 100,
+        position: (1, 2, 3),
+    };
+    let bytes = transformer.encode(&player).unwrap();
+
+    assert_eq!(
+        transformer.decode::<PlayerInformation>(&bytes).unwrap(),
+        player
+    );
+    bytes
+}
+
+/// Assert `bytes` fail to decode as the message of [`round_trip`]
+#[cfg(any(feature = "cbor", feature = "msgpack", feature = "postcard"))]
+pub(crate) fn assert_rejected<T: Transformer>(transformer: &T, bytes: &[u8]) {
+    let result = transformer.decode::<PlayerInformation>(bytes);
+
+    assert!(matches!(result, Err(NetworkError::DeserializeError(_))));
+}
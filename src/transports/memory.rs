@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use bevy::prelude::*;
+use kanal::{Receiver, Sender, unbounded};
+
+use crate::{
+    channels::ChannelId,
+    client::{ClientNode, StartClient},
+    conditioner::NetworkConditioner,
+    connection_state::ConnectionState,
+    error::NetworkError,
+    network_node::{
+        DisconnectReason, NetworkAddress, NetworkEvent, NetworkNode, NetworkPeer, NetworkRawPacket,
+        RemoteAddr, TransportLayers,
+    },
+    plugin::NetworkSet,
+    queue::QueueSettings,
+    server::{ServerNode, StartServer},
+    shutdown::graceful_shutdown,
+    transports::virtual_addr,
+};
+
+/// Servers listening in this process, shared by every `App`
+static LISTENERS: LazyLock<Mutex<HashMap<String, Listener>>> = LazyLock::new(Default::default);
+
+pub struct MemoryPlugin;
+
+impl Plugin for MemoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PreUpdate, accept_connections.before(NetworkSet::Receive))
+            .add_systems(PostUpdate, forward_sends.after(NetworkSet::Send))
+            .add_systems(Last, close_on_exit.before(graceful_shutdown))
+            .add_observer(on_start_server)
+            .add_observer(on_start_client);
+    }
+}
+
+/// Named in-process endpoint, packets are moved between nodes by systems
+/// without sockets or the async runtime
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MemoryAddress {
+    pub name: String,
+}
+
+impl MemoryAddress {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl NetworkAddress for MemoryAddress {
+    fn to_string(&self) -> String {
+        format!("memory://{}", self.name)
+    }
+
+    fn from_string(s: &str) -> Result<Self, String> {
+        s.strip_prefix("memory://")
+            .map(Self::new)
+            .ok_or_else(|| format!("{} is not a memory address", s))
+    }
+}
+
+struct Listener {
+    addr: SocketAddr,
+    accept_tx: Sender<MemoryConnection>,
+    /// Inherited by the peers
+    layers: TransportLayers,
+}
+
+/// Registration of a listening server, removed again when dropped
+#[derive(Component)]
+struct MemoryListener {
+    name: String,
+    addr: SocketAddr,
+    accept_rx: Receiver<MemoryConnection>,
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        let mut listeners = LISTENERS.lock().unwrap();
+        if listeners
+            .get(&self.name)
+            .is_some_and(|listener| listener.addr == self.addr)
+        {
+            listeners.remove(&self.name);
+        }
+    }
+}
+
+/// A client connecting to a server, carrying the node of its peer entity
+struct MemoryConnection {
+    node: NetworkNode,
+    addr: SocketAddr,
+    link: MemoryLink,
+    layers: TransportLayers,
+}
+
+/// Channels of a node as its transport sees them
+#[derive(Clone)]
+struct Wire {
+    recv_tx: Sender<NetworkRawPacket>,
+    message_rx: Receiver<NetworkRawPacket>,
+    shutdown_rx: Receiver<DisconnectReason>,
+    event_tx: Sender<NetworkEvent>,
+}
+
+impl Wire {
+    fn new(node: &NetworkNode, layers: &TransportLayers) -> Self {
+        let (recv_tx, message_rx) = node.transport_channels(layers);
+
+        Self {
+            recv_tx: recv_tx.to_sync(),
+            message_rx: message_rx.to_sync(),
+            shutdown_rx: node.shutdown_channel.receiver.clone(),
+            event_tx: node.event_channel.sender.clone(),
+        }
+    }
+}
+
+/// One end of an in-process connection.
+///
+/// Dropping it with the entity closes the connection with the reason queued
+/// on the node, so despawned nodes still flush and notify the remote.
+#[derive(Component)]
+struct MemoryLink {
+    /// Address the remote sees this end as
+    addr: SocketAddr,
+    /// Shared by both ends, false once either closed
+    open: Arc<AtomicBool>,
+    message_rx: Receiver<NetworkRawPacket>,
+    /// Packet the full receive queue of the remote did not take yet
+    pending: Mutex<Option<NetworkRawPacket>>,
+    shutdown_rx: Receiver<DisconnectReason>,
+    event_tx: Sender<NetworkEvent>,
+    remote_recv_tx: Sender<NetworkRawPacket>,
+    remote_event_tx: Sender<NetworkEvent>,
+}
+
+impl MemoryLink {
+    fn pair(
+        (wire, addr): (Wire, SocketAddr),
+        (remote, remote_addr): (Wire, SocketAddr),
+    ) -> (Self, Self) {
+        let open = Arc::new(AtomicBool::new(true));
+        let end = |wire: Wire, addr, remote: &Wire| Self {
+            addr,
+            open: open.clone(),
+            message_rx: wire.message_rx,
+            pending: Mutex::new(None),
+            shutdown_rx: wire.shutdown_rx,
+            event_tx: wire.event_tx,
+            remote_recv_tx: remote.recv_tx.clone(),
+            remote_event_tx: remote.event_tx.clone(),
+        };
+
+        (
+            end(wire.clone(), addr, &remote),
+            end(remote.clone(), remote_addr, &wire),
+        )
+    }
+
+    fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Hand queued packets to the remote until its receive queue is full
+    fn flush(&self) -> Result<(), DisconnectReason> {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if pending.is_none() {
+                let Ok(Some(mut packet)) = self.message_rx.try_recv() else {
+                    return Ok(());
+                };
+                packet.addr = Some(self.addr);
+                *pending = Some(packet);
+            }
+            match self.remote_recv_tx.try_send_option(&mut pending) {
+                Ok(true) => {}
+                // the rest waits for the next frame
+                Ok(false) => return Ok(()),
+                Err(_) => return Err(DisconnectReason::ConnectionLost),
+            }
+        }
+    }
+
+    /// Flush and report both ends disconnected, unless the remote closed first
+    fn close(&self, reason: DisconnectReason) {
+        if !self.open.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let _ = self.flush();
+        let remote_reason = match &reason {
+            DisconnectReason::Closed | DisconnectReason::Timeout(_) => {
+                DisconnectReason::RemoteClosed
+            }
+            DisconnectReason::QueueOverflow => DisconnectReason::Kicked("queue overflow".into()),
+            reason => reason.clone(),
+        };
+        let _ = self
+            .remote_event_tx
+            .send(NetworkEvent::Disconnected(remote_reason));
+        let _ = self.event_tx.send(NetworkEvent::Disconnected(reason));
+    }
+}
+
+impl Drop for MemoryLink {
+    fn drop(&mut self) {
+        let reason = self.shutdown_rx.try_recv().ok().flatten();
+        self.close(reason.unwrap_or(DisconnectReason::Closed));
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn on_start_server(
+    on: On<StartServer>,
+    mut commands: Commands,
+    q_memory_server: Query<(
+        &NetworkNode,
+        &ServerNode<MemoryAddress>,
+        Option<&NetworkConditioner>,
+        Option<&QueueSettings>,
+    )>,
+) {
+    let ev = on.event();
+    let Ok((net_node, server, opt_conditioner, opt_queue)) = q_memory_server.get(ev.entity) else {
+        return;
+    };
+    let event_tx = &net_node.event_channel.sender;
+
+    let mut listeners = LISTENERS.lock().unwrap();
+    if listeners.contains_key(&server.name) {
+        let e = io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is already listening", server.to_string()),
+        );
+        let _ = event_tx.try_send(NetworkEvent::Error(NetworkError::Listen(e)));
+        return;
+    }
+
+    let addr = virtual_addr();
+    let (accept_tx, accept_rx) = unbounded();
+    listeners.insert(
+        server.name.clone(),
+        Listener {
+            addr,
+            accept_tx,
+            layers: TransportLayers::new(opt_queue, None, opt_conditioner),
+        },
+    );
+    commands.entity(ev.entity).insert(MemoryListener {
+        name: server.name.clone(),
+        addr,
+        accept_rx,
+    });
+    info!("Memory Server listening on {}", server.to_string());
+    let _ = event_tx.try_send(NetworkEvent::Listen(addr));
+}
+
+#[allow(clippy::type_complexity)]
+fn on_start_client(
+    on: On<StartClient>,
+    mut commands: Commands,
+    q_memory_client: Query<
+        (
+            &NetworkNode,
+            &ClientNode<MemoryAddress>,
+            Option<&NetworkConditioner>,
+            Option<&QueueSettings>,
+        ),
+        Without<NetworkPeer>,
+    >,
+) {
+    let ev = on.event();
+    let Ok((net_node, remote_addr, opt_conditioner, opt_queue)) = q_memory_client.get(ev.entity)
+    else {
+        return;
+    };
+    info!("try connect to {}", remote_addr.to_string());
+    let event_tx = &net_node.event_channel.sender;
+
+    let listeners = LISTENERS.lock().unwrap();
+    let Some(listener) = listeners.get(&remote_addr.name) else {
+        let _ = event_tx.try_send(NetworkEvent::Error(NetworkError::Connection(format!(
+            "nothing listening on {}",
+            remote_addr.to_string()
+        ))));
+        return;
+    };
+
+    let addr = virtual_addr();
+    let layers = TransportLayers::new(opt_queue, None, opt_conditioner);
+    let peer_layers = listener.layers.for_peer();
+    let peer_node = peer_layers.node();
+    let (link, peer_link) = MemoryLink::pair(
+        (Wire::new(net_node, &layers), addr),
+        (Wire::new(&peer_node, &peer_layers), listener.addr),
+    );
+    // the listener is unregistered before its receiver is dropped
+    let _ = listener.accept_tx.send(MemoryConnection {
+        node: peer_node,
+        addr,
+        link: peer_link,
+        layers: peer_layers,
+    });
+    commands.entity(ev.entity).insert(link);
+    let _ = event_tx.try_send(NetworkEvent::Connected);
+}
+
+/// Spawn a peer entity for every client connected to a server, or stop
+/// listening once the server is closed
+fn accept_connections(
+    mut commands: Commands,
+    q_memory_server: Query<(
+        Entity,
+        &NetworkNode,
+        &ServerNode<MemoryAddress>,
+        &MemoryListener,
+        &ChannelId,
+    )>,
+) {
+    for (entity, net_node, server, listener, channel_id) in q_memory_server.iter() {
+        if let Ok(Some(reason)) = net_node.shutdown_channel.receiver.try_recv() {
+            commands.entity(entity).remove::<MemoryListener>();
+            let _ = net_node
+                .event_channel
+                .sender
+                .try_send(NetworkEvent::Disconnected(reason));
+            continue;
+        }
+
+        while let Ok(Some(connection)) = listener.accept_rx.try_recv() {
+            let _ = connection
+                .node
+                .event_channel
+                .sender
+                .try_send(NetworkEvent::Connected);
+            let peer_entity = commands
+                .spawn((
+                    connection.node,
+                    *channel_id,
+                    ClientNode(server.0.clone()),
+                    RemoteAddr(connection.addr),
+                    NetworkPeer,
+                    connection.link,
+                ))
+                .id();
+            connection
+                .layers
+                .insert_on(&mut commands.entity(peer_entity));
+            info!("new client connected {:?}", peer_entity);
+
+            commands.entity(entity).add_child(peer_entity);
+        }
+    }
+}
+
+/// Move queued packets to the remote and close links whose node or remote
+/// was closed
+fn forward_sends(mut commands: Commands, q_link: Query<(Entity, &MemoryLink)>) {
+    for (entity, link) in q_link.iter() {
+        if !link.is_open() {
+            commands.entity(entity).remove::<MemoryLink>();
+            continue;
+        }
+
+        let closed = link
+            .flush()
+            .err()
+            .or_else(|| link.shutdown_rx.try_recv().ok().flatten());
+        if let Some(reason) = closed {
+            link.close(reason);
+            commands.entity(entity).remove::<MemoryLink>();
+        }
+    }
+}
+
+/// Close memory nodes on [`AppExit`] right away, they have no transport task
+/// [`graceful_shutdown`] could wait on
+#[allow(clippy::type_complexity)]
+fn close_on_exit(
+    mut exit: MessageReader<AppExit>,
+    mut commands: Commands,
+    mut q_link: Query<(Entity, &MemoryLink, &mut ConnectionState, Has<NetworkPeer>)>,
+    mut q_listener: Query<
+        (Entity, &mut ConnectionState),
+        (With<MemoryListener>, Without<MemoryLink>),
+    >,
+) {
+    if exit.read().last().is_none() {
+        return;
+    }
+
+    for (entity, link, mut state, is_peer) in q_link.iter_mut() {
+        let reason = if is_peer {
+            DisconnectReason::ServerShutdown
+        } else {
+            DisconnectReason::Closed
+        };
+        link.close(reason);
+        state.set_if_neq(ConnectionState::Disconnected);
+        commands.entity(entity).remove::<MemoryLink>();
+    }
+    for (entity, mut state) in q_listener.iter_mut() {
+        state.set_if_neq(ConnectionState::Disconnected);
+        commands.entity(entity).remove::<MemoryListener>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::time::TimePlugin;
+
+    use super::*;
+    use crate::{
+        network_node::{CloseNode, NetworkBundle, NodeEvent},
+        plugin::OctopusPlugin,
+    };
+
+    const CHANNEL: ChannelId = ChannelId("memory test");
+
+    #[derive(Resource, Default)]
+    struct Events(Vec<(Entity, String)>);
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((TimePlugin, OctopusPlugin))
+            .init_resource::<Events>()
+            .add_observer(|on: On<NodeEvent>, mut events: ResMut<Events>| {
+                let ev = on.event();
+                events.0.push((ev.entity, format!("{:?}", ev.event)));
+            });
+        app
+    }
+
+    fn events(app: &mut App) -> Vec<(Entity, String)> {
+        std::mem::take(&mut app.world_mut().resource_mut::<Events>().0)
+    }
+
+    fn node(app: &App, entity: Entity) -> &NetworkNode {
+        app.world().get::<NetworkNode>(entity).unwrap()
+    }
+
+    fn peers(app: &mut App) -> Vec<Entity> {
+        app.world_mut()
+            .query_filtered::<Entity, With<NetworkPeer>>()
+            .iter(app.world())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_between_apps() {
+        let (mut server_app, mut client_app) = (app(), app());
+        let address = MemoryAddress::new("round trip");
+        let server = server_app
+            .world_mut()
+            .spawn((NetworkBundle::new(CHANNEL), ServerNode(address.clone())))
+            .id();
+        let client = client_app
+            .world_mut()
+            .spawn((NetworkBundle::new(CHANNEL), ClientNode(address)))
+            .id();
+
+        server_app.update();
+        client_app.update();
+        server_app.update();
+        let [peer] = peers(&mut server_app)[..] else {
+            panic!("one peer expected");
+        };
+        let server_events = events(&mut server_app);
+        assert!(server_events.contains(&(peer, "Connected".to_string())));
+        assert!(
+            server_events
+                .iter()
+                .any(|(entity, event)| *entity == server && event.starts_with("Listen"))
+        );
+        assert_eq!(events(&mut client_app), [(client, "Connected".to_string())]);
+        assert_eq!(
+            server_app.world().get::<ConnectionState>(peer),
+            Some(&ConnectionState::Connected)
+        );
+
+        node(&client_app, client).send_bytes(b"hello");
+        client_app.update();
+        let received = node(&server_app, peer).try_recv().unwrap();
+        assert_eq!(&received.bytes[..], b"hello");
+        assert_eq!(
+            received.addr,
+            server_app
+                .world()
+                .get::<RemoteAddr>(peer)
+                .map(|addr| addr.0)
+        );
+
+        node(&server_app, peer).send_bytes(b"welcome");
+        server_app.update();
+        let received = node(&client_app, client).try_recv().unwrap();
+        assert_eq!(&received.bytes[..], b"welcome");
+
+        client_app.world_mut().trigger(CloseNode {
+            entity: client,
+            reason: DisconnectReason::Closed,
+        });
+        client_app.update();
+        client_app.update();
+        assert!(client_app.world().get_entity(client).is_err());
+        assert_eq!(
+            events(&mut client_app),
+            [(client, "Disconnected(Closed)".to_string())]
+        );
+
+        server_app.update();
+        assert!(peers(&mut server_app).is_empty());
+        assert_eq!(
+            events(&mut server_app),
+            [(peer, "Disconnected(RemoteClosed)".to_string())]
+        );
+    }
+}
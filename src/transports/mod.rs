@@ -1,2 +1,37 @@
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub mod memory;
 pub mod tcp;
 pub mod udp;
+#[cfg(unix)]
+pub mod unix;
+
+/// Unique address standing in for endpoints without a socket address in
+/// [`LocalAddr`](crate::network_node::LocalAddr),
+/// [`RemoteAddr`](crate::network_node::RemoteAddr) and packet sources.
+///
+/// The addresses come from the IPv6 discard prefix `100::/64` (RFC 6666), no
+/// real peer has one.
+pub(crate) fn virtual_addr() -> SocketAddr {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let ip = Ipv6Addr::from((0x0100_u128 << 112) | id as u128);
+    SocketAddr::from((ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_addrs_are_unique_and_not_loopback() {
+        let (a, b) = (virtual_addr(), virtual_addr());
+
+        assert_ne!(a, b);
+        assert!(!a.ip().is_loopback());
+        assert_eq!(a.to_string()[..6], *"[100::");
+    }
+}
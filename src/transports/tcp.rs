@@ -1,23 +1,31 @@
 use std::net::{SocketAddr, ToSocketAddrs};
 
 use async_std::{
+    future::timeout,
     io::WriteExt,
     net::{TcpListener, TcpStream},
     prelude::StreamExt,
     task,
 };
 use bevy::prelude::*;
-use bytes::Bytes;
-use futures::{AsyncReadExt, future};
+use bytes::{Bytes, BytesMut};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, future, pin_mut};
 use kanal::{AsyncReceiver, AsyncSender};
 
+#[cfg(feature = "tls")]
+use crate::tls::TlsSettings;
 use crate::{
     channels::ChannelId,
-    client::{ClientNode, StartClient},
+    client::{ClientNode, ReconnectSetting, StartClient},
+    conditioner::NetworkConditioner,
     error::NetworkError,
+    framing::FrameCodec,
+    heartbeat::{Heartbeat, NetworkRtt},
     network_node::{
-        AsyncChannel, NetworkAddress, NetworkEvent, NetworkNode, NetworkPeer, NetworkRawPacket,
+        AsyncChannel, DisconnectReason, NetworkAddress, NetworkEvent, NetworkNode, NetworkPeer,
+        NetworkRawPacket, Outgoing, RemoteAddr, SendQueue, TransportLayers, until_shutdown,
     },
+    queue::QueueSettings,
     server::{ServerNode, StartServer},
 };
 
@@ -69,8 +77,9 @@ async fn listen(
     new_connection_tx: AsyncSender<TcpStream>,
 ) -> Result<(), NetworkError> {
     let listener = TcpListener::bind(addr).await?;
-    info!("TCP Server listening on {}", addr);
-    let _ = event_tx.send(NetworkEvent::Listen).await;
+    let local_addr = listener.local_addr()?;
+    info!("TCP Server listening on {}", local_addr);
+    let _ = event_tx.send(NetworkEvent::Listen(local_addr)).await;
     let mut incoming = listener.incoming();
 
     while let Some(stream) = incoming.next().await {
@@ -82,46 +91,142 @@ async fn listen(
     Ok(())
 }
 
+/// Handshake applied to a TCP stream before messages flow
+#[derive(Clone)]
+enum StreamSecurity {
+    Plain,
+    #[cfg(feature = "tls")]
+    TlsServer(TlsSettings),
+    #[cfg(feature = "tls")]
+    TlsClient(TlsSettings),
+}
+
+/// Control messages need message boundaries, the wire format is up to the app
+fn check_heartbeat(layers: &mut TransportLayers, codec: Option<&FrameCodec>, addr: SocketAddr) {
+    if layers.heartbeat.is_some() && codec.is_none() {
+        error!(
+            "Heartbeat with {} needs a FrameCodec on the TCP node, heartbeat disabled",
+            addr
+        );
+        layers.heartbeat = None;
+    }
+}
+
 async fn handle_connection(
     stream: TcpStream,
+    security: StreamSecurity,
+    codec: Option<FrameCodec>,
     recv_tx: AsyncSender<NetworkRawPacket>,
     message_rx: AsyncReceiver<NetworkRawPacket>,
     event_tx: AsyncSender<NetworkEvent>,
-    shutdown_rx: AsyncReceiver<()>,
+    shutdown_rx: AsyncReceiver<DisconnectReason>,
 ) {
     let local_addr = stream.local_addr().unwrap();
     let addr = stream.peer_addr().unwrap();
     info!("TCP local {} connected to remote {}", local_addr, addr);
 
+    let channels = (recv_tx, message_rx, event_tx, shutdown_rx);
+    match security {
+        StreamSecurity::Plain => serve_stream(stream, local_addr, addr, codec, channels).await,
+        #[cfg(feature = "tls")]
+        StreamSecurity::TlsServer(tls) => match tls.accept(stream).await {
+            Ok(stream) => serve_stream(stream, local_addr, addr, codec, channels).await,
+            Err(e) => {
+                error!("TLS handshake with {} failed: {}", addr, e);
+                let reason = DisconnectReason::ProtocolError(e.to_string());
+                let _ = channels.2.send(NetworkEvent::Error(e)).await;
+                let _ = channels.2.send(NetworkEvent::Disconnected(reason)).await;
+            }
+        },
+        #[cfg(feature = "tls")]
+        StreamSecurity::TlsClient(tls) => match tls.connect(addr, stream).await {
+            Ok(stream) => serve_stream(stream, local_addr, addr, codec, channels).await,
+            Err(e) => {
+                error!("TLS handshake with {} failed: {}", addr, e);
+                let reason = DisconnectReason::ProtocolError(e.to_string());
+                let _ = channels.2.send(NetworkEvent::Error(e)).await;
+                let _ = channels.2.send(NetworkEvent::Disconnected(reason)).await;
+            }
+        },
+    }
+}
+
+pub(crate) async fn serve_stream<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    stream: S,
+    local_addr: SocketAddr,
+    addr: SocketAddr,
+    codec: Option<FrameCodec>,
+    (recv_tx, message_rx, event_tx, shutdown_rx): (
+        AsyncSender<NetworkRawPacket>,
+        AsyncReceiver<NetworkRawPacket>,
+        AsyncSender<NetworkEvent>,
+        AsyncReceiver<DisconnectReason>,
+    ),
+) {
     let (mut reader, mut writer) = stream.split();
     let _ = event_tx.send(NetworkEvent::Connected).await;
     let event_tx_clone = event_tx.clone();
+    let read_codec = codec.clone();
     let read_task = async move {
         let mut buffer = vec![0; 1024];
+        let mut frames = BytesMut::new();
 
         loop {
             match reader.read(&mut buffer).await {
                 Ok(0) => {
-                    let _ = event_tx_clone.send(NetworkEvent::Disconnected).await;
+                    let reason = DisconnectReason::RemoteClosed;
+                    let _ = event_tx_clone
+                        .send(NetworkEvent::Disconnected(reason))
+                        .await;
                     break;
                 }
                 Ok(n) => {
-                    let data = buffer[..n].to_vec();
                     trace!("{} read {} bytes from {}", local_addr, n, addr);
-                    let _ = recv_tx
-                        .send(NetworkRawPacket {
-                            addr: Some(addr),
-                            bytes: Bytes::from_iter(data),
-                            text: None,
-                        })
-                        .await;
+                    let Some(codec) = &read_codec else {
+                        let _ = recv_tx
+                            .send(NetworkRawPacket {
+                                addr: Some(addr),
+                                bytes: Bytes::copy_from_slice(&buffer[..n]),
+                                text: None,
+                            })
+                            .await;
+                        continue;
+                    };
+
+                    frames.extend_from_slice(&buffer[..n]);
+                    loop {
+                        match codec.decode(&mut frames) {
+                            Ok(Some(bytes)) => {
+                                let _ = recv_tx
+                                    .send(NetworkRawPacket {
+                                        addr: Some(addr),
+                                        bytes,
+                                        text: None,
+                                    })
+                                    .await;
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("{} invalid frame from {}: {}", local_addr, addr, e);
+                                let reason = DisconnectReason::ProtocolError(e.to_string());
+                                let _ = event_tx_clone.send(NetworkEvent::Error(e)).await;
+                                let _ = event_tx_clone
+                                    .send(NetworkEvent::Disconnected(reason))
+                                    .await;
+                                return;
+                            }
+                        }
+                    }
                 }
                 Err(e) => {
                     trace!("Failed to read data from socket: {}", e);
                     let _ = event_tx_clone
                         .send(NetworkEvent::Error(NetworkError::Common(e.to_string())))
                         .await;
-                    let _ = event_tx_clone.send(NetworkEvent::Disconnected).await;
+                    let reason = DisconnectReason::ConnectionLost;
+                    let _ = event_tx_clone
+                        .send(NetworkEvent::Disconnected(reason))
+                        .await;
                     break;
                 }
             }
@@ -129,28 +234,51 @@ async fn handle_connection(
     };
 
     let write_task = async move {
-        while let Ok(data) = message_rx.recv().await {
+        let mut frame = BytesMut::new();
+        let mut queue = SendQueue::new(message_rx, shutdown_rx);
+        while let Some(outgoing) = queue.next().await {
+            let data = match outgoing {
+                Outgoing::Packet(data) => data,
+                Outgoing::Close(reason) => {
+                    debug!(
+                        "{} closing connection to {}: {:?}",
+                        local_addr, addr, reason
+                    );
+                    let _ = futures::AsyncWriteExt::close(&mut writer).await;
+                    let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                    break;
+                }
+            };
             trace!("write {} bytes to {} ", data.bytes.len(), addr);
-            if let Err(e) = writer.write_all(&data.bytes).await {
+            let bytes = match &codec {
+                Some(codec) => {
+                    frame.clear();
+                    if let Err(e) = codec.encode(&data.bytes, &mut frame) {
+                        error!("{} failed to frame message for {}: {}", local_addr, addr, e);
+                        let reason = DisconnectReason::ProtocolError(e.to_string());
+                        let _ = event_tx.send(NetworkEvent::Error(e)).await;
+                        let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                        break;
+                    }
+                    &frame[..]
+                }
+                None => &data.bytes[..],
+            };
+            if let Err(e) = writer.write_all(bytes).await {
                 trace!("Failed to write data to socket: {}", e);
                 let _ = event_tx
                     .send(NetworkEvent::Error(NetworkError::Common(e.to_string())))
                     .await;
-                let _ = event_tx.send(NetworkEvent::Disconnected).await;
+                let reason = DisconnectReason::ConnectionLost;
+                let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
                 break;
             }
         }
     };
 
-    let tasks = vec![
-        task::spawn(read_task),
-        task::spawn(write_task),
-        task::spawn(async move {
-            let _ = shutdown_rx.recv().await;
-        }),
-    ];
-
-    future::join_all(tasks).await;
+    // the socket is closed once any side of the connection finished
+    pin_mut!(read_task, write_task);
+    future::select(read_task, write_task).await;
 }
 
 /// TcpNode with local socket meas TCP server need to listen socket
@@ -162,77 +290,159 @@ fn on_start_server(
     if let Ok((net_node, server)) = q_tcp_server.get(ev.entity) {
         let local_addr = server.socket_addr;
         let event_tx = net_node.event_channel.sender.clone_async();
-        let event_tx_clone = net_node.event_channel.sender.clone_async();
-        let shutdown_clone = net_node.shutdown_channel.receiver.clone_async();
+        let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
         let new_connection_tx = server.new_connection_channel.sender.clone_async();
-        task::spawn(async move {
-            let tasks = vec![
-                task::spawn(listen(local_addr, event_tx_clone, new_connection_tx)),
-                task::spawn(async move {
-                    match shutdown_clone.recv().await {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(NetworkError::Common(e.to_string())),
-                    }
-                }),
-            ];
-
-            if let Err(err) = future::try_join_all(tasks).await {
-                let _ = event_tx.send(NetworkEvent::Error(err)).await;
-            }
-        });
+        task::spawn(until_shutdown(
+            listen(local_addr, event_tx.clone(), new_connection_tx),
+            shutdown_rx,
+            event_tx,
+        ));
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn on_start_client(
     on: On<StartClient>,
-    q_tcp_client: Query<(&NetworkNode, &ClientNode<TcpAddress>), Without<NetworkPeer>>,
+    q_tcp_client: Query<
+        (
+            &NetworkNode,
+            &ClientNode<TcpAddress>,
+            Option<&FrameCodec>,
+            Option<&ReconnectSetting>,
+            Option<(&Heartbeat, &NetworkRtt)>,
+            Option<&NetworkConditioner>,
+            Option<&QueueSettings>,
+        ),
+        Without<NetworkPeer>,
+    >,
+    #[cfg(feature = "tls")] q_tls: Query<&TlsSettings>,
 ) {
     let ev = on.event();
-    if let Ok((net_node, remote_addr)) = q_tcp_client.get(ev.entity) {
+    if let Ok((
+        net_node,
+        remote_addr,
+        opt_codec,
+        opt_reconnect,
+        opt_heartbeat,
+        opt_conditioner,
+        opt_queue,
+    )) = q_tcp_client.get(ev.entity)
+    {
         info!("try connect to {}", remote_addr.to_string());
 
         let addr = remote_addr.socket_addr;
-        let recv_tx = net_node.recv_message_channel.sender.clone_async();
-        let message_rx = net_node.send_message_channel.receiver.clone_async();
+        let connect_timeout = opt_reconnect
+            .map(ReconnectSetting::connect_timeout)
+            .unwrap_or_else(|| ReconnectSetting::default().connect_timeout());
+        let codec = opt_codec.cloned();
+        let mut layers = TransportLayers::new(opt_queue, opt_heartbeat, opt_conditioner);
+        check_heartbeat(&mut layers, opt_codec, addr);
+        #[allow(unused_mut)]
+        let mut security = StreamSecurity::Plain;
+        #[cfg(feature = "tls")]
+        if let Ok(tls) = q_tls.get(ev.entity) {
+            security = StreamSecurity::TlsClient(tls.clone());
+        }
+        // the layers start with the connection, heartbeats time out from there
+        let net_node = net_node.clone();
         let event_tx = net_node.event_channel.sender.clone_async();
         let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
 
         task::spawn(async move {
-            match TcpStream::connect(addr).await {
-                Ok(tcp_stream) => {
+            #[cfg(feature = "tls")]
+            let started = std::time::Instant::now();
+            match timeout(connect_timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(tcp_stream)) => {
                     tcp_stream
                         .set_nodelay(true)
                         .expect("set_nodelay call failed");
-                    handle_connection(tcp_stream, recv_tx, message_rx, event_tx, shutdown_rx).await;
+                    // the handshake is part of connecting
+                    #[cfg(feature = "tls")]
+                    if let StreamSecurity::TlsClient(tls) = &mut security {
+                        let remaining = connect_timeout.saturating_sub(started.elapsed());
+                        tls.handshake_timeout = tls.handshake_timeout.min(remaining);
+                    }
+                    let (recv_tx, message_rx) = net_node.transport_channels(&layers);
+                    handle_connection(
+                        tcp_stream,
+                        security,
+                        codec,
+                        recv_tx,
+                        message_rx,
+                        event_tx,
+                        shutdown_rx,
+                    )
+                    .await;
                 }
-                Err(err) => {
+                Ok(Err(err)) => {
                     let _ = event_tx
                         .send(NetworkEvent::Error(NetworkError::Connection(
                             err.to_string(),
                         )))
                         .await;
                 }
+                Err(_) => {
+                    let _ = event_tx
+                        .send(NetworkEvent::Error(NetworkError::Connection(format!(
+                            "connect to {} timed out",
+                            addr
+                        ))))
+                        .await;
+                }
             }
         });
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn handle_endpoint(
     mut commands: Commands,
-    q_tcp_server: Query<(Entity, &ServerNode<TcpAddress>, &NetworkNode, &ChannelId)>,
+    q_tcp_server: Query<(
+        Entity,
+        &ServerNode<TcpAddress>,
+        &ChannelId,
+        Option<&FrameCodec>,
+        Option<(&Heartbeat, &NetworkRtt)>,
+        Option<&NetworkConditioner>,
+        Option<&QueueSettings>,
+    )>,
+    #[cfg(feature = "tls")] q_tls: Query<&TlsSettings>,
 ) {
-    for (entity, tcp_node, net_node, channel_id) in q_tcp_server.iter() {
+    for (entity, tcp_node, channel_id, opt_codec, opt_heartbeat, opt_conditioner, opt_queue) in
+        q_tcp_server.iter()
+    {
+        #[allow(unused_mut)]
+        let mut security = StreamSecurity::Plain;
+        #[cfg(feature = "tls")]
+        if let Ok(tls) = q_tls.get(entity) {
+            security = StreamSecurity::TlsServer(tls.clone());
+        }
+
+        let layers = TransportLayers::new(opt_queue, opt_heartbeat, opt_conditioner);
+
         while let Ok(Some(tcp_stream)) = tcp_node.new_connection_channel.receiver.try_recv() {
-            let new_net_node = NetworkNode::default();
             // Create a new entity for the client
             let peer_entity = commands.spawn_empty().id();
-            let recv_tx = net_node.recv_message_channel.sender.clone_async();
-            let message_rx = new_net_node.send_message_channel.receiver.clone_async();
+            let peer_socket = tcp_stream.peer_addr().unwrap();
+            let mut peer_layers = layers.for_peer();
+            let new_net_node = peer_layers.node();
+            check_heartbeat(&mut peer_layers, opt_codec, peer_socket);
+            let (recv_tx, message_rx) = new_net_node.transport_channels(&peer_layers);
             let event_tx = new_net_node.event_channel.sender.clone_async();
             let shutdown_rx = new_net_node.shutdown_channel.receiver.clone_async();
-            let peer_socket = tcp_stream.peer_addr().unwrap();
+            let codec = opt_codec.cloned();
+            let security = security.clone();
             task::spawn(async move {
-                handle_connection(tcp_stream, recv_tx, message_rx, event_tx, shutdown_rx).await;
+                handle_connection(
+                    tcp_stream,
+                    security,
+                    codec,
+                    recv_tx,
+                    message_rx,
+                    event_tx,
+                    shutdown_rx,
+                )
+                .await;
             });
             let peer = NetworkPeer;
 
@@ -240,8 +450,13 @@ fn handle_endpoint(
                 new_net_node,
                 *channel_id,
                 ClientNode(TcpAddress::new(peer_socket)),
+                RemoteAddr(peer_socket),
                 peer,
             ));
+            if let Some(codec) = opt_codec {
+                commands.entity(peer_entity).insert(codec.clone());
+            }
+            peer_layers.insert_on(&mut commands.entity(peer_entity));
 
             info!("new client connected {:?}", peer_entity);
 
@@ -8,21 +8,41 @@ use std::{
 use async_std::{future::timeout, net::UdpSocket, task};
 use bevy::prelude::*;
 use bytes::Bytes;
-use futures::future;
-use kanal::{AsyncReceiver, AsyncSender};
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use kanal::AsyncSender;
 
 use crate::{
+    conditioner::NetworkConditioner,
     error::NetworkError,
-    network_node::{NetworkEvent, NetworkNode, NetworkPeer, NetworkRawPacket},
+    heartbeat::{Heartbeat, NetworkRtt},
+    network_node::{
+        AsyncChannel, DisconnectReason, NetworkEvent, NetworkNode, NetworkPeer, NetworkRawPacket,
+        Outgoing, SendQueue, TransportLayers,
+    },
     prelude::{ClientNode, NetworkAddress, ServerNode},
+    queue::QueueSettings,
     server::StartServer,
+    transports::udp::{
+        reliable::{Reliable, ReliableStats},
+        session::{UdpSession, spawn_session_peers},
+    },
 };
 
+pub mod reliable;
+pub mod session;
+
+/// Largest payload of an IPv4 UDP datagram
+const MAX_PACKET_SIZE: usize = 65_507;
+
 pub struct UdpPlugin;
 
 impl Plugin for UdpPlugin {
     fn build(&self, app: &mut App) {
-        app.add_observer(on_start_server);
+        app.add_systems(PostUpdate, spawn_session_peers)
+            .add_observer(on_start_server);
     }
 }
 
@@ -91,12 +111,17 @@ async fn recv_loop(
     }
 }
 
+/// Send queued packets, returning why the node was closed
 async fn send_loop(
     socket: Arc<UdpSocket>,
     to_socket: Option<SocketAddr>,
-    message_receiver: AsyncReceiver<NetworkRawPacket>,
-) -> Result<(), NetworkError> {
-    while let Ok(packet) = message_receiver.recv().await {
+    mut queue: SendQueue,
+) -> Result<Option<DisconnectReason>, NetworkError> {
+    while let Some(outgoing) = queue.next().await {
+        let packet = match outgoing {
+            Outgoing::Packet(packet) => packet,
+            Outgoing::Close(reason) => return Ok(Some(reason)),
+        };
         trace!(
             "{} Sending {} bytes",
             socket.local_addr().unwrap(),
@@ -122,7 +147,7 @@ async fn send_loop(
         .await?;
     }
 
-    Ok(())
+    Ok(None)
 }
 
 async fn send_data(
@@ -201,10 +226,12 @@ async fn listen(
     has_broadcast: bool,
     opt_v4: Option<MulticastV4Setting>,
     opt_v6: Option<MulticastV6Setting>,
+    reliable: Option<(Reliable, ReliableStats)>,
+    conditioner: Option<NetworkConditioner>,
     recv_tx: AsyncSender<NetworkRawPacket>,
-    send_rx: AsyncReceiver<NetworkRawPacket>,
+    queue: SendQueue,
     event_tx: AsyncSender<NetworkEvent>,
-) -> Result<(), NetworkError> {
+) -> Result<Option<DisconnectReason>, NetworkError> {
     let socket = Arc::new(UdpSocket::bind(listener_socket).await?);
 
     if has_broadcast {
@@ -225,24 +252,37 @@ async fn listen(
         socket.join_multicast_v6(&multi_v6.multi_addr, multi_v6.interface)?;
     }
 
+    let local_addr = socket.local_addr()?;
     info!(
         "UDP listening on {} peer: {:?}",
-        socket.local_addr().unwrap(),
+        local_addr,
         socket.peer_addr().ok()
     );
 
-    let _ = event_tx.send(NetworkEvent::Listen).await;
-
-    let tasks = vec![
-        task::spawn(send_loop(socket.clone(), bind, send_rx)),
-        task::spawn(recv_loop(socket, recv_tx, 65_507)),
-    ];
-
-    if let Err(err) = future::try_join_all(tasks).await {
-        let _ = event_tx.send(NetworkEvent::Error(err)).await;
+    let _ = event_tx.send(NetworkEvent::Listen(local_addr)).await;
+
+    if let Some((settings, stats)) = reliable {
+        return reliable::run(
+            socket,
+            bind,
+            settings,
+            stats,
+            conditioner,
+            recv_tx,
+            queue,
+            MAX_PACKET_SIZE,
+        )
+        .await;
     }
 
-    Ok(())
+    // the socket is closed once sending finished
+    let send = send_loop(socket.clone(), bind, queue);
+    let recv = recv_loop(socket, recv_tx, MAX_PACKET_SIZE);
+    pin_mut!(send, recv);
+    match future::select(send, recv).await {
+        Either::Left((result, _)) => result,
+        Either::Right((result, _)) => result.map(|_| None),
+    }
 }
 
 #[allow(clippy::type_complexity)]
@@ -256,13 +296,29 @@ fn on_start_server(
             Option<&UdpBroadcast>,
             Option<&MulticastV4Setting>,
             Option<&MulticastV6Setting>,
+            Option<(&Reliable, &ReliableStats)>,
+            Option<&UdpSession>,
+            Option<(&Heartbeat, &NetworkRtt)>,
+            Option<&NetworkConditioner>,
+            Option<&QueueSettings>,
         ),
         Without<NetworkPeer>,
     >,
 ) {
     let ev = on.event();
-    if let Ok((net_node, server_addr, opt_remote_addr, opt_broadcast, opt_v4, opt_v6)) =
-        q_udp.get(ev.entity)
+    if let Ok((
+        net_node,
+        server_addr,
+        opt_remote_addr,
+        opt_broadcast,
+        opt_v4,
+        opt_v6,
+        opt_reliable,
+        opt_session,
+        opt_heartbeat,
+        opt_conditioner,
+        opt_queue,
+    )) = q_udp.get(ev.entity)
     {
         let local_addr = server_addr.socket_addr;
 
@@ -271,34 +327,77 @@ fn on_start_server(
         let has_broadcast = opt_broadcast.is_some();
         let opt_v4 = opt_v4.cloned();
         let opt_v6 = opt_v6.cloned();
+        let reliable = opt_reliable.map(|(settings, stats)| (settings.clone(), stats.clone()));
         let listener_socket = local_addr;
-        let recv_tx = net_node.recv_message_channel.sender.clone_async();
-        let send_rx = net_node.send_message_channel.receiver.clone_async();
+        // session peers get their own layers, the router takes the datagrams
+        let layers = match opt_session {
+            Some(_) => TransportLayers::default(),
+            None => TransportLayers::new(
+                opt_queue,
+                opt_heartbeat.filter(|_| remote_addr.is_some()),
+                None,
+            ),
+        };
+        let (mut recv_tx, mut send_rx) = net_node.transport_channels(&layers);
         let event_tx = net_node.event_channel.sender.clone_async();
         let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
 
+        // datagrams go through the session router instead of the server channel
+        let mut opt_router = None;
+        if let Some(session) = opt_session {
+            let routed: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+            recv_tx = routed.sender.clone_async();
+            opt_router = Some(session::route(
+                session.idle_timeout,
+                session.max_sessions,
+                net_node.traffic.clone(),
+                TransportLayers::new(opt_queue, opt_heartbeat, None),
+                session.new_peer_channel.sender.clone_async(),
+                routed.receiver.clone_async(),
+                net_node.send_message_channel.sender.clone_async(),
+            ));
+        }
+        // conditions the whole socket, session peers included. Reliable UDP
+        // conditions its datagrams below the reliability layer instead
+        let conditioner = opt_conditioner.cloned();
+        if reliable.is_none()
+            && let Some(conditioner) = &conditioner
+        {
+            (recv_tx, send_rx) = conditioner.spawn_layer(recv_tx, send_rx);
+        }
+
         task::spawn(async move {
-            let tasks = vec![
-                task::spawn(listen(
-                    listener_socket,
-                    remote_addr,
-                    has_broadcast,
-                    opt_v4,
-                    opt_v6,
-                    recv_tx,
-                    send_rx,
-                    event_tx.clone(),
-                )),
-                task::spawn(async move {
-                    match shutdown_rx.recv().await {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(NetworkError::Common(e.to_string())),
+            let serve = listen(
+                listener_socket,
+                remote_addr,
+                has_broadcast,
+                opt_v4,
+                opt_v6,
+                reliable,
+                conditioner,
+                recv_tx,
+                SendQueue::new(send_rx, shutdown_rx),
+                event_tx.clone(),
+            );
+            let result = match opt_router {
+                Some(router) => {
+                    pin_mut!(serve, router);
+                    match future::select(serve, router).await {
+                        Either::Left((result, _)) => result,
+                        Either::Right((result, _)) => result.map(|_| None),
                     }
-                }),
-            ];
-
-            if let Err(err) = future::try_join_all(tasks).await {
-                let _ = event_tx.send(NetworkEvent::Error(err)).await;
+                }
+                None => serve.await,
+            };
+
+            match result {
+                Ok(Some(reason)) => {
+                    let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let _ = event_tx.send(NetworkEvent::Error(err)).await;
+                }
             }
         });
     }
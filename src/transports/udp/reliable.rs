@@ -0,0 +1,934 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use async_std::{net::UdpSocket, task};
+use bevy::prelude::*;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use kanal::{AsyncReceiver, AsyncSender};
+
+use crate::{
+    conditioner::NetworkConditioner,
+    error::NetworkError,
+    network_node::{AsyncChannel, DisconnectReason, NetworkRawPacket, Outgoing, SendQueue},
+};
+
+/// `kind` `epoch` `seq` `base` payload
+const DATA: u8 = 0x01;
+/// `kind` `epoch` `seq`
+const ACK: u8 = 0x02;
+/// `kind` `epoch` `base`, sent when the sender gives up on a packet
+const SYNC: u8 = 0x03;
+/// `kind` `epoch` `cookie`, asks the sender of an unknown epoch to confirm it
+const CHALLENGE: u8 = 0x04;
+/// `kind` `epoch` `cookie`, answers a challenge for the current epoch
+const CONFIRM: u8 = 0x05;
+
+const DATA_HEADER_LEN: usize = 13;
+const CONTROL_LEN: usize = 9;
+
+/// Sequence numbers accepted ahead of the next expected one
+const RECEIVE_WINDOW: u32 = 1 << 15;
+const TICK: Duration = Duration::from_millis(10);
+/// Idle peers without packets in flight are forgotten after this
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How a reliable UDP node hands received messages to the channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Reflect)]
+pub enum DeliveryMode {
+    /// Messages are delivered in the order they were sent
+    #[default]
+    Ordered,
+    /// Messages are delivered as soon as they arrive
+    Unordered,
+}
+
+/// Acknowledge and retransmit every message of a UDP node.
+///
+/// Both sides of the conversation need the component. A message not
+/// acknowledged after `max_retransmits` retransmissions is counted as lost in
+/// [`ReliableStats`] and skipped by ordered receivers.
+///
+/// A remote restarting with a new sequence has to answer a challenge before
+/// its packets are accepted, so spoofed packets can not reset the receive
+/// state. Text messages are not carried.
+#[derive(Component, Debug, Clone, Reflect)]
+#[require(ReliableStats)]
+pub struct Reliable {
+    pub mode: DeliveryMode,
+    pub max_retransmits: u32,
+    /// Retransmission timeout before the first round trip was measured
+    pub initial_rto: Duration,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    /// Out of order payloads held per remote in ordered mode, packets beyond
+    /// are not acknowledged and arrive again by retransmission
+    pub max_buffered_bytes: usize,
+    /// Remotes tracked at once, packets of further remotes are dropped
+    pub max_peers: usize,
+}
+
+impl Default for Reliable {
+    fn default() -> Self {
+        Self {
+            mode: DeliveryMode::Ordered,
+            max_retransmits: 10,
+            initial_rto: Duration::from_millis(250),
+            min_rto: Duration::from_millis(50),
+            max_rto: Duration::from_secs(2),
+            max_buffered_bytes: 1 << 20,
+            max_peers: 1024,
+        }
+    }
+}
+
+impl Reliable {
+    pub fn ordered() -> Self {
+        Self::default()
+    }
+
+    pub fn unordered() -> Self {
+        Self {
+            mode: DeliveryMode::Unordered,
+            ..default()
+        }
+    }
+
+    pub fn with_max_retransmits(mut self, max_retransmits: u32) -> Self {
+        self.max_retransmits = max_retransmits;
+        self
+    }
+}
+
+/// Delivery counters of a [`Reliable`] node, updated by the network task
+#[derive(Component, Debug, Clone, Default)]
+pub struct ReliableStats(Arc<ReliableCounters>);
+
+#[derive(Debug, Default)]
+struct ReliableCounters {
+    delivered: AtomicU64,
+    lost: AtomicU64,
+    retransmitted: AtomicU64,
+    duplicates: AtomicU64,
+    rtt_micros: AtomicU64,
+}
+
+impl ReliableStats {
+    /// Messages acknowledged by the remote
+    pub fn delivered(&self) -> u64 {
+        self.0.delivered.load(Ordering::Relaxed)
+    }
+
+    /// Messages given up after `max_retransmits`
+    pub fn lost(&self) -> u64 {
+        self.0.lost.load(Ordering::Relaxed)
+    }
+
+    pub fn retransmitted(&self) -> u64 {
+        self.0.retransmitted.load(Ordering::Relaxed)
+    }
+
+    /// Received messages dropped because they were already delivered
+    pub fn duplicates(&self) -> u64 {
+        self.0.duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Last smoothed round trip time
+    pub fn rtt(&self) -> Duration {
+        Duration::from_micros(self.0.rtt_micros.load(Ordering::Relaxed))
+    }
+}
+
+struct InFlight {
+    seq: u32,
+    payload: Bytes,
+    first_sent: Instant,
+    last_sent: Instant,
+    rto: Duration,
+    retransmits: u32,
+    done: bool,
+}
+
+struct SendState {
+    epoch: u32,
+    next_seq: u32,
+    in_flight: VecDeque<InFlight>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl SendState {
+    fn new(rto: Duration) -> Self {
+        Self {
+            epoch: fastrand::u32(..),
+            next_seq: 0,
+            in_flight: VecDeque::new(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto,
+        }
+    }
+
+    /// Oldest sequence still waiting for an ack
+    fn base(&self) -> u32 {
+        self.in_flight
+            .front()
+            .map_or(self.next_seq, |packet| packet.seq)
+    }
+
+    fn get_mut(&mut self, seq: u32) -> Option<&mut InFlight> {
+        let front = self.in_flight.front()?.seq;
+        self.in_flight
+            .get_mut(seq.wrapping_sub(front) as usize)
+            .filter(|packet| packet.seq == seq)
+    }
+
+    fn pop_done(&mut self) {
+        while self.in_flight.front().is_some_and(|packet| packet.done) {
+            self.in_flight.pop_front();
+        }
+    }
+
+    /// RFC 6298 round trip estimation
+    fn sample_rtt(&mut self, rtt: Duration, settings: &Reliable) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap() + (self.rttvar * 4).max(TICK))
+            .clamp(settings.min_rto, settings.max_rto);
+    }
+}
+
+struct RecvState {
+    epoch: u32,
+    next_expected: u32,
+    /// Out of order arrivals, payloads are kept until their turn in ordered mode
+    received: HashMap<u32, Option<Bytes>>,
+    /// Bytes of the payloads in `received`
+    buffered: usize,
+}
+
+impl RecvState {
+    fn new(epoch: u32, next_expected: u32) -> Self {
+        Self {
+            epoch,
+            next_expected,
+            received: HashMap::new(),
+            buffered: 0,
+        }
+    }
+
+    fn insert(&mut self, seq: u32, payload: Option<Bytes>) {
+        self.buffered += payload.as_ref().map_or(0, Bytes::len);
+        self.received.insert(seq, payload);
+    }
+
+    fn take(&mut self, seq: u32) -> Option<Option<Bytes>> {
+        let payload = self.received.remove(&seq)?;
+        self.buffered -= payload.as_ref().map_or(0, Bytes::len);
+        Some(payload)
+    }
+
+    fn advance(&mut self, delivered: &mut Vec<Bytes>) {
+        while let Some(payload) = self.take(self.next_expected) {
+            delivered.extend(payload);
+            self.next_expected = self.next_expected.wrapping_add(1);
+        }
+    }
+
+    /// Everything before `base` was acked or given up by the sender
+    fn skip_to(&mut self, base: u32, delivered: &mut Vec<Bytes>) {
+        if !is_before(self.next_expected, base) {
+            return;
+        }
+        let next_expected = self.next_expected;
+        let mut skipped: Vec<u32> = self
+            .received
+            .keys()
+            .copied()
+            .filter(|seq| is_before(*seq, base))
+            .collect();
+        skipped.sort_by_key(|seq| seq.wrapping_sub(next_expected));
+        for seq in skipped {
+            delivered.extend(self.take(seq).flatten());
+        }
+        self.next_expected = base;
+        self.advance(delivered);
+    }
+}
+
+/// Pending confirmation of a new epoch, the remote has to echo the cookie
+struct Challenge {
+    epoch: u32,
+    cookie: u32,
+    base: u32,
+}
+
+struct Peer {
+    send: SendState,
+    recv: Option<RecvState>,
+    challenge: Option<Challenge>,
+    last_seen: Instant,
+}
+
+struct Endpoint {
+    settings: Reliable,
+    stats: ReliableStats,
+    peers: Mutex<HashMap<SocketAddr, Peer>>,
+}
+
+impl Endpoint {
+    fn new(settings: Reliable, stats: ReliableStats) -> Self {
+        Self {
+            settings,
+            stats,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn new_peer(&self, now: Instant) -> Peer {
+        Peer {
+            send: SendState::new(self.settings.initial_rto),
+            recv: None,
+            challenge: None,
+            last_seen: now,
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, payload: Bytes, now: Instant) -> Bytes {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(addr).or_insert_with(|| self.new_peer(now));
+        peer.last_seen = now;
+
+        let send = &mut peer.send;
+        let seq = send.next_seq;
+        send.next_seq = seq.wrapping_add(1);
+        send.in_flight.push_back(InFlight {
+            seq,
+            payload: payload.clone(),
+            first_sent: now,
+            last_sent: now,
+            rto: send.rto,
+            retransmits: 0,
+            done: false,
+        });
+        encode_data(send.epoch, seq, send.base(), &payload)
+    }
+
+    /// Handle a datagram, returns the reply and the payloads to deliver
+    fn receive(&self, addr: SocketAddr, bytes: &[u8], now: Instant) -> (Option<Bytes>, Vec<Bytes>) {
+        let mut delivered = vec![];
+        let Some((&kind, rest)) = bytes.split_first() else {
+            return (None, delivered);
+        };
+        if !matches!(kind, DATA | ACK | SYNC | CHALLENGE | CONFIRM) || bytes.len() < CONTROL_LEN {
+            trace!("{} drop {} bytes of unknown packet", addr, bytes.len());
+            return (None, delivered);
+        }
+        let epoch = read_u32(&rest[..4]);
+        let value = read_u32(&rest[4..8]);
+        let rest = &rest[8..];
+
+        let mut peers = self.peers.lock().unwrap();
+        // only data starts a conversation, every remote costs an entry until
+        // it timed out
+        if !peers.contains_key(&addr) && (kind != DATA || peers.len() >= self.settings.max_peers) {
+            trace!("{} drop packet of unknown remote", addr);
+            return (None, delivered);
+        }
+        let peer = peers.entry(addr).or_insert_with(|| self.new_peer(now));
+        peer.last_seen = now;
+
+        let reply = match kind {
+            DATA if rest.len() >= DATA_HEADER_LEN - CONTROL_LEN => {
+                let base = read_u32(&rest[..4]);
+                let payload = Bytes::copy_from_slice(&rest[4..]);
+                self.receive_data(peer, epoch, value, base, payload, &mut delivered)
+            }
+            ACK => {
+                self.receive_ack(peer, epoch, value, now);
+                None
+            }
+            SYNC => {
+                if let Some(recv) = peer.recv.as_mut().filter(|recv| recv.epoch == epoch) {
+                    recv.skip_to(value, &mut delivered);
+                }
+                None
+            }
+            // prove that we sent the packets of this epoch
+            CHALLENGE => (peer.send.epoch == epoch).then(|| encode_control(CONFIRM, epoch, value)),
+            CONFIRM => {
+                if let Some(challenge) = peer
+                    .challenge
+                    .take_if(|challenge| challenge.epoch == epoch && challenge.cookie == value)
+                {
+                    debug!("{} restarted with epoch {}", addr, epoch);
+                    peer.recv = Some(RecvState::new(epoch, challenge.base));
+                }
+                None
+            }
+            _ => None,
+        };
+
+        (reply, delivered)
+    }
+
+    fn receive_data(
+        &self,
+        peer: &mut Peer,
+        epoch: u32,
+        seq: u32,
+        base: u32,
+        payload: Bytes,
+        delivered: &mut Vec<Bytes>,
+    ) -> Option<Bytes> {
+        let recv = match &mut peer.recv {
+            Some(recv) if recv.epoch == epoch => recv,
+            recv @ None => recv.insert(RecvState::new(epoch, base)),
+            // a new epoch means the remote restarted its sequence, anyone can
+            // claim that so the remote has to answer a challenge first
+            Some(_) => {
+                if peer
+                    .challenge
+                    .as_ref()
+                    .is_none_or(|challenge| challenge.epoch != epoch)
+                {
+                    peer.challenge = Some(Challenge {
+                        epoch,
+                        cookie: fastrand::u32(..),
+                        base,
+                    });
+                }
+                let cookie = peer.challenge.as_ref().unwrap().cookie;
+                return Some(encode_control(CHALLENGE, epoch, cookie));
+            }
+        };
+
+        if seq.wrapping_sub(recv.next_expected) >= RECEIVE_WINDOW
+            && !is_before(seq, recv.next_expected)
+        {
+            return None;
+        }
+
+        if is_before(seq, recv.next_expected) || recv.received.contains_key(&seq) {
+            self.stats.0.duplicates.fetch_add(1, Ordering::Relaxed);
+        } else {
+            match self.settings.mode {
+                DeliveryMode::Ordered => {
+                    // not acked, the sender retransmits it once the gap closed
+                    if seq != recv.next_expected
+                        && recv.buffered + payload.len() > self.settings.max_buffered_bytes
+                    {
+                        trace!("drop packet {}, receive buffer is full", seq);
+                        return None;
+                    }
+                    recv.insert(seq, Some(payload));
+                }
+                DeliveryMode::Unordered => {
+                    delivered.push(payload);
+                    recv.insert(seq, None);
+                }
+            }
+        }
+        recv.skip_to(base, delivered);
+        recv.advance(delivered);
+
+        Some(encode_control(ACK, epoch, seq))
+    }
+
+    fn receive_ack(&self, peer: &mut Peer, epoch: u32, seq: u32, now: Instant) {
+        let send = &mut peer.send;
+        if send.epoch != epoch {
+            return;
+        }
+        let Some(packet) = send.get_mut(seq).filter(|packet| !packet.done) else {
+            return;
+        };
+        packet.done = true;
+        // Karn's algorithm, retransmitted packets give ambiguous samples
+        let sample = (packet.retransmits == 0).then(|| now.duration_since(packet.first_sent));
+        if let Some(rtt) = sample {
+            send.sample_rtt(rtt, &self.settings);
+            self.stats
+                .0
+                .rtt_micros
+                .store(send.srtt.unwrap().as_micros() as u64, Ordering::Relaxed);
+        }
+        send.pop_done();
+        self.stats.0.delivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Packets due for retransmission
+    fn tick(&self, now: Instant) -> Vec<(SocketAddr, Bytes)> {
+        let mut outgoing = vec![];
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|addr, peer| {
+            let send = &mut peer.send;
+            let mut gave_up = false;
+            let base = send.base();
+            for packet in send.in_flight.iter_mut().filter(|packet| !packet.done) {
+                if now.duration_since(packet.last_sent) < packet.rto {
+                    continue;
+                }
+                if packet.retransmits >= self.settings.max_retransmits {
+                    packet.done = true;
+                    gave_up = true;
+                    self.stats.0.lost.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                packet.retransmits += 1;
+                packet.last_sent = now;
+                packet.rto = (packet.rto * 2).min(self.settings.max_rto);
+                self.stats.0.retransmitted.fetch_add(1, Ordering::Relaxed);
+                outgoing.push((
+                    *addr,
+                    encode_data(send.epoch, packet.seq, base, &packet.payload),
+                ));
+            }
+            send.pop_done();
+            if gave_up {
+                outgoing.push((*addr, encode_control(SYNC, send.epoch, send.base())));
+            }
+
+            !send.in_flight.is_empty() || now.duration_since(peer.last_seen) < PEER_TIMEOUT
+        });
+
+        outgoing
+    }
+}
+
+/// `a` comes before `b` in wrapping sequence order
+fn is_before(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+fn encode_data(epoch: u32, seq: u32, base: u32, payload: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(DATA_HEADER_LEN + payload.len());
+    bytes.put_u8(DATA);
+    bytes.put_u32(epoch);
+    bytes.put_u32(seq);
+    bytes.put_u32(base);
+    bytes.extend_from_slice(payload);
+    bytes.freeze()
+}
+
+fn encode_control(kind: u8, epoch: u32, value: u32) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(CONTROL_LEN);
+    bytes.put_u8(kind);
+    bytes.put_u32(epoch);
+    bytes.put_u32(value);
+    bytes.freeze()
+}
+
+/// The socket as the endpoint sees it, datagrams, acks and retransmits
+/// included, pass the conditioner when there is one
+#[derive(Clone)]
+enum Wire {
+    Socket(Arc<UdpSocket>),
+    Conditioned {
+        /// Datagrams the conditioner let through from the socket
+        inbound: AsyncReceiver<NetworkRawPacket>,
+        /// Datagrams for the conditioner on their way to the socket
+        outbound: AsyncSender<NetworkRawPacket>,
+    },
+}
+
+impl Wire {
+    async fn send_to(&self, data: Bytes, addr: SocketAddr) -> Result<(), NetworkError> {
+        match self {
+            Self::Socket(socket) => {
+                socket.send_to(&data, addr).await?;
+            }
+            Self::Conditioned { outbound, .. } => {
+                let packet = NetworkRawPacket {
+                    addr: Some(addr),
+                    bytes: data,
+                    text: None,
+                };
+                let _ = outbound.send(packet).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::Socket(socket) => socket.recv_from(buf).await,
+            Self::Conditioned { inbound, .. } => {
+                let packet = inbound
+                    .recv()
+                    .await
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+                let len = packet.bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet.bytes[..len]);
+                Ok((len, packet.addr.expect("datagrams carry their address")))
+            }
+        }
+    }
+}
+
+/// Move datagrams between the socket and the conditioner
+async fn pump(
+    socket: Arc<UdpSocket>,
+    inbound_tx: AsyncSender<NetworkRawPacket>,
+    outbound_rx: AsyncReceiver<NetworkRawPacket>,
+    max_packet_size: usize,
+) -> Result<(), NetworkError> {
+    let receive = async {
+        let mut buf: Vec<u8> = vec![0; max_packet_size];
+        loop {
+            let (len, from_addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                #[cfg(target_os = "windows")]
+                Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err::<(), _>(NetworkError::Listen(e)),
+            };
+            let packet = NetworkRawPacket {
+                addr: Some(from_addr),
+                bytes: Bytes::copy_from_slice(&buf[..len]),
+                text: None,
+            };
+            let _ = inbound_tx.send(packet).await;
+        }
+    };
+    let send = async {
+        while let Ok(packet) = outbound_rx.recv().await {
+            if let Some(addr) = packet.addr {
+                socket.send_to(&packet.bytes, addr).await?;
+            }
+        }
+        Ok(())
+    };
+
+    future::try_join(receive, send).await.map(|_| ())
+}
+
+/// Send and receive loops of a reliable UDP node
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run(
+    socket: Arc<UdpSocket>,
+    to_socket: Option<SocketAddr>,
+    settings: Reliable,
+    stats: ReliableStats,
+    conditioner: Option<NetworkConditioner>,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    queue: SendQueue,
+    max_packet_size: usize,
+) -> Result<Option<DisconnectReason>, NetworkError> {
+    let endpoint = Arc::new(Endpoint::new(settings, stats));
+
+    let (wire, pumping) = match conditioner {
+        Some(conditioner) => {
+            let inbound: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+            let outbound: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+            let (inbound_tx, outbound_rx) = conditioner.spawn_layer(
+                inbound.sender.clone_async(),
+                outbound.receiver.clone_async(),
+            );
+            let wire = Wire::Conditioned {
+                inbound: inbound.receiver.clone_async(),
+                outbound: outbound.sender.clone_async(),
+            };
+            let pumping = pump(socket, inbound_tx, outbound_rx, max_packet_size);
+            (wire, Either::Left(pumping))
+        }
+        None => (Wire::Socket(socket), Either::Right(future::pending())),
+    };
+
+    let send = send_loop(
+        wire.clone(),
+        endpoint.clone(),
+        to_socket,
+        queue,
+        max_packet_size,
+    );
+    let background = future::try_join3(
+        recv_loop(wire.clone(), endpoint.clone(), recv_tx, max_packet_size),
+        retransmit_loop(wire, endpoint),
+        pumping,
+    );
+    pin_mut!(send, background);
+    match future::select(send, background).await {
+        Either::Left((result, _)) => result,
+        Either::Right((result, _)) => result.map(|_| None),
+    }
+}
+
+async fn send_loop(
+    wire: Wire,
+    endpoint: Arc<Endpoint>,
+    to_socket: Option<SocketAddr>,
+    mut queue: SendQueue,
+    max_packet_size: usize,
+) -> Result<Option<DisconnectReason>, NetworkError> {
+    while let Some(outgoing) = queue.next().await {
+        let packet = match outgoing {
+            Outgoing::Packet(packet) => packet,
+            Outgoing::Close(reason) => return Ok(Some(reason)),
+        };
+        let Some(addr) = packet.addr.or(to_socket) else {
+            continue;
+        };
+        if packet.text.is_some() {
+            error!("reliable UDP does not carry text messages, use bytes");
+            continue;
+        }
+        if packet.bytes.len() + DATA_HEADER_LEN > max_packet_size {
+            error!(
+                "{} bytes exceed the reliable UDP payload limit",
+                packet.bytes.len()
+            );
+            continue;
+        }
+        let data = endpoint.send(addr, packet.bytes, Instant::now());
+        wire.send_to(data, addr).await?;
+    }
+
+    Ok(None)
+}
+
+async fn recv_loop(
+    wire: Wire,
+    endpoint: Arc<Endpoint>,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    max_packet_size: usize,
+) -> Result<(), NetworkError> {
+    let mut buf: Vec<u8> = vec![0; max_packet_size];
+
+    loop {
+        let (len, from_addr) = match wire.recv_from(&mut buf).await {
+            Ok(received) => received,
+            #[cfg(target_os = "windows")]
+            Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+            Err(e) => return Err(NetworkError::Listen(e)),
+        };
+        let (reply, delivered) = endpoint.receive(from_addr, &buf[..len], Instant::now());
+        if let Some(reply) = reply {
+            wire.send_to(reply, from_addr).await?;
+        }
+        for bytes in delivered {
+            let _ = recv_tx
+                .send(NetworkRawPacket {
+                    addr: Some(from_addr),
+                    bytes,
+                    text: None,
+                })
+                .await;
+        }
+    }
+}
+
+async fn retransmit_loop(wire: Wire, endpoint: Arc<Endpoint>) -> Result<(), NetworkError> {
+    loop {
+        task::sleep(TICK).await;
+        for (addr, data) in endpoint.tick(Instant::now()) {
+            wire.send_to(data, addr).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn endpoint(settings: Reliable) -> Endpoint {
+        Endpoint::new(settings, ReliableStats::default())
+    }
+
+    /// Data packets of `count` numbered payloads sent from `sender`
+    fn send_all(sender: &Endpoint, count: u8, now: Instant) -> Vec<Bytes> {
+        (0..count)
+            .map(|i| sender.send(addr(2), Bytes::from(vec![i]), now))
+            .collect()
+    }
+
+    fn payloads(delivered: Vec<Bytes>) -> Vec<u8> {
+        delivered.iter().map(|bytes| bytes[0]).collect()
+    }
+
+    #[test]
+    fn ordered_delivery_waits_for_gaps() {
+        let (sender, receiver) = (endpoint(Reliable::ordered()), endpoint(Reliable::ordered()));
+        let now = Instant::now();
+        let packets = send_all(&sender, 3, now);
+
+        let (ack, delivered) = receiver.receive(addr(1), &packets[0], now);
+        assert_eq!(ack, Some(encode_control(ACK, sender_epoch(&sender), 0)));
+        assert_eq!(payloads(delivered), [0]);
+        assert!(receiver.receive(addr(1), &packets[2], now).1.is_empty());
+        assert_eq!(
+            payloads(receiver.receive(addr(1), &packets[1], now).1),
+            [1, 2]
+        );
+
+        // duplicates are acked again but not delivered
+        let (ack, delivered) = receiver.receive(addr(1), &packets[1], now);
+        assert!(ack.is_some());
+        assert!(delivered.is_empty());
+        assert_eq!(receiver.stats.duplicates(), 1);
+    }
+
+    #[test]
+    fn unordered_delivery_does_not_wait() {
+        let sender = endpoint(Reliable::unordered());
+        let receiver = endpoint(Reliable::unordered());
+        let now = Instant::now();
+        let packets = send_all(&sender, 3, now);
+
+        assert_eq!(payloads(receiver.receive(addr(1), &packets[0], now).1), [0]);
+        assert_eq!(payloads(receiver.receive(addr(1), &packets[2], now).1), [2]);
+        assert_eq!(payloads(receiver.receive(addr(1), &packets[1], now).1), [1]);
+        assert!(receiver.receive(addr(1), &packets[2], now).1.is_empty());
+    }
+
+    #[test]
+    fn acks_complete_packets() {
+        let (sender, receiver) = (endpoint(Reliable::ordered()), endpoint(Reliable::ordered()));
+        let now = Instant::now();
+        let rtt = Duration::from_millis(40);
+
+        for packet in send_all(&sender, 3, now) {
+            let (ack, _) = receiver.receive(addr(1), &packet, now);
+            sender.receive(addr(2), &ack.unwrap(), now + rtt);
+        }
+
+        assert_eq!(sender.stats.delivered(), 3);
+        assert_eq!(sender.stats.rtt(), rtt);
+        assert!(sender.tick(now + Duration::from_secs(5)).is_empty());
+    }
+
+    #[test]
+    fn lost_packets_are_retransmitted_then_skipped() {
+        let settings = Reliable::ordered().with_max_retransmits(2);
+        let rto = settings.initial_rto;
+        let (sender, receiver) = (endpoint(settings.clone()), endpoint(settings));
+        let now = Instant::now();
+        let packets = send_all(&sender, 2, now);
+
+        // the second packet arrives, the first is lost
+        let (ack, _) = receiver.receive(addr(1), &packets[1], now);
+        sender.receive(addr(2), &ack.unwrap(), now);
+
+        assert!(sender.tick(now + rto / 2).is_empty());
+        let retransmitted = sender.tick(now + rto);
+        assert_eq!(retransmitted, vec![(addr(2), packets[0].clone())]);
+        // the timeout doubles
+        assert!(sender.tick(now + rto * 2).is_empty());
+        assert_eq!(sender.tick(now + rto * 3).len(), 1);
+        assert_eq!(sender.stats.retransmitted(), 2);
+
+        // give up and tell the receiver to move on
+        let sync = sender.tick(now + rto * 7);
+        assert_eq!(sender.stats.lost(), 1);
+        assert_eq!(
+            sync,
+            vec![(addr(2), encode_control(SYNC, sender_epoch(&sender), 2))]
+        );
+        assert_eq!(payloads(receiver.receive(addr(1), &sync[0].1, now).1), [1]);
+    }
+
+    #[test]
+    fn new_epochs_are_confirmed_before_reset() {
+        let receiver = endpoint(Reliable::ordered());
+        let now = Instant::now();
+        let sender = endpoint(Reliable::ordered());
+        let packets = send_all(&sender, 2, now);
+        receiver.receive(addr(1), &packets[0], now);
+
+        // a restarted sender or a spoofed packet with another epoch
+        let restarted = endpoint(Reliable::ordered());
+        let packet = send_all(&restarted, 1, now).remove(0);
+        let (challenge, delivered) = receiver.receive(addr(1), &packet, now);
+        let challenge = challenge.unwrap();
+        assert_eq!(challenge[0], CHALLENGE);
+        assert!(delivered.is_empty());
+        // a wrong cookie changes nothing
+        let forged = encode_control(CONFIRM, sender_epoch(&restarted), 0);
+        receiver.receive(addr(1), &forged, now);
+        assert_eq!(payloads(receiver.receive(addr(1), &packets[1], now).1), [1]);
+
+        // only the owner of the epoch answers the challenge
+        assert!(sender.receive(addr(2), &challenge, now).0.is_none());
+        let (confirm, _) = restarted.receive(addr(2), &challenge, now);
+        receiver.receive(addr(1), &confirm.unwrap(), now);
+        assert_eq!(payloads(receiver.receive(addr(1), &packet, now).1), [0]);
+    }
+
+    #[test]
+    fn buffered_bytes_are_capped() {
+        let settings = Reliable {
+            max_buffered_bytes: 2,
+            ..Reliable::ordered()
+        };
+        let (sender, receiver) = (endpoint(settings.clone()), endpoint(settings));
+        let now = Instant::now();
+        let packets = send_all(&sender, 5, now);
+
+        receiver.receive(addr(1), &packets[0], now);
+        assert!(receiver.receive(addr(1), &packets[2], now).0.is_some());
+        assert!(receiver.receive(addr(1), &packets[3], now).0.is_some());
+        // not acked, arrives again once the gap closed
+        assert!(receiver.receive(addr(1), &packets[4], now).0.is_none());
+
+        assert_eq!(
+            payloads(receiver.receive(addr(1), &packets[1], now).1),
+            [1, 2, 3]
+        );
+        assert_eq!(payloads(receiver.receive(addr(1), &packets[4], now).1), [4]);
+    }
+
+    #[test]
+    fn remotes_are_bounded() {
+        let settings = Reliable {
+            max_peers: 2,
+            ..default()
+        };
+        let receiver = endpoint(settings);
+        let sender = endpoint(default());
+        let now = Instant::now();
+        let packet = send_all(&sender, 1, now).remove(0);
+
+        assert!(receiver.receive(addr(1), &packet, now).0.is_some());
+        assert!(receiver.receive(addr(2), &packet, now).0.is_some());
+        assert!(receiver.receive(addr(3), &packet, now).0.is_none());
+        // control packets never add remotes
+        let ack = encode_control(ACK, sender_epoch(&sender), 0);
+        receiver.receive(addr(4), &ack, now);
+        assert_eq!(receiver.peers.lock().unwrap().len(), 2);
+
+        // idle remotes are forgotten
+        receiver.tick(now + PEER_TIMEOUT);
+        assert!(receiver.receive(addr(3), &packet, now).0.is_some());
+    }
+
+    fn sender_epoch(endpoint: &Endpoint) -> u32 {
+        endpoint.peers.lock().unwrap()[&addr(2)].send.epoch
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use bevy::prelude::*;
+use futures::{future, pin_mut};
+use kanal::{AsyncReceiver, AsyncSender};
+
+use crate::{
+    channels::ChannelId,
+    client::ClientNode,
+    error::NetworkError,
+    network_node::{
+        AsyncChannel, DisconnectReason, NetworkEvent, NetworkNode, NetworkPeer, NetworkRawPacket,
+        Outgoing, RemoteAddr, SendQueue, TransportLayers,
+    },
+    stats::TrafficCounters,
+    transports::udp::UdpAddress,
+};
+
+/// Spawn a [`NetworkPeer`] child per remote address of a UDP server.
+///
+/// The peer gets its own [`NetworkNode`], its sends go out of the server
+/// socket, and it is disconnected after `idle_timeout` without datagrams.
+/// With a [`Heartbeat`](crate::heartbeat::Heartbeat) on the server every peer is pinged as well.
+#[derive(Component)]
+pub struct UdpSession {
+    pub idle_timeout: Duration,
+    /// Sessions open at once, datagrams of further remote addresses are
+    /// dropped and counted in the `dropped_received` stat of the server
+    pub max_sessions: usize,
+    pub(crate) new_peer_channel: AsyncChannel<(SocketAddr, NetworkNode, TransportLayers)>,
+}
+
+impl Default for UdpSession {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+impl Debug for UdpSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpSession")
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_sessions", &self.max_sessions)
+            .finish()
+    }
+}
+
+impl UdpSession {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            max_sessions: 1024,
+            new_peer_channel: AsyncChannel::new(),
+        }
+    }
+
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+}
+
+struct SessionLink {
+    /// Tells the sessions of a remote address that reconnected apart
+    id: u64,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    event_tx: AsyncSender<NetworkEvent>,
+    last_seen: Instant,
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, SessionLink>>>;
+
+/// Hand received datagrams to the peer of their remote address
+pub(crate) async fn route(
+    idle_timeout: Duration,
+    max_sessions: usize,
+    traffic: Arc<TrafficCounters>,
+    layers: TransportLayers,
+    new_peer_tx: AsyncSender<(SocketAddr, NetworkNode, TransportLayers)>,
+    packet_rx: AsyncReceiver<NetworkRawPacket>,
+    send_tx: AsyncSender<NetworkRawPacket>,
+) -> Result<(), NetworkError> {
+    let sessions: Sessions = Default::default();
+    let mut next_id = 0;
+
+    let routing = async {
+        while let Ok(packet) = packet_rx.recv().await {
+            let Some(addr) = packet.addr else {
+                continue;
+            };
+
+            let recv_tx = {
+                let mut links = sessions.lock().unwrap();
+                let full = links.len() >= max_sessions;
+                match links.get_mut(&addr) {
+                    Some(link) => {
+                        link.last_seen = Instant::now();
+                        link.recv_tx.clone()
+                    }
+                    None if full => {
+                        trace!("{} drop datagram, too many UDP sessions", addr);
+                        traffic.dropped_received();
+                        continue;
+                    }
+                    None => {
+                        let peer_layers = layers.for_peer();
+                        let net_node = peer_layers.node();
+                        let (recv_tx, message_rx) = net_node.transport_channels(&peer_layers);
+                        let event_tx = net_node.event_channel.sender.clone_async();
+                        let _ = event_tx.try_send(NetworkEvent::Connected);
+                        let id = next_id;
+                        next_id += 1;
+                        task::spawn(forward_sends(
+                            addr,
+                            id,
+                            message_rx,
+                            net_node.shutdown_channel.receiver.clone_async(),
+                            send_tx.clone(),
+                            sessions.clone(),
+                        ));
+                        links.insert(
+                            addr,
+                            SessionLink {
+                                id,
+                                recv_tx: recv_tx.clone(),
+                                event_tx,
+                                last_seen: Instant::now(),
+                            },
+                        );
+                        let _ = new_peer_tx.try_send((addr, net_node, peer_layers));
+                        recv_tx
+                    }
+                }
+            };
+
+            let _ = recv_tx.send(packet).await;
+        }
+    };
+    let expire = expire_idle(sessions.clone(), idle_timeout);
+    pin_mut!(routing, expire);
+    future::select(routing, expire).await;
+
+    Ok(())
+}
+
+/// Send the packets of a peer through the server socket
+async fn forward_sends(
+    addr: SocketAddr,
+    id: u64,
+    message_rx: AsyncReceiver<NetworkRawPacket>,
+    shutdown_rx: AsyncReceiver<DisconnectReason>,
+    send_tx: AsyncSender<NetworkRawPacket>,
+    sessions: Sessions,
+) {
+    let mut queue = SendQueue::new(message_rx, shutdown_rx);
+    let mut closed = None;
+    while let Some(outgoing) = queue.next().await {
+        let packet = match outgoing {
+            Outgoing::Packet(packet) => NetworkRawPacket {
+                addr: Some(addr),
+                ..packet
+            },
+            Outgoing::Close(reason) => {
+                closed = Some(reason);
+                break;
+            }
+        };
+        if send_tx.send(packet).await.is_err() {
+            break;
+        }
+    }
+
+    // an expired session may have been replaced by a new one of the remote
+    let link = {
+        let mut links = sessions.lock().unwrap();
+        links
+            .get(&addr)
+            .is_some_and(|link| link.id == id)
+            .then(|| links.remove(&addr))
+            .flatten()
+    };
+    if let (Some(link), Some(reason)) = (link, closed) {
+        let _ = link.event_tx.send(NetworkEvent::Disconnected(reason)).await;
+    }
+}
+
+async fn expire_idle(sessions: Sessions, idle_timeout: Duration) {
+    let interval = (idle_timeout / 4).max(Duration::from_millis(10));
+    loop {
+        task::sleep(interval).await;
+        let mut expired = vec![];
+        sessions.lock().unwrap().retain(|addr, link| {
+            let alive = link.last_seen.elapsed() < idle_timeout;
+            if !alive {
+                debug!("UDP session {} idle for {:?}", addr, idle_timeout);
+                expired.push(link.event_tx.clone());
+            }
+            alive
+        });
+        for event_tx in expired {
+            let reason = DisconnectReason::Timeout(idle_timeout);
+            let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) fn spawn_session_peers(
+    mut commands: Commands,
+    q_session: Query<(Entity, &UdpSession, &ChannelId)>,
+) {
+    for (entity, session, channel_id) in q_session.iter() {
+        while let Ok(Some((addr, net_node, layers))) = session.new_peer_channel.receiver.try_recv()
+        {
+            let peer_entity = commands
+                .spawn((
+                    net_node,
+                    *channel_id,
+                    ClientNode(UdpAddress { socket_addr: addr }),
+                    RemoteAddr(addr),
+                    NetworkPeer,
+                ))
+                .id();
+            layers.insert_on(&mut commands.entity(peer_entity));
+
+            debug!("new UDP session {} {:?}", addr, peer_entity);
+
+            commands.entity(entity).add_child(peer_entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::{future::timeout, task::block_on};
+
+    use super::*;
+    use crate::stats::NetworkStats;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn datagram(addr: SocketAddr, byte: u8) -> NetworkRawPacket {
+        NetworkRawPacket {
+            addr: Some(addr),
+            bytes: vec![byte].into(),
+            text: None,
+        }
+    }
+
+    async fn recv<T>(rx: &AsyncReceiver<T>) -> T {
+        timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn next_event(node: &NetworkNode) -> NetworkEvent {
+        recv(node.event_channel.receiver.as_async()).await
+    }
+
+    fn spawn_route(
+        max_sessions: usize,
+        traffic: Arc<TrafficCounters>,
+        new_peers: &AsyncChannel<(SocketAddr, NetworkNode, TransportLayers)>,
+        packets: &AsyncChannel<NetworkRawPacket>,
+        sends: &AsyncChannel<NetworkRawPacket>,
+    ) {
+        task::spawn(route(
+            IDLE_TIMEOUT,
+            max_sessions,
+            traffic,
+            TransportLayers::default(),
+            new_peers.sender.clone_async(),
+            packets.receiver.clone_async(),
+            sends.sender.clone_async(),
+        ));
+    }
+
+    #[test]
+    fn expired_session_does_not_remove_its_successor() {
+        let new_peers = AsyncChannel::new();
+        let packets = AsyncChannel::new();
+        let sends = AsyncChannel::new();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+
+        block_on(async {
+            spawn_route(1024, Default::default(), &new_peers, &packets, &sends);
+            let new_peer = new_peers.receiver.as_async();
+            let packet_tx = packets.sender.as_async();
+
+            packet_tx.send(datagram(addr, 1)).await.unwrap();
+            let (_, expired, _) = recv(new_peer).await;
+            assert!(matches!(
+                next_event(&expired).await,
+                NetworkEvent::Connected
+            ));
+            assert!(matches!(
+                next_event(&expired).await,
+                NetworkEvent::Disconnected(DisconnectReason::Timeout(_))
+            ));
+
+            // the remote comes back before the expired peer is closed
+            packet_tx.send(datagram(addr, 2)).await.unwrap();
+            let (_, current, _) = recv(new_peer).await;
+            assert!(matches!(
+                next_event(&current).await,
+                NetworkEvent::Connected
+            ));
+            expired.close(DisconnectReason::Closed);
+            task::sleep(IDLE_TIMEOUT / 5).await;
+
+            packet_tx.send(datagram(addr, 3)).await.unwrap();
+            let received = current.recv_message_channel.receiver.as_async();
+            assert_eq!(recv(received).await.bytes[..], [2]);
+            assert_eq!(recv(received).await.bytes[..], [3]);
+            assert!(new_peer.is_empty());
+            assert!(current.event_channel.receiver.is_empty());
+
+            current.send_bytes(&[4]);
+            let sent = recv(sends.receiver.as_async()).await;
+            assert_eq!((sent.addr, &sent.bytes[..]), (Some(addr), &[4][..]));
+        });
+    }
+
+    #[test]
+    fn sessions_are_bounded() {
+        let new_peers = AsyncChannel::new();
+        let packets = AsyncChannel::new();
+        let sends = AsyncChannel::new();
+        let traffic = Arc::new(TrafficCounters::default());
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+
+        block_on(async {
+            spawn_route(2, traffic.clone(), &new_peers, &packets, &sends);
+            let new_peer = new_peers.receiver.as_async();
+            let packet_tx = packets.sender.as_async();
+
+            packet_tx.send(datagram(addr(5000), 1)).await.unwrap();
+            packet_tx.send(datagram(addr(5001), 2)).await.unwrap();
+            let (first, _first_node, _) = recv(new_peer).await;
+            let (second, second_node, _) = recv(new_peer).await;
+            assert_eq!((first, second), (addr(5000), addr(5001)));
+
+            packet_tx.send(datagram(addr(5002), 3)).await.unwrap();
+            // known remotes still get through behind the dropped datagram
+            packet_tx.send(datagram(addr(5001), 4)).await.unwrap();
+            let received = second_node.recv_message_channel.receiver.as_async();
+            assert_eq!(recv(received).await.bytes[..], [2]);
+            assert_eq!(recv(received).await.bytes[..], [4]);
+            assert!(new_peer.is_empty());
+
+            let mut stats = NetworkStats::default();
+            traffic.snapshot(&mut stats);
+            assert_eq!(stats.dropped_received, 1);
+        });
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    net::SocketAddr,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{
+            SocketAddr as UnixSocketAddr, UnixListener as StdUnixListener,
+            UnixStream as StdUnixStream,
+        },
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use async_std::{
+    future::timeout,
+    os::unix::net::{UnixListener, UnixStream},
+    prelude::StreamExt,
+    task,
+};
+use bevy::prelude::*;
+use kanal::{AsyncReceiver, AsyncSender};
+
+use crate::{
+    channels::ChannelId,
+    client::{ClientNode, ReconnectSetting, StartClient},
+    conditioner::NetworkConditioner,
+    error::NetworkError,
+    framing::{FrameCodec, LengthPrefix},
+    heartbeat::{Heartbeat, NetworkRtt},
+    network_node::{
+        AsyncChannel, DisconnectReason, NetworkAddress, NetworkEvent, NetworkNode, NetworkPeer,
+        NetworkRawPacket, RemoteAddr, TransportLayers, until_shutdown,
+    },
+    queue::QueueSettings,
+    server::{ServerNode, StartServer},
+    transports::{tcp::serve_stream, virtual_addr},
+};
+
+pub mod datagram;
+
+pub use datagram::UnixDatagramAddress;
+
+pub struct UnixPlugin;
+
+impl Plugin for UnixPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostUpdate, handle_endpoint)
+            .add_observer(on_start_server)
+            .add_observer(on_start_client)
+            .add_observer(datagram::on_start_server);
+    }
+}
+
+/// Where a Unix domain socket is bound.
+///
+/// Written as `unix:/run/game.sock` or `unix:@game` for the abstract namespace.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UnixPath {
+    /// Socket file, removed again once the node stops listening
+    File(PathBuf),
+    /// Name in the Linux abstract namespace, no file is created
+    Abstract(String),
+}
+
+impl UnixPath {
+    fn socket_addr(&self) -> io::Result<UnixSocketAddr> {
+        match self {
+            UnixPath::File(path) => UnixSocketAddr::from_pathname(path),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            UnixPath::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                UnixSocketAddr::from_abstract_name(name)
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            UnixPath::Abstract(name) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("abstract socket @{} needs Linux", name),
+            )),
+        }
+    }
+
+    /// Path of a bound remote, `None` for unnamed sockets
+    fn from_socket_addr(addr: &UnixSocketAddr) -> Option<Self> {
+        if let Some(path) = addr.as_pathname() {
+            return Some(UnixPath::File(path.to_path_buf()));
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            if let Some(name) = addr.as_abstract_name() {
+                return Some(UnixPath::Abstract(
+                    String::from_utf8_lossy(name).into_owned(),
+                ));
+            }
+        }
+
+        None
+    }
+}
+
+impl Display for UnixPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnixPath::File(path) => write!(f, "unix:{}", path.display()),
+            UnixPath::Abstract(name) => write!(f, "unix:@{}", name),
+        }
+    }
+}
+
+impl FromStr for UnixPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = s.strip_prefix("unix:").unwrap_or(s);
+        match path.strip_prefix('@') {
+            Some("") => Err(format!("{} has an empty abstract name", s)),
+            Some(name) => Ok(UnixPath::Abstract(name.to_string())),
+            None if path.is_empty() => Err(format!("{} is not a unix socket path", s)),
+            None => Ok(UnixPath::File(PathBuf::from(path))),
+        }
+    }
+}
+
+/// Removes the socket file of a listening node when dropped
+pub(crate) struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.0) {
+            Ok(()) => debug!("removed socket file {}", self.0.display()),
+            Err(e) => warn!("failed to remove socket file {}: {}", self.0.display(), e),
+        }
+    }
+}
+
+/// Bind `path` with `bind`, replacing a socket file left behind by a process
+/// that is gone
+pub(crate) fn bind_path<T>(
+    path: &UnixPath,
+    bind: impl Fn(&UnixSocketAddr) -> io::Result<T>,
+) -> io::Result<(T, Option<SocketFile>)> {
+    let addr = path.socket_addr()?;
+    let UnixPath::File(file) = path else {
+        return Ok((bind(&addr)?, None));
+    };
+
+    let socket = match bind(&addr) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale(file) => {
+            debug!("replacing stale socket file {}", file.display());
+            fs::remove_file(file)?;
+            bind(&addr)?
+        }
+        result => result?,
+    };
+
+    Ok((socket, Some(SocketFile(file.clone()))))
+}
+
+/// Whether `file` is a socket nothing is bound to anymore
+fn is_stale(file: &Path) -> bool {
+    let is_socket = fs::symlink_metadata(file).is_ok_and(|meta| meta.file_type().is_socket());
+    is_socket
+        && matches!(
+            StdUnixStream::connect(file),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused
+        )
+}
+
+#[derive(Debug, Clone)]
+pub struct UnixAddress {
+    pub path: UnixPath,
+    /// Accepted streams with the address of the listener
+    new_connection_channel: AsyncChannel<(UnixStream, SocketAddr)>,
+}
+
+impl UnixAddress {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self::from_path(UnixPath::File(path.into()))
+    }
+
+    /// Address in the Linux abstract namespace
+    pub fn abstract_name(name: impl Into<String>) -> Self {
+        Self::from_path(UnixPath::Abstract(name.into()))
+    }
+
+    fn from_path(path: UnixPath) -> Self {
+        Self {
+            path,
+            new_connection_channel: Default::default(),
+        }
+    }
+}
+
+impl NetworkAddress for UnixAddress {
+    fn to_string(&self) -> String {
+        self.path.to_string()
+    }
+
+    fn from_string(s: &str) -> Result<Self, String> {
+        s.parse().map(Self::from_path)
+    }
+}
+
+async fn listen(
+    path: UnixPath,
+    event_tx: AsyncSender<NetworkEvent>,
+    new_connection_tx: AsyncSender<(UnixStream, SocketAddr)>,
+) -> Result<(), NetworkError> {
+    // the socket file lives as long as the listener
+    let (listener, _socket_file) = bind_path(&path, StdUnixListener::bind_addr)?;
+    let listener = UnixListener::from(listener);
+    let local_addr = virtual_addr();
+    info!("Unix Server listening on {} as {}", path, local_addr);
+    let _ = event_tx.send(NetworkEvent::Listen(local_addr)).await;
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        if new_connection_tx.send((stream?, local_addr)).await.is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+async fn connect(path: &UnixPath) -> io::Result<UnixStream> {
+    let addr = path.socket_addr()?;
+    let stream = task::spawn_blocking(move || StdUnixStream::connect_addr(&addr)).await?;
+
+    Ok(stream.into())
+}
+
+/// Framing of a connection, control messages need message boundaries
+fn stream_codec(codec: Option<&FrameCodec>, layers: &TransportLayers) -> Option<FrameCodec> {
+    codec.cloned().or_else(|| {
+        layers
+            .heartbeat
+            .as_ref()
+            .map(|_| FrameCodec::length_prefixed(LengthPrefix::U32))
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection(
+    stream: UnixStream,
+    local_addr: SocketAddr,
+    addr: SocketAddr,
+    codec: Option<FrameCodec>,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    message_rx: AsyncReceiver<NetworkRawPacket>,
+    event_tx: AsyncSender<NetworkEvent>,
+    shutdown_rx: AsyncReceiver<DisconnectReason>,
+) {
+    let channels = (recv_tx, message_rx, event_tx, shutdown_rx);
+    serve_stream(stream, local_addr, addr, codec, channels).await;
+}
+
+fn on_start_server(
+    on: On<StartServer>,
+    q_unix_server: Query<(&NetworkNode, &ServerNode<UnixAddress>)>,
+) {
+    let ev = on.event();
+    if let Ok((net_node, server)) = q_unix_server.get(ev.entity) {
+        let path = server.path.clone();
+        let event_tx = net_node.event_channel.sender.clone_async();
+        let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
+        let new_connection_tx = server.new_connection_channel.sender.clone_async();
+        task::spawn(until_shutdown(
+            listen(path, event_tx.clone(), new_connection_tx),
+            shutdown_rx,
+            event_tx,
+        ));
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn on_start_client(
+    on: On<StartClient>,
+    q_unix_client: Query<
+        (
+            &NetworkNode,
+            &ClientNode<UnixAddress>,
+            Option<&FrameCodec>,
+            Option<&ReconnectSetting>,
+            Option<(&Heartbeat, &NetworkRtt)>,
+            Option<&NetworkConditioner>,
+            Option<&QueueSettings>,
+        ),
+        Without<NetworkPeer>,
+    >,
+) {
+    let ev = on.event();
+    if let Ok((
+        net_node,
+        remote_addr,
+        opt_codec,
+        opt_reconnect,
+        opt_heartbeat,
+        opt_conditioner,
+        opt_queue,
+    )) = q_unix_client.get(ev.entity)
+    {
+        info!("try connect to {}", remote_addr.to_string());
+
+        let path = remote_addr.path.clone();
+        let connect_timeout = opt_reconnect
+            .map(ReconnectSetting::connect_timeout)
+            .unwrap_or_else(|| ReconnectSetting::default().connect_timeout());
+        let layers = TransportLayers::new(opt_queue, opt_heartbeat, opt_conditioner);
+        let codec = stream_codec(opt_codec, &layers);
+        // the layers start with the connection, heartbeats time out from there
+        let net_node = net_node.clone();
+        let event_tx = net_node.event_channel.sender.clone_async();
+        let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
+
+        task::spawn(async move {
+            match timeout(connect_timeout, connect(&path)).await {
+                Ok(Ok(stream)) => {
+                    info!("Unix connected to {}", path);
+                    let (recv_tx, message_rx) = net_node.transport_channels(&layers);
+                    handle_connection(
+                        stream,
+                        virtual_addr(),
+                        virtual_addr(),
+                        codec,
+                        recv_tx,
+                        message_rx,
+                        event_tx,
+                        shutdown_rx,
+                    )
+                    .await;
+                }
+                Ok(Err(err)) => {
+                    let _ = event_tx
+                        .send(NetworkEvent::Error(NetworkError::Connection(
+                            err.to_string(),
+                        )))
+                        .await;
+                }
+                Err(_) => {
+                    let _ = event_tx
+                        .send(NetworkEvent::Error(NetworkError::Connection(format!(
+                            "connect to {} timed out",
+                            path
+                        ))))
+                        .await;
+                }
+            }
+        });
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn handle_endpoint(
+    mut commands: Commands,
+    q_unix_server: Query<(
+        Entity,
+        &ServerNode<UnixAddress>,
+        &ChannelId,
+        Option<&FrameCodec>,
+        Option<(&Heartbeat, &NetworkRtt)>,
+        Option<&NetworkConditioner>,
+        Option<&QueueSettings>,
+    )>,
+) {
+    for (entity, unix_node, channel_id, opt_codec, opt_heartbeat, opt_conditioner, opt_queue) in
+        q_unix_server.iter()
+    {
+        let layers = TransportLayers::new(opt_queue, opt_heartbeat, opt_conditioner);
+
+        while let Ok(Some((stream, local_addr))) =
+            unix_node.new_connection_channel.receiver.try_recv()
+        {
+            // Create a new entity for the client
+            let peer_entity = commands.spawn_empty().id();
+            let peer_layers = layers.for_peer();
+            let new_net_node = peer_layers.node();
+            let (recv_tx, message_rx) = new_net_node.transport_channels(&peer_layers);
+            let event_tx = new_net_node.event_channel.sender.clone_async();
+            let shutdown_rx = new_net_node.shutdown_channel.receiver.clone_async();
+            // clients are usually unnamed, peers are told apart by a virtual address
+            let peer_addr = virtual_addr();
+            task::spawn(handle_connection(
+                stream,
+                local_addr,
+                peer_addr,
+                stream_codec(opt_codec, &peer_layers),
+                recv_tx,
+                message_rx,
+                event_tx,
+                shutdown_rx,
+            ));
+
+            commands.entity(peer_entity).insert((
+                new_net_node,
+                *channel_id,
+                ClientNode(UnixAddress::from_path(unix_node.path.clone())),
+                RemoteAddr(peer_addr),
+                NetworkPeer,
+            ));
+            if let Some(codec) = opt_codec {
+                commands.entity(peer_entity).insert(codec.clone());
+            }
+            peer_layers.insert_on(&mut commands.entity(peer_entity));
+
+            info!("new client connected {:?}", peer_entity);
+
+            // Add the client to the server's children
+            commands.entity(entity).add_child(peer_entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Socket file in the temp dir, unique to the test process
+    pub(super) fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("octopus-{}-{}.sock", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn bound_socket_file_is_removed_on_drop() {
+        let file = socket_path("bind");
+        let path = UnixPath::File(file.clone());
+
+        let (listener, socket_file) = bind_path(&path, StdUnixListener::bind_addr).unwrap();
+        assert!(file.exists());
+        drop(listener);
+        drop(socket_file);
+
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn stale_socket_file_is_replaced() {
+        let file = socket_path("stale");
+        let path = UnixPath::File(file.clone());
+        // a crashed process leaves its file behind
+        drop(StdUnixListener::bind(&file).unwrap());
+        assert!(file.exists());
+
+        let (listener, _socket_file) = bind_path(&path, StdUnixListener::bind_addr).unwrap();
+        StdUnixStream::connect(&file).unwrap();
+        drop(listener);
+    }
+
+    #[test]
+    fn live_socket_and_other_files_are_kept() {
+        let file = socket_path("live");
+        let path = UnixPath::File(file.clone());
+        let (_listener, _socket_file) = bind_path(&path, StdUnixListener::bind_addr).unwrap();
+
+        let err = bind_path(&path, StdUnixListener::bind_addr).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        StdUnixStream::connect(&file).unwrap();
+
+        let regular = socket_path("regular");
+        fs::write(&regular, b"not a socket").unwrap();
+        let regular_path = UnixPath::File(regular.clone());
+        assert!(bind_path(&regular_path, StdUnixListener::bind_addr).is_err());
+        assert!(regular.exists());
+        fs::remove_file(regular).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn abstract_names_have_no_file() {
+        let name = format!("octopus-{}-abstract", std::process::id());
+        let path: UnixPath = format!("unix:@{}", name).parse().unwrap();
+
+        let (_listener, socket_file) = bind_path(&path, StdUnixListener::bind_addr).unwrap();
+        assert!(socket_file.is_none());
+        StdUnixStream::connect_addr(&path.socket_addr().unwrap()).unwrap();
+        assert!(bind_path(&path, StdUnixListener::bind_addr).is_err());
+    }
+
+    #[test]
+    fn paths_parse_and_display() {
+        assert_eq!(
+            "unix:/run/game.sock".parse(),
+            Ok(UnixPath::File("/run/game.sock".into()))
+        );
+        assert_eq!("@game".parse(), Ok(UnixPath::Abstract("game".into())));
+        assert_eq!(UnixPath::Abstract("game".into()).to_string(), "unix:@game");
+        assert!("unix:@".parse::<UnixPath>().is_err());
+        assert!("unix:".parse::<UnixPath>().is_err());
+    }
+}
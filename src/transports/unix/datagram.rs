@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    os::unix::net::{SocketAddr as UnixSocketAddr, UnixDatagram},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::Async;
+use async_std::task;
+use bevy::prelude::*;
+use bytes::Bytes;
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use kanal::AsyncSender;
+
+use crate::{
+    client::ClientNode,
+    conditioner::NetworkConditioner,
+    error::NetworkError,
+    heartbeat::{Heartbeat, NetworkRtt},
+    network_node::{
+        DisconnectReason, NetworkAddress, NetworkEvent, NetworkNode, NetworkPeer, NetworkRawPacket,
+        Outgoing, SendQueue, TransportLayers,
+    },
+    queue::QueueSettings,
+    server::{ServerNode, StartServer},
+    transports::{
+        unix::{UnixPath, bind_path},
+        virtual_addr,
+    },
+};
+
+/// Largest datagram read from the socket
+const MAX_PACKET_SIZE: usize = 65_507;
+
+/// Remotes silent for this long get a new virtual address when they return
+const REMOTE_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Unix datagram socket, used like [`UdpAddress`](crate::transports::udp::UdpAddress).
+///
+/// The `ServerNode` binds the socket, a `ClientNode` on the same entity is the
+/// default destination of packets without an address.
+#[derive(Debug, Clone)]
+pub struct UnixDatagramAddress {
+    pub path: UnixPath,
+}
+
+impl UnixDatagramAddress {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: UnixPath::File(path.into()),
+        }
+    }
+
+    /// Address in the Linux abstract namespace
+    pub fn abstract_name(name: impl Into<String>) -> Self {
+        Self {
+            path: UnixPath::Abstract(name.into()),
+        }
+    }
+}
+
+impl NetworkAddress for UnixDatagramAddress {
+    fn to_string(&self) -> String {
+        self.path.to_string()
+    }
+
+    fn from_string(s: &str) -> Result<Self, String> {
+        s.parse().map(|path| Self { path })
+    }
+}
+
+/// Virtual addresses of the remotes a socket exchanged datagrams with
+#[derive(Default)]
+struct Remotes {
+    addrs: HashMap<UnixPath, SocketAddr>,
+    paths: HashMap<SocketAddr, Remote>,
+}
+
+struct Remote {
+    path: UnixPath,
+    socket_addr: UnixSocketAddr,
+    last_seen: Instant,
+}
+
+impl Remotes {
+    fn addr_of(&mut self, from: &UnixSocketAddr) -> Option<SocketAddr> {
+        let path = UnixPath::from_socket_addr(from)?;
+        let now = Instant::now();
+        if let Some(addr) = self.addrs.get(&path)
+            && let Some(remote) = self.paths.get_mut(addr)
+        {
+            remote.last_seen = now;
+            return Some(*addr);
+        }
+
+        self.prune(now);
+        let addr = virtual_addr();
+        self.addrs.insert(path.clone(), addr);
+        self.paths.insert(
+            addr,
+            Remote {
+                path,
+                socket_addr: from.clone(),
+                last_seen: now,
+            },
+        );
+
+        Some(addr)
+    }
+
+    fn socket_addr(&self, addr: &SocketAddr) -> Option<UnixSocketAddr> {
+        self.paths
+            .get(addr)
+            .map(|remote| remote.socket_addr.clone())
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) {
+        if let Some(remote) = self.paths.remove(addr) {
+            self.addrs.remove(&remote.path);
+        }
+    }
+
+    /// Forget remotes that have been silent too long
+    fn prune(&mut self, now: Instant) {
+        let Self { addrs, paths } = self;
+        paths.retain(|_, remote| {
+            let alive = now.duration_since(remote.last_seen) < REMOTE_IDLE_TIMEOUT;
+            if !alive {
+                addrs.remove(&remote.path);
+            }
+            alive
+        });
+    }
+}
+
+/// Whether a send failed because nothing is bound to the remote anymore
+fn is_gone(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+async fn recv_loop(
+    socket: Arc<Async<UnixDatagram>>,
+    remotes: Arc<Mutex<Remotes>>,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+) -> Result<(), NetworkError> {
+    let mut buf: Vec<u8> = vec![0; MAX_PACKET_SIZE];
+
+    loop {
+        let (len, from) = socket
+            .read_with(|socket| socket.recv_from(&mut buf))
+            .await
+            .map_err(NetworkError::Listen)?;
+        // unnamed sockets can not be answered
+        let addr = remotes.lock().unwrap().addr_of(&from);
+        trace!("Unix datagram received {} bytes from {:?}", len, from);
+        let _ = recv_tx
+            .send(NetworkRawPacket {
+                addr,
+                bytes: Bytes::copy_from_slice(&buf[..len]),
+                text: None,
+            })
+            .await;
+    }
+}
+
+/// Send queued packets, returning why the node was closed.
+///
+/// A failed send is reported and skipped, one remote going away must not stop
+/// the socket.
+async fn send_loop(
+    socket: Arc<Async<UnixDatagram>>,
+    remotes: Arc<Mutex<Remotes>>,
+    to_socket: Option<UnixSocketAddr>,
+    mut queue: SendQueue,
+    event_tx: AsyncSender<NetworkEvent>,
+) -> Result<Option<DisconnectReason>, NetworkError> {
+    while let Some(outgoing) = queue.next().await {
+        let packet = match outgoing {
+            Outgoing::Packet(packet) => packet,
+            Outgoing::Close(reason) => return Ok(Some(reason)),
+        };
+
+        let to_socket = match packet.addr {
+            Some(addr) => remotes.lock().unwrap().socket_addr(&addr),
+            None => to_socket.clone(),
+        };
+        let Some(to_socket) = to_socket else {
+            trace!("no unix socket to send {:?} to", packet);
+            continue;
+        };
+        trace!("Unix datagram sending {} bytes", packet.bytes.len());
+        if let Err(e) = socket
+            .write_with(|socket| socket.send_to_addr(&packet.bytes, &to_socket))
+            .await
+        {
+            debug!("Unix datagram send to {:?} failed: {}", to_socket, e);
+            if let Some(addr) = packet.addr.filter(|_| is_gone(&e)) {
+                remotes.lock().unwrap().remove(&addr);
+            }
+            let _ = event_tx.send(NetworkEvent::Error(e.into())).await;
+        }
+    }
+
+    Ok(None)
+}
+
+async fn listen(
+    path: UnixPath,
+    remote: Option<UnixPath>,
+    recv_tx: AsyncSender<NetworkRawPacket>,
+    queue: SendQueue,
+    event_tx: AsyncSender<NetworkEvent>,
+) -> Result<Option<DisconnectReason>, NetworkError> {
+    // the socket file lives as long as the socket
+    let (socket, _socket_file) = bind_path(&path, UnixDatagram::bind_addr)?;
+    let socket = Arc::new(Async::new(socket)?);
+    let remotes: Arc<Mutex<Remotes>> = Default::default();
+    let to_socket = remote.map(|remote| remote.socket_addr()).transpose()?;
+
+    let local_addr = virtual_addr();
+    info!("Unix datagram listening on {} as {}", path, local_addr);
+    let _ = event_tx.send(NetworkEvent::Listen(local_addr)).await;
+
+    // the socket is closed once sending finished
+    let send = send_loop(socket.clone(), remotes.clone(), to_socket, queue, event_tx);
+    let recv = recv_loop(socket, remotes, recv_tx);
+    pin_mut!(send, recv);
+    match future::select(send, recv).await {
+        Either::Left((result, _)) => result,
+        Either::Right((result, _)) => result.map(|_| None),
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn on_start_server(
+    on: On<StartServer>,
+    q_unix: Query<
+        (
+            &NetworkNode,
+            &ServerNode<UnixDatagramAddress>,
+            Option<&ClientNode<UnixDatagramAddress>>,
+            Option<(&Heartbeat, &NetworkRtt)>,
+            Option<&NetworkConditioner>,
+            Option<&QueueSettings>,
+        ),
+        Without<NetworkPeer>,
+    >,
+) {
+    let ev = on.event();
+    if let Ok((net_node, server_addr, opt_remote_addr, opt_heartbeat, opt_conditioner, opt_queue)) =
+        q_unix.get(ev.entity)
+    {
+        let path = server_addr.path.clone();
+        let remote = opt_remote_addr.map(|remote_addr| remote_addr.path.clone());
+        // only a node with a remote has someone to ping
+        let opt_heartbeat = opt_heartbeat.filter(|_| remote.is_some());
+        let layers = TransportLayers::new(opt_queue, opt_heartbeat, opt_conditioner);
+        let (recv_tx, send_rx) = net_node.transport_channels(&layers);
+        let event_tx = net_node.event_channel.sender.clone_async();
+        let shutdown_rx = net_node.shutdown_channel.receiver.clone_async();
+
+        task::spawn(async move {
+            let result = listen(
+                path,
+                remote,
+                recv_tx,
+                SendQueue::new(send_rx, shutdown_rx),
+                event_tx.clone(),
+            )
+            .await;
+
+            match result {
+                Ok(Some(reason)) => {
+                    let _ = event_tx.send(NetworkEvent::Disconnected(reason)).await;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let _ = event_tx.send(NetworkEvent::Error(err)).await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use async_std::{future::timeout, task::block_on};
+
+    use super::*;
+    use crate::{network_node::AsyncChannel, transports::unix::tests::socket_path};
+
+    fn bind(name: &str) -> (UnixDatagram, UnixSocketAddr) {
+        let socket = UnixDatagram::bind(socket_path(name)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn packet(addr: SocketAddr, bytes: &'static [u8]) -> NetworkRawPacket {
+        NetworkRawPacket {
+            addr: Some(addr),
+            bytes: Bytes::from_static(bytes),
+            text: None,
+        }
+    }
+
+    #[test]
+    fn sending_to_a_gone_remote_is_reported_and_skipped() {
+        let (socket, local) = bind("datagram-local");
+        let (gone, gone_addr) = bind("datagram-gone");
+        let (live, live_addr) = bind("datagram-live");
+        let remotes: Arc<Mutex<Remotes>> = Default::default();
+        let gone_remote = remotes.lock().unwrap().addr_of(&gone_addr).unwrap();
+        let live_remote = remotes.lock().unwrap().addr_of(&live_addr).unwrap();
+        drop(gone);
+        fs::remove_file(gone_addr.as_pathname().unwrap()).unwrap();
+
+        let messages: AsyncChannel<NetworkRawPacket> = AsyncChannel::new();
+        let shutdown: AsyncChannel<DisconnectReason> = AsyncChannel::new();
+        let events: AsyncChannel<NetworkEvent> = AsyncChannel::new();
+        messages.sender.send(packet(gone_remote, b"lost")).unwrap();
+        messages.sender.send(packet(live_remote, b"sent")).unwrap();
+        shutdown.sender.send(DisconnectReason::Closed).unwrap();
+
+        let result = block_on(timeout(
+            Duration::from_secs(1),
+            send_loop(
+                Arc::new(Async::new(socket).unwrap()),
+                remotes.clone(),
+                None,
+                SendQueue::new(
+                    messages.receiver.clone_async(),
+                    shutdown.receiver.clone_async(),
+                ),
+                events.sender.clone_async(),
+            ),
+        ))
+        .unwrap();
+
+        assert!(matches!(result, Ok(Some(DisconnectReason::Closed))));
+        let mut buf = [0; 8];
+        let (len, from) = live.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"sent");
+        assert_eq!(from.as_pathname(), local.as_pathname());
+        assert!(matches!(
+            events.receiver.try_recv(),
+            Ok(Some(NetworkEvent::Error(NetworkError::IoError(_))))
+        ));
+        let remotes = remotes.lock().unwrap();
+        assert!(remotes.socket_addr(&gone_remote).is_none());
+        assert!(remotes.socket_addr(&live_remote).is_some());
+        fs::remove_file(local.as_pathname().unwrap()).unwrap();
+        fs::remove_file(live_addr.as_pathname().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn silent_remotes_are_pruned() {
+        let (_first, first_addr) = bind("datagram-first");
+        let (_second, second_addr) = bind("datagram-second");
+        let mut remotes = Remotes::default();
+        let first = remotes.addr_of(&first_addr).unwrap();
+        assert_eq!(remotes.addr_of(&first_addr), Some(first));
+
+        remotes.prune(Instant::now() + REMOTE_IDLE_TIMEOUT);
+        assert!(remotes.socket_addr(&first).is_none());
+        let second = remotes.addr_of(&second_addr).unwrap();
+        assert_ne!(remotes.addr_of(&first_addr), Some(first));
+        assert_eq!(remotes.paths.len(), 2);
+        assert_ne!(first, second);
+        fs::remove_file(first_addr.as_pathname().unwrap()).unwrap();
+        fs::remove_file(second_addr.as_pathname().unwrap()).unwrap();
+    }
+}